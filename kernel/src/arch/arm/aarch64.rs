@@ -61,6 +61,12 @@ pub fn wfe() {
     }
 }
 
+pub fn sev() {
+    unsafe {
+        asm!("sev", options(nomem));
+    }
+}
+
 pub fn wfi() {
     unsafe {
         asm!("wfi", options(nomem));
@@ -74,6 +80,13 @@ pub fn dc_clean_by_va_PoU(vaddr: usize) {
     dsb();
 }
 
+pub fn dc_clean_by_va_PoC(vaddr: usize) {
+    unsafe {
+        asm!("dc cvac, {vaddr}", vaddr = in(reg) vaddr, options(nomem));
+    }
+    dsb();
+}
+
 pub fn clid() -> usize {
     let clid: usize;
     unsafe {
@@ -136,3 +149,26 @@ pub fn clean_l1_cache() {
     isb();
     dsb();
 }
+
+/// Let EL0 take breakpoint and software step exceptions, which the OS lock blocks out of reset.
+pub fn unlock_os_lock() {
+    unsafe { asm!("msr oslar_el1, xzr", options(nomem)) }
+    isb();
+}
+
+/// Software step the thread about to be entered. It takes a `Step` exception after executing one
+/// instruction if SPSR_EL1.SS is set as well.
+pub fn set_single_step(enable: bool) {
+    const MDSCR_SS: usize = 1 << 0;
+    unsafe {
+        let mut mdscr: usize;
+        asm!("mrs {x}, mdscr_el1", x = out(reg) mdscr, options(nomem));
+        if enable {
+            mdscr |= MDSCR_SS;
+        } else {
+            mdscr &= !MDSCR_SS;
+        }
+        asm!("msr mdscr_el1, {x}", x = in(reg) mdscr, options(nomem));
+    }
+    isb();
+}
@@ -6,12 +6,13 @@ use core::mem::MaybeUninit;
 
 use crate::arch::cpuid;
 use crate::objects::*;
+use crate::utils::kernel_lock::KERNEL_LOCK;
 use crate::utils::percore::PerCore;
 use crate::NCPU;
 use sysapi::init::InitCSpaceSlot::*;
 use sysapi::process::{
-    ProcessCSpace, PROCESS_MAIN_THREAD_STACK_PAGES, PROCESS_MAIN_THREAD_STACK_TOP,
-    PROCESS_ROOT_CNODE_SIZE,
+    ProcessCSpace, PROCESS_MAIN_THREAD_IPC_BUFFER, PROCESS_MAIN_THREAD_STACK_PAGES,
+    PROCESS_MAIN_THREAD_STACK_TOP, PROCESS_ROOT_CNODE_SIZE,
 };
 use sysapi::vspace::{Permission, FRAME_BIT_SIZE, FRAME_SIZE};
 
@@ -111,6 +112,8 @@ lower64_trap:
     mrs     x23, spsr_el1;
     stp     x30, x21, [sp, #16 * 15];
     stp     x22, x23, [sp, #16 * 16];
+    mrs     x24, tpidr_el0;
+    str     x24, [sp, #16 * 17];
     mov     x0, sp
     mrs     x2, tpidr_el1
     bic     x2, x2, #0xfff
@@ -139,6 +142,8 @@ lower64_irq:
     mrs     x23, spsr_el1;
     stp     x30, x21, [sp, #16 * 15];
     stp     x22, x23, [sp, #16 * 16];
+    mrs     x24, tpidr_el0;
+    str     x24, [sp, #16 * 17];
     mov     x0, sp
     mrs     x2, tpidr_el1
     bic     x2, x2, #0xfff
@@ -160,8 +165,7 @@ unsafe extern "C" fn _start() {
     mov     x1, #0xc1000000
     bic     x0, x0, x1
     cbz     x0, zero_bss
-hang:
-    b       hang
+    b       jump_to_el1          // secondary cores: BSS was zeroed by core 0 already
 
 zero_bss:
     // load the start address and number of bytes in BSS section
@@ -227,7 +231,8 @@ fn initialize_init_cspace(cnode: &CNodeObj, cur_free_slot: &mut usize, bi: &Boot
                     is_device
                 );
                 assert!(cur % sz == 0);
-                cnode[*cptr].set(UntypedCap::mint(cur, bit_sz as usize, is_device));
+                cnode[*cptr]
+                    .set(UntypedCap::mint(cur, bit_sz as usize, is_device).into_revocable());
                 *cptr += 1;
             } else {
                 debug!(
@@ -267,12 +272,12 @@ fn initialize_init_cspace(cnode: &CNodeObj, cur_free_slot: &mut usize, bi: &Boot
         64 - cnode.len().trailing_zeros() as usize,
         0,
     );
-    cnode[ProcessCSpace::RootCNodeCap as usize].set(cnode_cap);
+    cnode[ProcessCSpace::RootCNodeCap as usize].set(cnode_cap.into_revocable());
 
     /* Insert monitor cap for super user to control kernel */
-    cnode[Monitor as usize].set(MonitorCap::mint());
+    cnode[Monitor as usize].set(MonitorCap::mint().into_revocable());
 
-    cnode[IrqController as usize].set(InterruptCap::mint());
+    cnode[IrqController as usize].set(InterruptCap::mint().into_revocable());
 
     /* Insert Init Thread TCB */
     alloc_obj::<TcbObj>(
@@ -379,7 +384,12 @@ impl<'a> ElfLoader for InitThreadLoader<'a> {
     fn allocate(&mut self, load_headers: LoadableHeaders) -> Result<(), &'static str> {
         for header in load_headers {
             let flags = header.flags();
-            let perm = Permission::new(flags.is_read(), flags.is_write(), flags.is_execute());
+            /* W^X: an executable segment is never mapped writable */
+            let perm = Permission::new(
+                flags.is_read(),
+                flags.is_write() && !flags.is_execute(),
+                flags.is_execute(),
+            );
             let base = align_down(header.virtual_addr() as usize, FRAME_SIZE);
             let top = (header.virtual_addr() + header.mem_size()) as usize;
             for page_base in (base..top).step_by(FRAME_SIZE) {
@@ -449,6 +459,21 @@ fn load_init_thread(tcb: &mut TcbObj, elf_file: &[u8], cur_free_slot: &mut usize
         );
     }
 
+    map_frame(
+        tcb,
+        PROCESS_MAIN_THREAD_IPC_BUFFER,
+        Permission::writable(),
+        cur_free_slot,
+    );
+    let ipc_buf = RamCap::try_from(&cspace[*cur_free_slot - 1]).unwrap();
+    tcb.configure(
+        None,
+        None,
+        None,
+        Some((ipc_buf, PROCESS_MAIN_THREAD_IPC_BUFFER)),
+    )
+    .expect("Installing IPC buffer failed");
+
     let entry = init_binary.entry_point();
 
     let mut initfs_base = 0x40000000;
@@ -471,13 +496,31 @@ fn load_init_thread(tcb: &mut TcbObj, elf_file: &[u8], cur_free_slot: &mut usize
     tcb.tf.init_user_thread();
 }
 
-fn run_secondary_cpus(entry: usize) {
-    const SECONDARY_CPU_ENTRY_ADDR: usize = 0xe0 + KERNEL_OFFSET;
+/*
+ * Release the secondary cores parked in the firmware spin table. They go back through the
+ * bootloader, which drops them to EL1 in the kernel address space and enters `_start`.
+ */
+fn run_secondary_cpus(bi: &BootInfo) {
+    const SPIN_TABLE_BASE: usize = 0xd8 + KERNEL_OFFSET;
 
-    for i in 0..crate::NCPU {
-        let addr = unsafe { &mut *((SECONDARY_CPU_ENTRY_ADDR + i * 8) as *mut usize) };
-        *addr = entry - KERNEL_OFFSET;
+    let entry = bi
+        .entries
+        .iter()
+        .filter_map(|e| match e {
+            BootInfoEntry::RamEntry(r) => Some(r),
+            _ => None,
+        })
+        .find(|e| e.mem_type == RamType::BootLoader)
+        .map(|e| e.base)
+        .expect("BootLoader entry not found");
+
+    for i in 1..NCPU {
+        let addr = SPIN_TABLE_BASE + i * 8;
+        unsafe { core::ptr::write_volatile(addr as *mut usize, entry) };
+        /* The parked cores read the spin table with their caches off */
+        crate::arch::dc_clean_by_va_PoC(addr);
     }
+    crate::arch::sev();
 }
 
 fn init_app_cpu() {
@@ -522,6 +565,8 @@ fn init_bsp_cpu(bi: &BootInfo) {
     let init_cnode_cap =
         CNodeCap::try_from(&init_cnode_obj[ProcessCSpace::RootCNodeCap as usize]).unwrap();
     init_tcb_cap.install_cspace(&init_cnode_cap).unwrap();
+    /* Init hands out priorities to everything else */
+    init_tcb_cap.set_mcp(crate::scheduler::NUM_PRIORITIES - 1);
 
     let init_fs_bytes = bi
         .entries
@@ -562,7 +607,7 @@ fn init_bsp_cpu(bi: &BootInfo) {
         &init_fs_bytes,
     );
 
-    run_secondary_cpus(crate::_start as usize);
+    run_secondary_cpus(bi);
 
     info!("Jumping to User Space!");
 
@@ -577,17 +622,23 @@ pub extern "C" fn kmain(bi_frame: usize) -> ! {
     use crate::scheduler::SCHEDULER;
     let cpuid = cpuid();
 
-    let bi_frame =
-        unsafe { BootInfo::new_from_frame((bi_frame + KERNEL_OFFSET) as *mut u8, false) };
+    /* Secondary cores wait here until core 0 has set up init */
+    KERNEL_LOCK.lock();
+
+    /* BootInfo is only handed to core 0 */
     if cpuid == 0 {
+        let bi_frame =
+            unsafe { BootInfo::new_from_frame((bi_frame + KERNEL_OFFSET) as *mut u8, false) };
         init_bsp_cpu(&bi_frame)
     } else {
         init_app_cpu()
     }
+    crate::plat::ipi::init(cpuid);
 
     unsafe {
         asm!("msr tpidrro_el0, {cpuid}", cpuid = in(reg) cpuid, options(nomem));
     }
+    crate::arch::unlock_os_lock();
 
     let mut timer = crate::arch::generic_timer::Timer::new();
     timer.initialize(cpuid);
@@ -30,4 +30,9 @@ impl Timer {
     pub fn is_pending(&self, cpu: usize) -> bool {
         self.inner.is_pending(cpu)
     }
+
+    /* Generic timer ticks since boot, the unit of IPC timeouts */
+    pub fn counter(&self) -> u64 {
+        generic_timer::get_cntpct_el0()
+    }
 }
@@ -1,11 +1,15 @@
-use rustyl4api::fault::{Fault as SysFault, VmFaultKind};
+use rustyl4api::error::SysError;
+use rustyl4api::fault::{Fault as SysFault, UserExceptionKind, VmFaultKind};
 
 use super::cpuid;
 use super::trapframe::TrapFrame;
 use crate::arch;
 use crate::interrupt::INTERRUPT_CONTROLLER;
 use crate::objects::TcbObj;
-use log::error;
+use crate::plat::ipi;
+use crate::timeout::TIMEOUT_QUEUE;
+use crate::utils::kernel_lock::KERNEL_LOCK;
+use log::{error, warn};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Fault {
@@ -134,10 +138,42 @@ impl From<u32> for Syndrome {
     }
 }
 
-pub fn handle_vfault(tcb: &mut TcbObj, fault: SysFault) -> ! {
+impl Into<UserExceptionKind> for Syndrome {
+    fn into(self) -> UserExceptionKind {
+        match self {
+            Self::Unknown => UserExceptionKind::UndefinedInstruction,
+            Self::IllegalExecutionState => UserExceptionKind::IllegalExecutionState,
+            Self::PCAlignmentFault => UserExceptionKind::PcAlignment,
+            Self::SpAlignmentFault => UserExceptionKind::SpAlignment,
+            Self::TrappedFpu => UserExceptionKind::TrappedFpu,
+            Self::Breakpoint => UserExceptionKind::Breakpoint,
+            Self::Step => UserExceptionKind::Step,
+            Self::Watchpoint => UserExceptionKind::Watchpoint,
+            Self::Brk(_) => UserExceptionKind::Brk,
+            _ => UserExceptionKind::Other,
+        }
+    }
+}
+
+/*
+ * Send the fault to the thread's fault endpoint, which blocks the thread until the handler
+ * replies. A thread without a fault endpoint is suspended instead.
+ */
+pub fn handle_fault(tcb: &mut TcbObj, fault: SysFault) -> ! {
     let tcb2 = unsafe { &mut *(tcb as *mut TcbObj) };
-    let fault_handler_ep = tcb.fault_handler_ep().unwrap();
-    fault_handler_ep.send_fault_ipc(tcb2, fault).unwrap();
+    let ret = match tcb.fault_handler_ep() {
+        Some(ep) => ep.send_fault_ipc(tcb2, fault),
+        None => Err(SysError::LookupError),
+    };
+    if let Err(e) = ret {
+        warn!(
+            "thread {:x} suspended on unhandled fault {:x?}: {:?}",
+            tcb.thread_id(),
+            fault,
+            e
+        );
+        tcb.suspend();
+    }
 
     crate::SCHEDULER.get().activate();
 }
@@ -180,27 +216,33 @@ pub unsafe extern "C" fn sync_handler() -> ! {
 pub unsafe extern "C" fn lower64_sync_handler(tf: &mut TrapFrame) -> ! {
     use self::Syndrome::*;
 
+    crate::scheduler::leave_thread();
+    KERNEL_LOCK.lock();
     let tcb = tf.get_tcb();
+    /* The thread was deleted by another core while this one trapped out of it */
+    if !crate::scheduler::is_current(tcb) {
+        crate::SCHEDULER.get().activate();
+    }
     let _ret = match Syndrome::from(arch::get_esr()) {
         Svc(1) => crate::syscall::handle_syscall(tcb),
+        Svc(num) => {
+            let fault = SysFault::new_unknown_syscall(tf.get_elr() as u64, num);
+            handle_fault(tcb, fault)
+        }
         InstructionAbort { kind, level } => {
             let fault_addr = arch::get_far();
             let fault = SysFault::new_prefetch_fault(fault_addr, level, kind.into());
-            handle_vfault(tcb, fault)
+            handle_fault(tcb, fault)
         }
         DataAbort { kind, level } => {
             let fault_addr = arch::get_far();
             let fault = SysFault::new_data_fault(fault_addr, level, kind.into());
-            handle_vfault(tcb, fault)
+            handle_fault(tcb, fault)
         }
         syn => {
-            panic!(
-                "Unhandled synchronous trap: {:?} thread_id {:x} elr {:x} tcb {:x?}",
-                syn,
-                tcb.thread_id(),
-                tf.get_elr(),
-                tf
-            );
+            let esr = arch::get_esr();
+            let fault = SysFault::new_user_exception(tf.get_elr() as u64, syn.into(), esr);
+            handle_fault(tcb, fault)
         }
     };
 }
@@ -209,12 +251,21 @@ pub unsafe extern "C" fn lower64_sync_handler(tf: &mut TrapFrame) -> ! {
 pub unsafe extern "C" fn lower64_irq_handler(tf: &mut TrapFrame) -> ! {
     use super::generic_timer::Timer;
 
+    crate::scheduler::leave_thread();
+    KERNEL_LOCK.lock();
     let cpuid = cpuid();
     let tcb = tf.get_tcb();
     let mut timer = Timer::new();
     if timer.is_pending(cpuid) {
-        tcb.timeslice_sub(crate::TICK as usize);
+        /* Unless another core deleted the thread in the meantime */
+        if crate::scheduler::is_current(tcb) {
+            tcb.timeslice_sub(crate::TICK as usize);
+        }
         timer.tick_in(crate::TICK);
+        TIMEOUT_QUEUE.get().expire(timer.counter());
+    } else if ipi::is_pending(cpuid) {
+        /* Another core queued threads here, picking them up is all there is to do */
+        ipi::take(cpuid);
     } else {
         INTERRUPT_CONTROLLER.lock().receive_irq();
     }
@@ -232,10 +283,18 @@ pub unsafe extern "C" fn unknown_exception_handler(tcb: &mut TcbObj) -> ! {
 pub unsafe extern "C" fn irq_trap() -> ! {
     use super::generic_timer::Timer;
 
+    KERNEL_LOCK.lock();
+    let cpuid = cpuid();
     // INTERRUPT_CONTROLLER.lock().receive_irq();
-    super::boot::IDLE_THREADS
-        .get_mut()
-        .timeslice_sub(crate::TICK as usize);
-    Timer::new().tick_in(crate::TICK);
+    if ipi::is_pending(cpuid) {
+        ipi::take(cpuid);
+    } else {
+        super::boot::IDLE_THREADS
+            .get_mut()
+            .timeslice_sub(crate::TICK as usize);
+        let mut timer = Timer::new();
+        timer.tick_in(crate::TICK);
+        TIMEOUT_QUEUE.get().expire(timer.counter());
+    }
     crate::SCHEDULER.get_mut().activate();
 }
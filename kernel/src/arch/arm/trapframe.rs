@@ -3,11 +3,13 @@ use crate::prelude::*;
 use crate::syscall::{MsgInfo, RespInfo};
 use core::arch::asm;
 use core::fmt::{Debug, Error, Formatter};
+use sysapi::thread::{UserContext, SPSR_SS};
 
 const EL1h: usize = 0b0101;
 const EL0t: usize = 0b0000;
 const AARCH64: usize = 0b0 << 4;
 const FIRQ_MASK: usize = 0b1 << 6;
+const NZCV_MASK: usize = 0b1111 << 28;
 
 #[repr(C)]
 #[derive(Default, Clone)]
@@ -16,6 +18,7 @@ pub struct TrapFrame {
     sp: usize,
     elr: usize,
     spsr: usize,
+    tpidr: usize,
 }
 impl Debug for TrapFrame {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
@@ -50,9 +53,11 @@ impl Debug for TrapFrame {
             .field("x27", &self.x_regs[27])
             .field("x28", &self.x_regs[28])
             .field("x29", &self.x_regs[29])
+            .field("x30", &self.x_regs[30])
             .field("sp", &self.sp)
             .field("elr", &self.elr)
             .field("spsr", &self.spsr)
+            .field("tpidr", &self.tpidr)
             .finish()
     }
 }
@@ -64,6 +69,7 @@ impl TrapFrame {
             sp: 0,
             elr: 0,
             spsr: 0,
+            tpidr: 0,
         }
     }
 
@@ -76,6 +82,8 @@ impl TrapFrame {
             msr     spsr_el1, x23
             msr     elr_el1, x22
             msr     sp_el0, x21
+            ldr     x24, [sp, #16 * 17]
+            msr     tpidr_el0, x24
             ldp     x28, x29, [sp, #16 * 14]
             ldp     x26, x27, [sp, #16 * 13]
             ldp     x24, x25, [sp, #16 * 12]
@@ -119,6 +127,10 @@ impl TrapFrame {
         self.spsr = spsr;
     }
 
+    pub fn single_step(&self) -> bool {
+        self.spsr & SPSR_SS != 0
+    }
+
     pub fn get_sp(&mut self) -> usize {
         self.sp
     }
@@ -127,6 +139,30 @@ impl TrapFrame {
         self.sp = sp;
     }
 
+    pub fn set_tpidr(&mut self, tpidr: usize) {
+        self.tpidr = tpidr;
+    }
+
+    pub fn get_context(&self, ctx: &mut UserContext) {
+        ctx.x = self.x_regs;
+        ctx.sp = self.sp;
+        ctx.elr = self.elr;
+        ctx.spsr = self.spsr;
+        ctx.tpidr = self.tpidr;
+    }
+
+    /*
+     * A user thread can change the condition flags and ask for a single step, but not its
+     * exception level or masks
+     */
+    pub fn set_context(&mut self, ctx: &UserContext) {
+        self.x_regs = ctx.x;
+        self.sp = ctx.sp;
+        self.elr = ctx.elr;
+        self.spsr = ctx.spsr & (NZCV_MASK | SPSR_SS) | FIRQ_MASK | AARCH64 | EL0t;
+        self.tpidr = ctx.tpidr;
+    }
+
     pub fn get_mr(&self, idx: usize) -> usize {
         self.x_regs[idx]
     }
@@ -0,0 +1,99 @@
+use crate::NCPU;
+use spin::Mutex;
+use vspace::arch::mmu::{invalidate_local_tlb_all, invalidate_tlb_asid_is};
+use vspace::PhysAddr;
+
+/*
+ * Fewer than the 16 bits the hardware offers, so that finding the ASID of a root table stays a
+ * short scan.
+ */
+const ASID_BITS: usize = 8;
+const NUM_ASIDS: usize = 1 << ASID_BITS;
+
+pub static ASID_ALLOCATOR: Mutex<AsidAllocator> = Mutex::new(AsidAllocator::new());
+
+/*
+ * Hands out ASIDs to root tables the first time one of their threads runs, so TLB entries of
+ * different VSpaces never mix. When none is left, a new generation starts: every ASID is free
+ * again except the ones the cores are running with, and each core flushes its TLB before it
+ * switches to another VSpace. A core in its idle thread runs with none.
+ *
+ * ASID 0 is never handed out.
+ */
+pub struct AsidAllocator {
+    /* The root table holding each ASID in the current generation, 0 if it is free */
+    roots: [usize; NUM_ASIDS],
+    next: usize,
+    active: [usize; NCPU],
+    flush_pending: [bool; NCPU],
+}
+
+impl AsidAllocator {
+    pub const fn new() -> Self {
+        Self {
+            roots: [0; NUM_ASIDS],
+            next: 1,
+            active: [0; NCPU],
+            flush_pending: [false; NCPU],
+        }
+    }
+
+    /* The ASID `root` holds in the current generation. `hint` is where it was seen last. */
+    pub fn lookup(&self, root: PhysAddr, hint: usize) -> Option<usize> {
+        if hint != 0 && hint < NUM_ASIDS && self.roots[hint] == root.0 {
+            return Some(hint);
+        }
+        (1..NUM_ASIDS).find(|asid| self.roots[*asid] == root.0)
+    }
+
+    /* The ASID `cpu` has to run threads of `root` with */
+    pub fn activate(&mut self, cpu: usize, root: PhysAddr, hint: usize) -> usize {
+        let asid = match self.lookup(root, hint) {
+            Some(asid) => asid,
+            None => self.allocate(root),
+        };
+
+        if core::mem::replace(&mut self.flush_pending[cpu], false) {
+            invalidate_local_tlb_all();
+        }
+        self.active[cpu] = asid;
+        asid
+    }
+
+    /* `cpu` switched to its idle thread, which has no VSpace to keep an ASID for */
+    pub fn deactivate(&mut self, cpu: usize) {
+        self.active[cpu] = 0;
+    }
+
+    /* Give back the ASID of a root table that is going away, with its TLB entries */
+    pub fn release(&mut self, root: PhysAddr) {
+        if let Some(asid) = self.lookup(root, 0) {
+            self.roots[asid] = 0;
+            invalidate_tlb_asid_is(asid);
+        }
+    }
+
+    fn allocate(&mut self, root: PhysAddr) -> usize {
+        let asid = match (self.next..NUM_ASIDS).find(|asid| self.roots[*asid] == 0) {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                (1..NUM_ASIDS).find(|asid| self.roots[*asid] == 0).unwrap()
+            }
+        };
+        self.roots[asid] = root.0;
+        self.next = asid + 1;
+        asid
+    }
+
+    fn rollover(&mut self) {
+        let mut roots = [0; NUM_ASIDS];
+        for asid in self.active.iter() {
+            roots[*asid] = self.roots[*asid];
+        }
+
+        self.roots = roots;
+        self.next = 1;
+        self.flush_pending = [true; NCPU];
+    }
+}
@@ -1,23 +1,31 @@
-use crate::objects::{CNodeEntry, CNodeLookupErr, CNodeObj};
+use core::convert::TryFrom;
 
-pub struct CSpace<'a>(pub &'a mut CNodeObj);
+use crate::objects::{CNodeCap, CNodeEntry, CNodeLookupErr, CNodeObj, CapRaw};
+use sysapi::objects::CNODE_DEPTH;
 
-impl<'a> core::ops::Deref for CSpace<'a> {
+/*
+ * A thread's view of its capability space. It keeps a copy of the root CNode capability, so that
+ * lookups go through the root's guard and descend into nested CNodes.
+ */
+pub struct CSpace(CNodeEntry);
+
+impl core::ops::Deref for CSpace {
     type Target = CNodeObj;
     fn deref(&self) -> &Self::Target {
-        self.0
+        self.root().as_object_mut()
     }
 }
 
-impl<'a> CSpace<'a> {
-    pub fn lookup_slot(&self, idx: usize) -> Result<&CNodeEntry, CNodeLookupErr> {
-        let slot = self.0.get(idx).ok_or(CNodeLookupErr::CNodeMiss(idx))?;
-        Ok(unsafe { &*(slot as *const CNodeEntry) })
+impl CSpace {
+    pub fn new(root: CapRaw) -> Self {
+        Self(CNodeEntry::new(root))
+    }
+
+    pub fn root(&self) -> CNodeCap {
+        CNodeCap::try_from(&self.0).unwrap()
     }
-}
 
-impl<'a> core::ops::DerefMut for CSpace<'a> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self.0
+    pub fn lookup_slot(&self, cptr: usize) -> Result<&'static CNodeEntry, CNodeLookupErr> {
+        self.root().resolve_address(cptr, CNODE_DEPTH)
     }
 }
@@ -4,24 +4,37 @@ use spin::Mutex;
 
 use crate::prelude::*;
 
-use crate::objects::{CNodeEntry, CapRaw, EndpointCap, NullCap};
+use crate::objects::{CNodeEntry, CapRaw, NotificationCap, NullCap};
 use crate::plat::interrupt::Controller;
 
 const NUM_IRQ: usize = 64;
 
 pub struct InterruptController {
-    IrqEp: [CapRaw; NUM_IRQ],
+    IrqNtfn: [CapRaw; NUM_IRQ],
 }
 
 impl InterruptController {
     pub const fn new() -> Self {
         Self {
-            IrqEp: [NullCap::mint(); NUM_IRQ],
+            IrqNtfn: [NullCap::mint(); NUM_IRQ],
         }
     }
 
-    pub fn attach_irq(&mut self, irq: usize, ep: CNodeEntry) {
-        self.IrqEp[irq] = ep.get();
+    pub fn attach_irq(&mut self, irq: usize, ntfn: CNodeEntry) {
+        self.IrqNtfn[irq] = ntfn.get();
+    }
+
+    /* Mask the irqs delivered to the notification at `paddr`, which is going away */
+    pub fn detach_ntfn(&mut self, paddr: usize) {
+        for irq in 0..NUM_IRQ {
+            let cap = Cell::new(self.IrqNtfn[irq]);
+            let attached =
+                NotificationCap::try_from(&cap).map_or(false, |ntfn| ntfn.paddr().0 == paddr);
+            if attached {
+                Controller::new().disable(irq);
+                self.IrqNtfn[irq] = NullCap::mint();
+            }
+        }
     }
 
     pub fn receive_irq(&self) {
@@ -29,11 +42,12 @@ impl InterruptController {
 
         Controller::new().disable(irq);
 
-        let cap = Cell::new(self.IrqEp[irq]);
+        let cap = Cell::new(self.IrqNtfn[irq]);
+        let ntfn =
+            NotificationCap::try_from(&cap).expect("Receiving interrupt from unattached irq!");
 
-        EndpointCap::try_from(&cap)
-            .expect("Receiving interrupt from unattached irq!")
-            .do_set_signal(1 << irq);
+        /* Unbadged notifications see which irq fired */
+        ntfn.signal(ntfn.badge().unwrap_or(1 << irq));
     }
 
     #[allow(dead_code)]
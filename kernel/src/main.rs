@@ -16,12 +16,14 @@ mod macros;
 mod console;
 #[macro_use]
 mod arch;
+mod asid;
 mod cspace;
 mod interrupt;
 mod objects;
 mod plat;
 mod scheduler;
 mod syscall;
+mod timeout;
 mod utils;
 mod vspace;
 
@@ -9,6 +9,7 @@ use super::*;
 pub enum CNodeLookupErr {
     CNodeMiss(usize),
     GuardError,
+    DepthMismatch,
 }
 
 impl From<CNodeLookupErr> for SysError {
@@ -29,6 +30,22 @@ impl From<CNodeLookupErr> for SysError {
 
 pub type CNodeEntry = Cell<CapRaw>;
 
+/* Whether a CNode of `radix_bits` can take `guard`, `guard_bits` long, without resolving more
+ * than a whole cptr */
+pub fn guard_is_valid(radix_bits: usize, guard: usize, guard_bits: usize) -> bool {
+    guard_bits <= CNODE_DEPTH - radix_bits && cptr_bits(guard, 0, guard_bits) == guard
+}
+
+/* `bits` bits of `cptr` starting at bit `shift`, tolerating shifts and widths of a full word */
+fn cptr_bits(cptr: usize, shift: usize, bits: usize) -> usize {
+    let value = cptr.checked_shr(shift as u32).unwrap_or(0);
+    if bits >= CNODE_DEPTH {
+        value
+    } else {
+        value & MASK!(bits)
+    }
+}
+
 pub type CNodeObj = [CNodeEntry];
 
 /* Asserting size of a CNodeEntry aligns power of 2 */
@@ -48,7 +65,7 @@ pub type CNodeCap<'a> = CapRef<'a, CNodeObj>;
 
 impl<'a> CNodeCap<'a> {
     const GUARD_SZ_OFFSET: usize = 0;
-    const GUARD_SZ_BITS: usize = 4;
+    const GUARD_SZ_BITS: usize = 6;
     const RADIX_SZ_OFFSET: usize = Self::GUARD_SZ_OFFSET + Self::GUARD_SZ_BITS;
     const RADIX_SZ_BITS: usize = 6;
     //    const ADDR_OFFSET    : usize = Self::RADIX_OFFSET + Self::RADIX_SZ_BITS;
@@ -108,44 +125,72 @@ impl<'a> CNodeCap<'a> {
     }
 
     pub fn guard(&self) -> usize {
-        self.raw.get().arg2 & !MASK!(self.guard_bits())
+        cptr_bits(self.raw.get().arg2, 0, self.guard_bits())
+    }
+
+    pub fn set_guard(&self, guard: usize, guard_bits: usize) -> SysResult<()> {
+        if !guard_is_valid(self.radix_bits(), guard, guard_bits) {
+            return Err(SysError::InvalidValue);
+        }
+
+        let mut raw = self.raw();
+        raw.arg1 = raw.arg1 & !(MASK!(Self::GUARD_SZ_BITS) << Self::GUARD_SZ_OFFSET)
+            | guard_bits << Self::GUARD_SZ_OFFSET;
+        raw.arg2 = guard;
+        self.raw.set(raw);
+        Ok(())
     }
 
     pub fn size(&self) -> usize {
         1 << self.radix_bits()
     }
 
-    // fn resolve_address(&self, idx: usize, depth: usize) -> Result<&CNodeEntry, CNodeLookupErr> {
-    //     let mut cnode_slot = self.raw;
-    //     let mut n_bits = depth;
+    /*
+     * Resolve the lowest `depth` bits of `cptr`, most significant first. Every CNode on the way
+     * consumes its guard, which has to match, followed by its radix bits, which index the slot.
+     * The walk stops once all bits are consumed or the slot reached does not hold a CNode.
+     */
+    pub fn resolve_address(
+        &self,
+        cptr: usize,
+        depth: usize,
+    ) -> Result<&'static CNodeEntry, CNodeLookupErr> {
+        if depth > CNODE_DEPTH {
+            return Err(CNodeLookupErr::DepthMismatch);
+        }
 
-    //     while let Ok(cnode) = CNodeCap::try_from(cnode_slot) {
-    //         let radix_bits = cnode.radix_bits();
-    //         let guard_bits = cnode.guard_bits();
-    //         let level_bits = radix_bits + guard_bits;
+        let mut cnode: CNodeCap = *self;
+        let mut n_bits = depth;
 
-    //         let guard = (idx >> ((n_bits - guard_bits) & !0usize )) & MASK!(guard_bits);
-    //         if cnode.guard() != guard {
-    //             return Err(CNodeLookupErr::GuardError);
-    //         }
+        loop {
+            let radix_bits = cnode.radix_bits();
+            let guard_bits = cnode.guard_bits();
+            let level_bits = radix_bits + guard_bits;
 
-    //         if level_bits > n_bits {
-    //             return Err(CNodeLookupErr::GuardError);
-    //         }
+            if level_bits == 0 || level_bits > n_bits {
+                return Err(CNodeLookupErr::DepthMismatch);
+            }
 
-    //         let offset = (idx >> (n_bits - level_bits)) & MASK!(radix_bits);
-    //         let cap = unsafe { &*(&cnode.as_object()[offset] as *const CNodeEntry) };
+            let guard = cptr_bits(cptr, n_bits - guard_bits, guard_bits);
+            if cnode.guard() != guard {
+                return Err(CNodeLookupErr::GuardError);
+            }
 
-    //         if n_bits <= level_bits {
-    //             return Ok(cap);
-    //         }
+            let offset = cptr_bits(cptr, n_bits - level_bits, radix_bits);
+            let node: &'static [CNodeEntry] = cnode.as_object_mut();
+            let slot = node.get(offset).ok_or(CNodeLookupErr::CNodeMiss(offset))?;
 
-    //         n_bits -= level_bits;
-    //         cnode_slot = cap;
-    //     };
+            n_bits -= level_bits;
+            if n_bits == 0 {
+                return Ok(slot);
+            }
 
-    //     Ok(cnode_slot)
-    // }
+            match CNodeCap::try_from(slot) {
+                Ok(next) => cnode = next,
+                Err(_) => return Ok(slot),
+            }
+        }
+    }
 
     pub fn lookup_slot(&self, idx: usize) -> Result<&CNodeEntry, CNodeLookupErr> {
         // Ok(unsafe { &*(&self.as_object()[idx] as *const CNodeEntry) })
@@ -154,12 +199,20 @@ impl<'a> CNodeCap<'a> {
             .get(idx)
             .ok_or(CNodeLookupErr::CNodeMiss(idx))?;
         Ok(unsafe { &*(slot as *const CNodeEntry) })
+    }
 
-        // self.resolve_address(idx, CNODE_DEPTH)
+    /* Called when the last capability to the CNode is deleted, which deletes what it holds */
+    pub fn finalize(&self) -> SysResult<()> {
+        for slot in self.as_object() {
+            if slot.get().cap_type() != ObjType::NullObj {
+                cap_delete(slot)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn derive(&self, dst: &NullCap) -> SysResult<()> {
-        dst.raw.set(self.raw());
+        cnode_entry_copy(self.raw, dst.raw);
         Ok(())
     }
 
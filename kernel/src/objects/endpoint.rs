@@ -2,16 +2,16 @@ use super::*;
 use crate::objects::tcb::ThreadState;
 use crate::syscall::{MsgInfo, RespInfo};
 use crate::utils::tcb_queue::TcbQueue;
+use core::mem::size_of;
 use core::num::NonZeroUsize;
-use num_traits::FromPrimitive;
 use sysapi::fault::Fault;
+use sysapi::ipc::{IPC_MAX_ARGS, IPC_MAX_CAPS, IPC_MAX_MSG_LEN};
 use sysapi::syscall::SyscallOp;
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum EpState {
     Free,
     Sending,
     Receiving,
-    SignalPending,
 }
 
 impl core::default::Default for EpState {
@@ -23,82 +23,47 @@ impl core::default::Default for EpState {
 #[derive(Debug, Default)]
 pub struct EndpointObj {
     queue: TcbQueue,
-    signal: Cell<u64>,
-    irq: Cell<u64>,
 }
 
-pub type EndpointCap<'a> = CapRef<'a, EndpointObj>;
-
-#[derive(Clone, Copy, Debug, FromPrimitive)]
-pub enum AttachType {
-    Unattached = 0,
-    IrqController = 1,
-}
+pub const ENDPOINT_OBJ_SZ: usize = size_of::<EndpointObj>().next_power_of_two();
+pub const ENDPOINT_OBJ_BIT_SZ: usize = ENDPOINT_OBJ_SZ.trailing_zeros() as usize;
 
-#[derive(Clone, Copy, Debug)]
-pub enum Attach {
-    Unattached,
-    Irq(usize),
-}
+pub type EndpointCap<'a> = CapRef<'a, EndpointObj>;
 
 /* Capability Entry Field Definition
  * -------------------------------------------------
- * |             paddr                    |    |W|R|
- * |              52                      |    |1|1|
+ * |                    paddr                      |
+ * |                      64                       |
+ * -------------------------------------------------
+ * |                                       |G|W|R|
+ * |                                       |1|1|1|
  * -------------------------------------------------
  * |                    Badge                      |
  * |                      64                       |
  * -------------------------------------------------
  */
+const RIGHTS_BITS: usize = 3;
 
 impl<'a> EndpointCap<'a> {
-    pub const ADDR_MASK: usize = !MASK!(12); // TODO: check real obj type
-
-    pub fn mint(paddr: usize, badge: usize) -> CapRaw {
-        CapRaw::new(
-            paddr,
-            AttachType::Unattached as usize,
-            badge,
-            None,
-            None,
-            ObjType::Endpoint,
-        )
-    }
-
-    pub fn set_attach(&self, attach: Attach) {
-        let mut cap = self.raw();
-
-        match attach {
-            Attach::Unattached => {
-                cap.arg1 = AttachType::Unattached as usize;
-            }
-            Attach::Irq(i) => {
-                cap.arg1 = AttachType::IrqController as usize;
-                cap.arg2 = i;
-
-                let irq = self.irq.get() | (1 << i);
-                self.irq.set(irq);
-            }
-        }
+    pub const ADDR_MASK: usize = !MASK!(ENDPOINT_OBJ_BIT_SZ);
 
-        self.raw.replace(cap);
+    pub fn mint(paddr: usize, badge: usize, rights: CapRights) -> CapRaw {
+        CapRaw::new(paddr, rights.bits(), badge, None, None, ObjType::Endpoint)
     }
 
-    pub fn get_attach(&self) -> Attach {
-        let cap = self.raw();
-        let attach_type = AttachType::from_usize(cap.arg1).unwrap();
+    pub fn rights(&self) -> CapRights {
+        CapRights::from_bits(self.raw().arg1 & MASK!(RIGHTS_BITS))
+    }
 
-        match attach_type {
-            AttachType::Unattached => Attach::Unattached,
-            AttachType::IrqController => Attach::Irq(cap.arg2),
+    pub fn check_rights(&self, rights: CapRights) -> SysResult<()> {
+        if self.rights().contains(rights) {
+            Ok(())
+        } else {
+            Err(SysError::InsufficientRights)
         }
     }
 
     pub fn state(&self) -> EpState {
-        if self.signal.get() != 0 {
-            return EpState::SignalPending;
-        }
-
         let head = self.queue.head();
         if head.is_none() {
             return EpState::Free;
@@ -114,22 +79,6 @@ impl<'a> EndpointCap<'a> {
         }
     }
 
-    pub fn do_set_signal(&mut self, sig: u64) {
-        let state = self.state();
-        let signal = self.signal.get() | sig;
-        self.signal.set(signal);
-
-        if let EpState::Receiving = state {
-            let receiver = self.queue.dequeue().unwrap();
-            receiver.set_mr(1, self.signal.take() as usize);
-            receiver.set_state(ThreadState::Ready);
-            receiver.set_respinfo(RespInfo::new_notification());
-            crate::SCHEDULER.get_mut().push(receiver);
-
-            self.signal.set(0);
-        }
-    }
-
     pub fn handle_send(&self, info: MsgInfo, tcb: &mut TcbObj) -> SysResult<()> {
         match self.state() {
             EpState::Receiving => {
@@ -145,19 +94,19 @@ impl<'a> EndpointCap<'a> {
                     false,
                 )?;
                 receiver.set_state(ThreadState::Ready);
-                crate::SCHEDULER.get_mut().push(receiver);
+                crate::SCHEDULER.push(receiver);
 
                 Ok(())
             }
             _ => {
-                // TODO: check if send slot is legit if info.cap_transfer is set
+                // TODO: check if send slot is legit if info.caps is set
                 tcb.detach();
                 tcb.set_state(ThreadState::Sending);
                 let badge = self.badge();
                 if let Some(b) = badge {
                     tcb.set_sending_badge(b);
                 }
-                self.queue.enqueue(tcb);
+                self.queue.enqueue_by_priority(tcb);
                 Ok(())
             }
         }
@@ -168,29 +117,17 @@ impl<'a> EndpointCap<'a> {
             EpState::Free => {
                 tcb.detach();
                 tcb.set_state(ThreadState::Receiving);
-                self.queue.enqueue(tcb);
-
-                // TODO: check if recv slot is legit if info.cap_transfer is set
-                if self.irq.get() != 0 {
-                    unsafe {
-                        crate::interrupt::INTERRUPT_CONTROLLER
-                            .lock()
-                            .listen_irq_mask(self.irq.get());
-                    }
-                }
+                self.queue.enqueue_by_priority(tcb);
+
+                // TODO: check if recv slot is legit if info.caps is set
 
                 Ok(())
             }
             EpState::Receiving => {
-                // TODO: check if recv slot is legit if info.cap_transfer is set
+                // TODO: check if recv slot is legit if info.caps is set
                 tcb.detach();
                 tcb.set_state(ThreadState::Receiving);
-                self.queue.enqueue(tcb);
-                Ok(())
-            }
-            EpState::SignalPending => {
-                tcb.set_mr(1, self.signal.take() as usize);
-                tcb.set_respinfo(RespInfo::new_notification());
+                self.queue.enqueue_by_priority(tcb);
                 Ok(())
             }
             EpState::Sending => {
@@ -215,7 +152,7 @@ impl<'a> EndpointCap<'a> {
                         tcb.set_reply(Some(sender));
                     } else {
                         sender.set_state(ThreadState::Ready);
-                        crate::SCHEDULER.get_mut().push(sender);
+                        crate::SCHEDULER.push(sender);
                     }
 
                     Ok(())
@@ -235,7 +172,7 @@ impl<'a> EndpointCap<'a> {
                 sender.detach();
                 receiver.set_state(ThreadState::Ready);
                 receiver.set_reply(Some(sender));
-                crate::SCHEDULER.get_mut().push(receiver);
+                crate::SCHEDULER.push(receiver);
 
                 Ok(ret)
             }
@@ -246,7 +183,7 @@ impl<'a> EndpointCap<'a> {
                 if let Some(b) = badge {
                     sender.set_sending_badge(b);
                 }
-                self.queue.enqueue(sender);
+                self.queue.enqueue_by_priority(sender);
                 Ok(())
             }
         }
@@ -271,7 +208,7 @@ impl<'a> EndpointCap<'a> {
                 receiver.set_respinfo(RespInfo::new_fault_resp(3, badge.is_some()));
                 receiver.set_state(ThreadState::Ready);
                 receiver.set_reply(Some(sender));
-                crate::SCHEDULER.get_mut().push(receiver);
+                crate::SCHEDULER.push(receiver);
 
                 Ok(())
             }
@@ -280,17 +217,24 @@ impl<'a> EndpointCap<'a> {
                 if let Some(b) = badge {
                     sender.set_sending_badge(b);
                 }
-                self.queue.enqueue(sender);
+                self.queue.enqueue_by_priority(sender);
                 Ok(())
             }
         }
     }
 
     pub fn derive(&self, dst: &NullCap) -> SysResult<()> {
-        dst.raw.set(self.raw());
+        cnode_entry_copy(self.raw, dst.raw);
         Ok(())
     }
 
+    /* Called when the last capability to the endpoint is deleted. Blocked threads get IpcAborted */
+    pub fn finalize(&self) {
+        while let Some(tcb) = self.queue.dequeue() {
+            tcb.abort_ipc();
+        }
+    }
+
     pub fn badge(&self) -> Option<usize> {
         let b = self.raw().arg2;
         if b == 0 {
@@ -301,14 +245,19 @@ impl<'a> EndpointCap<'a> {
     }
 
     pub fn set_badge(&self, badge: usize) {
-        self.raw().arg2 = badge;
+        let mut raw = self.raw();
+        raw.arg2 = badge;
+        self.raw.set(raw);
     }
 
-    pub fn derive_badged(&self, badge: Option<NonZeroUsize>) -> CapRaw {
-        if badge.is_some() {
-            EndpointCap::mint(self.paddr().0, badge.map(|b| b.get()).unwrap_or(0))
+    pub fn derive_badged(&self, badge: Option<NonZeroUsize>, rights: CapRights) -> CapRaw {
+        let rights = self.rights() & rights;
+        if let Some(b) = badge {
+            EndpointCap::mint(self.paddr().0, b.get(), rights)
         } else {
-            self.raw()
+            let mut raw = self.raw();
+            raw.arg1 = raw.arg1 & !MASK!(RIGHTS_BITS) | rights.bits();
+            raw
         }
     }
 
@@ -317,8 +266,10 @@ impl<'a> EndpointCap<'a> {
         1
     }
 
-    pub fn debug_formatter(_f: &mut core::fmt::DebugStruct, _cap: &CapRaw) {
-        return;
+    pub fn debug_formatter(f: &mut core::fmt::DebugStruct, cap: &CapRaw) {
+        let c = Cell::new(*cap);
+        let c = EndpointCap::try_from(&c).unwrap();
+        f.field("rights", &c.rights()).field("badge", &c.badge());
     }
 }
 
@@ -331,41 +282,91 @@ pub fn do_ipc(
     is_call: bool,
     is_reply: bool,
 ) -> SysResult<()> {
-    let mut has_cap_trans = false;
-
-    let msglen = send_info.get_length();
-    for i in 1..=msglen {
+    let msglen = send_info.get_length().min(IPC_MAX_MSG_LEN);
+    for i in 1..=msglen.min(IPC_MAX_ARGS) {
         let data = send.get_mr(i);
         recv.set_mr(i, data);
     }
+    /* The rest is copied between the IPC buffers, and cut off if either thread has none */
+    let msglen = match (send.ipc_buffer(), recv.ipc_buffer()) {
+        (Some(send_buf), Some(recv_buf)) => {
+            if msglen > IPC_MAX_ARGS {
+                recv_buf.msg[IPC_MAX_ARGS..msglen]
+                    .copy_from_slice(&send_buf.msg[IPC_MAX_ARGS..msglen]);
+            }
+            msglen
+        }
+        _ => msglen.min(IPC_MAX_ARGS),
+    };
     if let Some(b) = badge {
         recv.set_mr(0, b);
     }
-    if recv_info.cap_transfer && send_info.cap_transfer {
-        let recv_cspace = recv.cspace()?;
-        let recv_idx = recv.get_mr(5);
-        let recv_slot = recv_cspace.lookup_slot(recv_idx)?;
-
-        let send_cspace = send.cspace()?;
-        let send_idx = send.get_mr(5);
-        let send_slot = send_cspace.lookup_slot(send_idx)?;
-
-        let recv_cap = NullCap::try_from(recv_slot)?;
-        recv_cap.insert_raw(send_slot.get());
-        send_slot.set(NullCap::mint());
-        has_cap_trans = true;
-    }
+    let ncaps = transfer_caps(send, send_info, recv, recv_info)?;
 
     recv.set_respinfo(RespInfo::ipc_resp(
         SysError::OK,
         msglen,
-        has_cap_trans,
+        ncaps,
         is_call,
         badge.is_some(),
     ));
+    /* The capabilities that did not go over stay in the slots of the sender */
     if !is_call && !is_reply {
-        send.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+        send.set_respinfo(RespInfo::ipc_resp(SysError::OK, 0, ncaps, false, false));
     }
 
     Ok(())
 }
+
+/*
+ * Slot of the `i`th capability to send or receive: MR5 for the first, `caps[i]` of the IPC buffer
+ * after. Like the message words passed in registers, the first one leaves `caps[0]` unused.
+ */
+fn cap_transfer_slot(tcb: &TcbObj, i: usize) -> usize {
+    match i {
+        0 => tcb.get_mr(5),
+        _ => tcb.ipc_buffer().unwrap().caps[i],
+    }
+}
+
+fn cap_transfer_count(tcb: &TcbObj, info: MsgInfo) -> usize {
+    match tcb.ipc_buffer() {
+        Some(_) => info.caps.min(IPC_MAX_CAPS),
+        None => info.caps.min(1),
+    }
+}
+
+/*
+ * Move as many capabilities as the receiver has slots for. The transfer stops at the first
+ * capability that cannot be moved. The receiver learns how many arrived, and so does the sender
+ * of a Send, whose other capabilities stay where they are.
+ */
+fn transfer_caps(
+    send: &TcbObj,
+    send_info: MsgInfo,
+    recv: &TcbObj,
+    recv_info: MsgInfo,
+) -> SysResult<usize> {
+    let ncaps = cap_transfer_count(send, send_info).min(cap_transfer_count(recv, recv_info));
+    if ncaps == 0 {
+        return Ok(0);
+    }
+
+    let send_cspace = send.cspace()?;
+    let recv_cspace = recv.cspace()?;
+    for i in 0..ncaps {
+        let send_slot = send_cspace.lookup_slot(cap_transfer_slot(send, i));
+        let recv_slot = recv_cspace.lookup_slot(cap_transfer_slot(recv, i));
+        let (send_slot, recv_slot) = match (send_slot, recv_slot) {
+            (Ok(s), Ok(r)) => (s, r),
+            _ => return Ok(i),
+        };
+        /* Listing a slot twice must not move a capability over another one */
+        if send_slot.get().cap_type() == ObjType::NullObj || NullCap::try_from(recv_slot).is_err() {
+            return Ok(i);
+        }
+        cnode_entry_move(send_slot, recv_slot);
+    }
+
+    Ok(ncaps)
+}
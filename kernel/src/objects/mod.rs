@@ -3,6 +3,7 @@ mod cnode;
 mod endpoint;
 mod interrupt;
 mod monitor;
+mod notification;
 mod nullcap;
 mod ram;
 mod reply;
@@ -21,10 +22,11 @@ pub use cnode::*;
 pub use endpoint::*;
 pub use interrupt::*;
 pub use monitor::*;
+pub use notification::*;
 pub use nullcap::*;
 pub use ram::*;
 pub use reply::*;
-pub use sysapi::objects::ObjType;
+pub use sysapi::objects::{CapRights, ObjType};
 pub use tcb::*;
 pub use traits::*;
 pub use untyped::*;
@@ -81,6 +83,20 @@ impl<'a, T: KernelObject + ?Sized> CapRef<'a, T> {
     }
 }
 
+fn cnode_entry_set_next(slot: NonNull<CNodeEntry>, next: Option<NonNull<CNodeEntry>>) {
+    let cap = unsafe { slot.as_ref() };
+    let mut raw = cap.get();
+    raw.set_next(next);
+    cap.set(raw);
+}
+
+fn cnode_entry_set_prev(slot: NonNull<CNodeEntry>, prev: Option<NonNull<CNodeEntry>>) {
+    let cap = unsafe { slot.as_ref() };
+    let mut raw = cap.get();
+    raw.set_prev(prev);
+    cap.set(raw);
+}
+
 pub fn cnode_entry_append_next(src: &CNodeEntry, dst: &CNodeEntry) {
     let mut src_raw = src.get();
     let mut dst_raw = dst.get();
@@ -98,6 +114,306 @@ pub fn cnode_entry_append_next(src: &CNodeEntry, dst: &CNodeEntry) {
     src.set(src_raw);
 }
 
+/*
+ * Put a plain copy of the capability in `src` into the empty `dst`, right after `src` in the
+ * derivation list.
+ */
+pub fn cnode_entry_copy(src: &CNodeEntry, dst: &CNodeEntry) {
+    let mut raw = src.get();
+    raw.set_revocable(false);
+    dst.set(raw);
+    cnode_entry_append_next(src, dst);
+}
+
+/*
+ * Splice `slot` out of the derivation list, linking its neighbours to each other.
+ */
+pub fn cnode_entry_unlink(slot: &CNodeEntry) {
+    let mut raw = slot.get();
+    let prev = raw.get_prev();
+    let next = raw.get_next();
+
+    prev.map(|prev_ptr| cnode_entry_set_next(prev_ptr, next));
+    next.map(|next_ptr| cnode_entry_set_prev(next_ptr, prev));
+
+    raw.set_prev(None);
+    raw.set_next(None);
+    slot.set(raw);
+}
+
+/*
+ * Move the capability in `src` into `dst` while keeping its position in the derivation list.
+ * `src` is left empty.
+ */
+pub fn cnode_entry_move(src: &CNodeEntry, dst: &CNodeEntry) {
+    let raw = src.get();
+    let dst_ptr = Some(NonNull::from(dst));
+
+    raw.get_prev()
+        .map(|prev_ptr| cnode_entry_set_next(prev_ptr, dst_ptr));
+    raw.get_next()
+        .map(|next_ptr| cnode_entry_set_prev(next_ptr, dst_ptr));
+
+    dst.set(raw);
+    src.set(NullCap::mint());
+}
+
+/*
+ * Exchange the capabilities in `a` and `b`, each keeping its place in the derivation list.
+ */
+pub fn cnode_entry_swap(a: &CNodeEntry, b: &CNodeEntry) {
+    let a_ptr = NonNull::from(a);
+    let b_ptr = NonNull::from(b);
+    let a_raw = a.get();
+    let b_raw = b.get();
+    a.set(b_raw);
+    b.set(a_raw);
+
+    /* Neighbouring entries pointed at each other, which is now the other slot */
+    for &(slot, other) in &[(a, b_ptr), (b, a_ptr)] {
+        let mut raw = slot.get();
+        if raw.get_prev() == Some(NonNull::from(slot)) {
+            raw.set_prev(Some(other));
+        }
+        if raw.get_next() == Some(NonNull::from(slot)) {
+            raw.set_next(Some(other));
+        }
+        slot.set(raw);
+    }
+
+    for slot in &[a, b] {
+        let raw = slot.get();
+        let slot_ptr = Some(NonNull::from(*slot));
+        raw.get_prev()
+            .map(|prev_ptr| cnode_entry_set_next(prev_ptr, slot_ptr));
+        raw.get_next()
+            .map(|next_ptr| cnode_entry_set_prev(next_ptr, slot_ptr));
+    }
+}
+
+/*
+ * Decide whether `child`, which follows `parent` in the derivation list, was derived from it.
+ *
+ * Only revocable capabilities (the ones created by retype, and badged endpoints or notifications
+ * minted from an unbadged one) have children. Objects carved out of an untyped are its children, and for every
+ * other type the children are the copies referring to the same object.
+ */
+fn cap_is_parent_of(parent: &CapRaw, child: &CapRaw) -> bool {
+    if !parent.is_revocable() {
+        return false;
+    }
+
+    match parent.cap_type() {
+        ObjType::NullObj => false,
+        ObjType::Untyped => {
+            let parent_raw = Cell::new(*parent);
+            let untyped = UntypedCap::try_from(&parent_raw).unwrap();
+            child.paddr >= parent.paddr && child.paddr < parent.paddr + untyped.size()
+        }
+        ObjType::Endpoint | ObjType::Notification => {
+            if child.cap_type() != parent.cap_type() || child.paddr != parent.paddr {
+                return false;
+            }
+            /* Both keep the badge in arg2 */
+            match parent.arg2 {
+                0 => true,
+                badge => !child.is_revocable() && child.arg2 == badge,
+            }
+        }
+        _ => child.cap_type() == parent.cap_type() && child.paddr == parent.paddr,
+    }
+}
+
+/*
+ * Whether any capability derived from the one in `slot` still exists. Like `cap_revoke`, only
+ * the entry right after `slot` has to be looked at.
+ */
+pub fn cap_has_children(slot: &CNodeEntry) -> bool {
+    slot.get().get_next().map_or(false, |next_ptr| {
+        let next = unsafe { next_ptr.as_ref() };
+        cap_is_parent_of(&slot.get(), &next.get())
+    })
+}
+
+/*
+ * Apply the data word of a CapMutate to the capability in `slot`. CNodes get a new guard, with the
+ * guard size in the low 6 bits and the guard above it. Unbadged endpoints and notifications get
+ * `data` as badge.
+ */
+pub fn cap_mutate(slot: &CNodeEntry, data: usize) -> SysResult<()> {
+    match slot.get().cap_type() {
+        ObjType::CNode => {
+            /* Only this cap changes. A TCB keeps its own copy of its root, which stays as it
+             * was until the thread is configured again */
+            let cap = CNodeCap::try_from(slot).unwrap();
+            cap.set_guard(data >> 6, data & MASK!(6))
+        }
+        ObjType::Endpoint if data != 0 => {
+            let cap = EndpointCap::try_from(slot).unwrap();
+            if cap.badge().is_some() {
+                return Err(SysError::UnableToDerive);
+            }
+            cap.set_badge(data);
+            Ok(())
+        }
+        ObjType::Notification if data != 0 => {
+            let cap = NotificationCap::try_from(slot).unwrap();
+            if cap.badge().is_some() {
+                return Err(SysError::UnableToDerive);
+            }
+            cap.set_badge(data);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/*
+ * Whether two capabilities refer to the same object. An untyped carved out at the start of its
+ * parent has the same address, so untypeds also have to agree on their size.
+ */
+fn same_object(a: &CapRaw, b: &CapRaw) -> bool {
+    if a.cap_type() != b.cap_type() || a.paddr != b.paddr {
+        return false;
+    }
+    match a.cap_type() {
+        ObjType::NullObj => false,
+        ObjType::Untyped => {
+            let (a, b) = (Cell::new(*a), Cell::new(*b));
+            let a = UntypedCap::try_from(&a).unwrap();
+            let b = UntypedCap::try_from(&b).unwrap();
+            a.bit_size() == b.bit_size()
+        }
+        _ => true,
+    }
+}
+
+/*
+ * The other capabilities referring to the object of `slot`. Copies and capabilities derived from
+ * them are always put after the one they come from, so all capabilities of an object form one
+ * run in the derivation list, which ends at the first capability of another object.
+ */
+fn cap_object_peers<'a>(slot: &'a CNodeEntry) -> impl Iterator<Item = &'a CNodeEntry> + 'a {
+    let raw = slot.get();
+    let peer = move |ptr: Option<NonNull<CNodeEntry>>| {
+        ptr.map(|p| unsafe { &*p.as_ptr() })
+            .filter(|entry: &&CNodeEntry| same_object(&raw, &entry.get()))
+    };
+    let before = core::iter::successors(peer(raw.get_prev()), move |e| peer(e.get().get_prev()));
+    let after = core::iter::successors(peer(raw.get_next()), move |e| peer(e.get().get_next()));
+    before.chain(after)
+}
+
+/* Whether the capability in `slot` is the last one referring to its object */
+fn cap_is_final(slot: &CNodeEntry) -> bool {
+    cap_object_peers(slot).next().is_none()
+}
+
+/*
+ * A copy made before the table was mapped does not know about the mapping. When the copy that
+ * does goes away, another one takes the mapping over, so that deleting the last copy still
+ * unmaps the table before its memory can be reset.
+ */
+fn vtable_cap_hand_over_mapping(cap: &VTableCap) -> SysResult<()> {
+    let (vaddr, root) = (cap.mapped_vaddr(), cap.mapped_root());
+    let peers = || cap_object_peers(cap.raw).map(|peer| VTableCap::try_from(peer).unwrap());
+
+    if peers().any(|peer| peer.mapped_vaddr() == vaddr && peer.mapped_root() == root) {
+        return Ok(());
+    }
+    match peers().find(|peer| peer.mapped_vaddr() == 0) {
+        Some(peer) => peer.set_mapped_vaddr_root(vaddr, root, cap.mapped_level()),
+        /* Every other copy records a mapping of its own */
+        None => cap.unmap_vtable()?,
+    }
+    Ok(())
+}
+
+/*
+ * Delete the capability in `slot`. Mapped frames and tables are unmapped before the capability
+ * goes away, otherwise the mapping would outlive the only handle able to remove it. Deleting the
+ * last capability to an object finalizes it, so that no kernel link into it survives the reset
+ * of its untyped: a thread is destroyed, a root table frees its ASID, the threads blocked on an
+ * endpoint or notification are released and the capabilities in a CNode are deleted.
+ */
+pub fn cap_delete(slot: &CNodeEntry) -> SysResult<()> {
+    if let Ok(ram_cap) = RamCap::try_from(slot) {
+        if ram_cap.mapped_vaddr() != 0 {
+            ram_cap.unmap_page()?;
+        }
+    }
+
+    /* Copies of a table cap carry its mapping, which stays until the last of them is deleted */
+    if let Ok(vtable_cap) = VTableCap::try_from(slot) {
+        if cap_is_final(slot) {
+            if vtable_cap.mapped_vaddr() != 0 {
+                vtable_cap.unmap_vtable()?;
+            }
+            /* The table may come back as the root of another VSpace, or as anything else */
+            crate::asid::ASID_ALLOCATOR
+                .lock()
+                .release(vtable_cap.paddr());
+            crate::vspace::VSPACE_ROOTS
+                .lock()
+                .remove(vtable_cap.paddr());
+        } else if vtable_cap.mapped_vaddr() != 0 {
+            vtable_cap_hand_over_mapping(&vtable_cap)?;
+        }
+    }
+
+    if let Ok(tcb_cap) = TcbCap::try_from(slot) {
+        if cap_is_final(slot) {
+            unsafe { tcb_cap.get_obj_mut() }.finalize();
+        }
+    }
+
+    if let Ok(ep_cap) = EndpointCap::try_from(slot) {
+        if cap_is_final(slot) {
+            ep_cap.finalize();
+        }
+    }
+
+    if let Ok(ntfn_cap) = NotificationCap::try_from(slot) {
+        /* The copy the bound thread keeps, e.g. revoked from under it */
+        let bound_tcb = ntfn_cap.bound_tcb();
+        if bound_tcb.map_or(false, |tcb| unsafe { tcb.as_ref() }.holds_bound_notification(slot)) {
+            ntfn_cap.bind_tcb(None);
+        }
+        if cap_is_final(slot) {
+            ntfn_cap.finalize();
+        }
+    }
+
+    if let Ok(cnode_cap) = CNodeCap::try_from(slot) {
+        if cap_is_final(slot) {
+            cnode_cap.finalize()?;
+        }
+    }
+
+    cnode_entry_unlink(slot);
+    slot.set(NullCap::mint());
+    Ok(())
+}
+
+/*
+ * Delete every capability derived from the one in `slot`. The capability itself stays.
+ * Children always directly follow their parent in the derivation list, so the walk stops at the
+ * first entry that is not derived from `slot`.
+ */
+pub fn cap_revoke(slot: &CNodeEntry) -> SysResult<()> {
+    let parent = slot.get();
+
+    while let Some(next_ptr) = slot.get().get_next() {
+        let next = unsafe { next_ptr.as_ref() };
+        if !cap_is_parent_of(&parent, &next.get()) {
+            break;
+        }
+        cap_delete(next)?;
+    }
+
+    Ok(())
+}
+
 impl<'a, T: KernelObject + Sized> CapRef<'a, T> {
     fn obj_ptr(&self) -> NonNull<T> {
         NonNull::new(self.vaddr() as *mut T).unwrap()
@@ -153,6 +469,7 @@ pub struct CapRaw {
     pub cap_type: ObjType,
     pub prev: Option<NonNull<CNodeEntry>>,
     pub next: Option<NonNull<CNodeEntry>>,
+    revocable: bool,
 }
 
 impl CapRaw {
@@ -171,9 +488,25 @@ impl CapRaw {
             cap_type: cap_type,
             prev: prev,
             next: next,
+            revocable: false,
         }
     }
 
+    pub const fn into_revocable(self) -> Self {
+        Self {
+            revocable: true,
+            ..self
+        }
+    }
+
+    pub fn is_revocable(&self) -> bool {
+        self.revocable
+    }
+
+    pub fn set_revocable(&mut self, revocable: bool) {
+        self.revocable = revocable;
+    }
+
     pub fn cap_type(&self) -> ObjType {
         self.cap_type
     }
@@ -210,23 +543,65 @@ impl core::fmt::Debug for CapRaw {
             ObjType::Reply => CapRef::<ReplyObj>::debug_formatter(&mut formatter, self),
             ObjType::Monitor => CapRef::<MonitorObj>::debug_formatter(&mut formatter, self),
             ObjType::Interrupt => CapRef::<InterruptObj>::debug_formatter(&mut formatter, self),
+            ObjType::Notification => {
+                CapRef::<NotificationObj>::debug_formatter(&mut formatter, self)
+            }
         }
+        formatter.field("revocable", &self.is_revocable());
         formatter.field("prev", &self.get_prev());
         formatter.field("next", &self.get_next());
         formatter.finish()
     }
 }
 
-pub fn cap_derive(cap_slot: &CNodeEntry, badge: Option<NonZeroUsize>) -> SysResult<CapRaw> {
-    match cap_slot.get().cap_type() {
+/* Rights can only be masked off: the derived cap gets the intersection of the
+ * source rights and `rights`. Objects without rights ignore the mask. */
+pub fn cap_derive(
+    cap_slot: &CNodeEntry,
+    rights: CapRights,
+    badge: Option<NonZeroUsize>,
+) -> SysResult<CapRaw> {
+    let mut raw = match cap_slot.get().cap_type() {
         ObjType::Endpoint => {
             let cap = EndpointCap::try_from(cap_slot).unwrap();
-            Ok(cap.derive_badged(badge))
+            cap.derive_badged(badge, rights)
+        }
+        ObjType::Notification => {
+            let cap = NotificationCap::try_from(cap_slot).unwrap();
+            cap.derive_badged(badge, rights)
         }
         ObjType::Ram => {
             let cap = RamCap::try_from(cap_slot).unwrap();
-            Ok(cap.derive())
+            cap.derive(rights)
         }
-        _ => Ok(cap_slot.get()),
-    }
+        /*
+         * A copy carries its own free_offset, so an untyped is only copied before anything is
+         * retyped out of it, and retype refuses an untyped that has copies
+         */
+        ObjType::Untyped => {
+            let raw = cap_slot.get();
+            let has_objects = raw.get_next().map_or(false, |next_ptr| {
+                let next = unsafe { next_ptr.as_ref() }.get();
+                cap_is_parent_of(&raw, &next) && !same_object(&raw, &next)
+            });
+            if has_objects {
+                return Err(SysError::RevokeFirst);
+            }
+            raw
+        }
+        /* Each call is answered at most once */
+        ObjType::Reply => return Err(SysError::UnableToDerive),
+        _ => cap_slot.get(),
+    };
+
+    /* A newly badged endpoint or notification is revocable itself, and so is an untyped copy,
+     * which may be the one objects get retyped out of. Every other derived cap is a plain copy */
+    let revocable = match raw.cap_type() {
+        ObjType::Endpoint | ObjType::Notification => badge.is_some(),
+        ObjType::Untyped => true,
+        _ => false,
+    };
+    raw.set_revocable(revocable);
+
+    Ok(raw)
 }
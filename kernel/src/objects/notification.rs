@@ -0,0 +1,200 @@
+use super::*;
+use crate::objects::tcb::ThreadState;
+use crate::syscall::RespInfo;
+use crate::utils::tcb_queue::TcbQueue;
+use core::mem::size_of;
+use core::num::NonZeroUsize;
+
+#[derive(Debug, Default)]
+pub struct NotificationObj {
+    queue: TcbQueue,
+    signal: Cell<usize>,
+    irq: Cell<u64>,
+    bound_tcb: Cell<Option<NonNull<TcbObj>>>,
+}
+
+pub const NOTIFICATION_OBJ_SZ: usize = size_of::<NotificationObj>().next_power_of_two();
+pub const NOTIFICATION_OBJ_BIT_SZ: usize = NOTIFICATION_OBJ_SZ.trailing_zeros() as usize;
+
+pub type NotificationCap<'a> = CapRef<'a, NotificationObj>;
+
+impl NotificationObj {
+    /*
+     * OR `bits` into the notification word. A thread waiting on the notification, or the bound
+     * thread if it is blocked receiving on an endpoint, gets the word right away instead.
+     */
+    pub fn signal(&self, bits: usize) {
+        let signal = self.signal.get() | bits;
+
+        if let Some(waiter) = self.queue.dequeue() {
+            Self::deliver(waiter, signal);
+            self.signal.set(0);
+            return;
+        }
+
+        if let Some(mut tcb_ptr) = self.bound_tcb.get() {
+            let tcb = unsafe { tcb_ptr.as_mut() };
+            if tcb.state() == ThreadState::Receiving {
+                tcb.detach();
+                Self::deliver(tcb, signal);
+                self.signal.set(0);
+                return;
+            }
+        }
+
+        self.signal.set(signal);
+    }
+
+    /*
+     * Hand the pending word to `tcb`. With nothing pending `tcb` blocks until the next signal,
+     * and the interrupts attached to this notification are unmasked again.
+     */
+    pub fn wait(&self, tcb: &mut TcbObj) {
+        if let Some(signal) = self.poll() {
+            tcb.set_mr(1, signal);
+            tcb.set_respinfo(RespInfo::new_notification());
+            return;
+        }
+
+        tcb.detach();
+        tcb.set_state(ThreadState::WaitingNotification);
+        self.queue.enqueue(tcb);
+        self.listen_irq();
+    }
+
+    pub fn poll(&self) -> Option<usize> {
+        match self.signal.take() {
+            0 => None,
+            s => Some(s),
+        }
+    }
+
+    pub fn listen_irq(&self) {
+        if self.irq.get() != 0 {
+            unsafe {
+                crate::interrupt::INTERRUPT_CONTROLLER
+                    .lock()
+                    .listen_irq_mask(self.irq.get());
+            }
+        }
+    }
+
+    pub fn attach_irq(&self, irq: usize) {
+        self.irq.set(self.irq.get() | (1 << irq));
+    }
+
+    pub fn bind_tcb(&self, tcb: Option<&TcbObj>) {
+        self.bound_tcb.set(tcb.map(NonNull::from));
+    }
+
+    pub fn bound_tcb(&self) -> Option<NonNull<TcbObj>> {
+        self.bound_tcb.get()
+    }
+
+    fn deliver(tcb: &mut TcbObj, signal: usize) {
+        tcb.set_mr(1, signal);
+        tcb.set_respinfo(RespInfo::new_notification());
+        tcb.set_state(ThreadState::Ready);
+        crate::SCHEDULER.push(tcb);
+    }
+}
+
+/* Capability Entry Field Definition
+ * -------------------------------------------------
+ * |                    paddr                      |
+ * |                      64                       |
+ * -------------------------------------------------
+ * |                                       |G|W|R|
+ * |                                       |1|1|1|
+ * -------------------------------------------------
+ * |                    Badge                      |
+ * |                      64                       |
+ * -------------------------------------------------
+ * R: wait and poll, W: signal, G: unused
+ */
+const RIGHTS_BITS: usize = 3;
+
+impl<'a> NotificationCap<'a> {
+    pub fn mint(paddr: usize, badge: usize, rights: CapRights) -> CapRaw {
+        CapRaw::new(
+            paddr,
+            rights.bits(),
+            badge,
+            None,
+            None,
+            ObjType::Notification,
+        )
+    }
+
+    pub fn rights(&self) -> CapRights {
+        CapRights::from_bits(self.raw().arg1 & MASK!(RIGHTS_BITS))
+    }
+
+    pub fn check_rights(&self, rights: CapRights) -> SysResult<()> {
+        if self.rights().contains(rights) {
+            Ok(())
+        } else {
+            Err(SysError::InsufficientRights)
+        }
+    }
+
+    pub fn badge(&self) -> Option<usize> {
+        let b = self.raw().arg2;
+        if b == 0 {
+            None
+        } else {
+            Some(b)
+        }
+    }
+
+    pub fn set_badge(&self, badge: usize) {
+        let mut raw = self.raw();
+        raw.arg2 = badge;
+        self.raw.set(raw);
+    }
+
+    /* Signalling through an unbadged cap still has to leave a mark in the word */
+    pub fn signal_bits(&self) -> usize {
+        self.badge().unwrap_or(1)
+    }
+
+    pub fn derive_badged(&self, badge: Option<NonZeroUsize>, rights: CapRights) -> CapRaw {
+        let rights = self.rights() & rights;
+        if let Some(b) = badge {
+            NotificationCap::mint(self.paddr().0, b.get(), rights)
+        } else {
+            let mut raw = self.raw();
+            raw.arg1 = raw.arg1 & !MASK!(RIGHTS_BITS) | rights.bits();
+            raw
+        }
+    }
+
+    /*
+     * Called when the last capability to the notification is deleted. Waiting threads get
+     * IpcAborted, the bound thread is unbound and the attached interrupts are masked.
+     */
+    pub fn finalize(&self) {
+        while let Some(tcb) = self.queue.dequeue() {
+            tcb.abort_ipc();
+        }
+        if let Some(tcb_ptr) = self.bound_tcb() {
+            unsafe { tcb_ptr.as_ref() }.bind_notification(None).unwrap();
+        }
+        unsafe {
+            crate::interrupt::INTERRUPT_CONTROLLER
+                .lock()
+                .detach_ntfn(self.paddr().0);
+        }
+    }
+
+    pub fn identify(&self, tcb: &mut TcbObj) -> usize {
+        tcb.set_mr(1, self.cap_type() as usize);
+        1
+    }
+
+    pub fn debug_formatter(f: &mut core::fmt::DebugStruct, cap: &CapRaw) {
+        let c = Cell::new(*cap);
+        let c = NotificationCap::try_from(&c).unwrap();
+        f.field("rights", &c.rights()).field("badge", &c.badge());
+    }
+}
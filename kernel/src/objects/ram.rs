@@ -1,15 +1,19 @@
 use super::*;
-use crate::vspace::{PageGlobalDirectory, VSpace};
-use ::vspace::{arch::Aarch64PageTableEntry, arch::Level1, Entry, TableLevel, VirtAddr};
+use crate::asid::ASID_ALLOCATOR;
+use crate::vspace::{vspace_of_root, VSpace, VSPACE_ROOTS};
+use ::vspace::{
+    arch::Aarch64PageTableEntry, arch::Level1, arch::Level2, arch::Level3, Entry, PhysAddr,
+    TableLevel, VirtAddr,
+};
 use core::convert::TryFrom;
-use sysapi::vspace::Permission;
+use sysapi::vspace::{Permission, FRAME_BIT_SIZE, HUGE_FRAME_BIT_SIZE, LARGE_FRAME_BIT_SIZE};
 
 /* Capability Entry Field Definition
  * -------------------------------------------------
- * |   recv  |        paddr        |W|R|bit_sz|    |
+ * |   recv  |     mapped_root     |W|R|bit_sz|    |
  * |    16   |         36          |1|1|  6   | 4  |
  * -------------------------------------------------
- * |   ASID  |     mapped_vaddr    |      recv     |
+ * |   recv  |     mapped_vaddr    |      recv     |
  * |    16   |         36          |       12      |
  * -------------------------------------------------
  */
@@ -57,13 +61,44 @@ impl<'a> CapRef<'a, RamObj> {
         self.raw.get().arg1 & READ_MASK != 0
     }
 
+    pub fn rights(&self) -> CapRights {
+        let mut rights = CapRights::NONE;
+        if self.is_readable() {
+            rights = rights | CapRights::READ;
+        }
+        if self.is_writable() {
+            rights = rights | CapRights::WRITE;
+        }
+        rights
+    }
+
+    /*
+     * A mapping may only ask for what the cap grants; execute needs read. No mapping is ever
+     * writable and executable at once.
+     */
+    pub fn check_permission(&self, perm: Permission) -> SysResult<()> {
+        if perm.is_writable_executable() {
+            return Err(SysError::VSpacePermissionError);
+        }
+        let rights = self.rights();
+        if perm.is_writable() && !rights.contains(CapRights::WRITE) {
+            return Err(SysError::VSpacePermissionError);
+        }
+        if (perm.is_readable() || perm.is_executable()) && !rights.contains(CapRights::READ) {
+            return Err(SysError::VSpacePermissionError);
+        }
+        Ok(())
+    }
+
     pub fn is_device(&self) -> bool {
         self.raw.get().arg2 & 0b1 != 0
     }
 
-    pub fn set_mapped_vaddr_asid(&self, vaddr: usize, asid: usize) {
+    /* `root` is the root table of the VSpace the frame is mapped in */
+    pub fn set_mapped_vaddr_root(&self, vaddr: usize, root: PhysAddr) {
         let mut raw = self.raw();
-        raw.arg2 = asid << 48 | vaddr | raw.arg2 & MASK!(12);
+        raw.arg1 = root.0 & Self::ADDR_MASK | raw.arg1 & MASK!(12);
+        raw.arg2 = vaddr & Self::ADDR_MASK | raw.arg2 & MASK!(12);
         self.raw.replace(raw);
     }
 
@@ -71,8 +106,19 @@ impl<'a> CapRef<'a, RamObj> {
         self.raw().arg2 & Self::ADDR_MASK
     }
 
+    pub fn mapped_root(&self) -> PhysAddr {
+        PhysAddr(self.raw().arg1 & Self::ADDR_MASK)
+    }
+
+    /* The ASID the VSpace of the mapping runs with at the moment, 0 if it has none */
     pub fn mapped_asid(&self) -> usize {
-        self.raw().arg2 >> 48
+        if self.mapped_vaddr() == 0 {
+            return 0;
+        }
+        ASID_ALLOCATOR
+            .lock()
+            .lookup(self.mapped_root(), 0)
+            .unwrap_or(0)
     }
 
     pub fn size(&self) -> usize {
@@ -106,38 +152,122 @@ impl<'a> CapRef<'a, RamObj> {
         }
     }
 
+    /* A 4K frame maps as a page, 2M and 1G frames as PD and PUD block entries */
+    pub fn map(&self, vspace: &mut VSpace, vaddr: usize, rights: Permission) -> SysResult<()> {
+        if vaddr & MASK!(self.size()) != 0 {
+            return Err(SysError::InvalidValue);
+        }
+
+        match self.size() {
+            FRAME_BIT_SIZE => self.map_page::<Level1>(vspace, vaddr, rights),
+            LARGE_FRAME_BIT_SIZE => self.map_page::<Level2>(vspace, vaddr, rights),
+            HUGE_FRAME_BIT_SIZE => self.map_page::<Level3>(vspace, vaddr, rights),
+            _ => Err(SysError::InvalidValue),
+        }
+    }
+
     pub fn map_page<L: TableLevel<EntryType = Aarch64PageTableEntry>>(
         &self,
         vspace: &mut VSpace,
         vaddr: usize,
         rights: Permission,
     ) -> SysResult<()> {
-        let entry = if self.is_device() {
+        let entry = self.page_entry::<L>(rights);
+        VSPACE_ROOTS.lock().insert(vspace.root_paddr())?;
+        vspace.map_entry::<L>(VirtAddr(vaddr), entry)?;
+
+        self.set_mapped_vaddr_root(vaddr, vspace.root_paddr());
+
+        Ok(())
+    }
+
+    fn page_entry<L: TableLevel<EntryType = Aarch64PageTableEntry>>(
+        &self,
+        rights: Permission,
+    ) -> Aarch64PageTableEntry {
+        if self.is_device() {
             Aarch64PageTableEntry::device_page_entry::<L>(self.paddr(), rights)
         } else {
             Aarch64PageTableEntry::normal_page_entry::<L>(self.paddr(), rights)
-        };
-        vspace.map_entry::<L>(VirtAddr(vaddr), entry)?;
+        }
+    }
 
-        let asid = (vspace.root_paddr().0 >> 12) & MASK!(16);
-        self.set_mapped_vaddr_asid(vaddr, asid);
+    /* Change the permission of the existing mapping in place */
+    pub fn remap(&self, rights: Permission) -> SysResult<()> {
+        match self.size() {
+            FRAME_BIT_SIZE => self.remap_entry::<Level1>(rights),
+            LARGE_FRAME_BIT_SIZE => self.remap_entry::<Level2>(rights),
+            HUGE_FRAME_BIT_SIZE => self.remap_entry::<Level3>(rights),
+            _ => Err(SysError::InvalidValue),
+        }
+    }
+
+    fn remap_entry<L: TableLevel<EntryType = Aarch64PageTableEntry>>(
+        &self,
+        rights: Permission,
+    ) -> SysResult<()> {
+        let root = self.mapped_root();
+        let mut vspace = vspace_of_root(root).ok_or(SysError::VSpaceCapNotMapped)?;
+        let mapped_vaddr = self.mapped_vaddr();
+
+        /* The mapping is gone if its page table was unmapped or its VSpace torn down */
+        match vspace.lookup_slot::<L>(VirtAddr(mapped_vaddr)) {
+            Ok(slot) if slot.is_valid() && !slot.is_table_entry() => {
+                if slot.paddr().0 != self.paddr().0 {
+                    return Err(SysError::VSpaceCapNotMapped);
+                }
+            }
+            _ => return Err(SysError::VSpaceCapNotMapped),
+        }
+
+        let entry = self.page_entry::<L>(rights);
+        vspace.remap_entry::<L>(VirtAddr(mapped_vaddr), entry)?;
+        crate::arch::dmb();
+
+        if let Some(asid) = ASID_ALLOCATOR.lock().lookup(root, 0) {
+            ::vspace::arch::mmu::invalidate_tlb_va_asid_is(asid, mapped_vaddr);
+        }
 
         Ok(())
     }
 
     pub fn unmap_page(&self) -> SysResult<()> {
-        let asid = self.mapped_asid();
-        let root_table =
-            unsafe { PageGlobalDirectory::from_vaddr(((asid << 12) + KERNEL_OFFSET) as *mut u8) };
-        let mut vspace = VSpace::from_root(root_table);
+        match self.size() {
+            FRAME_BIT_SIZE => self.unmap_entry::<Level1>(),
+            LARGE_FRAME_BIT_SIZE => self.unmap_entry::<Level2>(),
+            HUGE_FRAME_BIT_SIZE => self.unmap_entry::<Level3>(),
+            _ => Err(SysError::InvalidValue),
+        }
+    }
+
+    /*
+     * The entry is only cleared if it still maps this frame. It may be gone already, e.g. when
+     * its page table was unmapped or its VSpace torn down. A VSpace without an ASID has no TLB
+     * entries a core could still use. Nothing is walked once the root itself is gone.
+     */
+    fn unmap_entry<L: TableLevel<EntryType = Aarch64PageTableEntry>>(&self) -> SysResult<()> {
+        let root = self.mapped_root();
         let mapped_vaddr = self.mapped_vaddr();
 
-        let slot = vspace.lookup_slot_mut::<Level1>(VirtAddr(mapped_vaddr))?;
-        *slot = Entry::invalid_entry();
+        let mut vspace = vspace_of_root(root);
+        let slot = vspace
+            .as_mut()
+            .and_then(|vspace| vspace.lookup_slot_mut::<L>(VirtAddr(mapped_vaddr)).ok());
+        if let Some(slot) = slot {
+            if slot.is_valid() && !slot.is_table_entry() && slot.paddr().0 == self.paddr().0 {
+                *slot = Entry::invalid_entry();
 
-        crate::arch::dc_clean_by_va_PoU(slot as *const _ as usize);
-        crate::arch::dmb();
+                crate::arch::dc_clean_by_va_PoU(slot as *const _ as usize);
+                crate::arch::dmb();
+
+                /* The thread may be running on any core */
+                if let Some(asid) = ASID_ALLOCATOR.lock().lookup(root, 0) {
+                    ::vspace::arch::mmu::invalidate_tlb_va_asid_is(asid, mapped_vaddr);
+                }
+            }
+        }
 
+        self.set_mapped_vaddr_root(0, PhysAddr(0));
         Ok(())
     }
 
@@ -150,11 +280,12 @@ impl<'a> CapRef<'a, RamObj> {
         5
     }
 
-    pub fn derive(&self) -> CapRaw {
+    pub fn derive(&self, rights: CapRights) -> CapRaw {
+        let rights = self.rights() & rights;
         Self::mint(
             self.paddr().0,
-            self.is_writable(),
-            self.is_readable(),
+            rights.contains(CapRights::WRITE),
+            rights.contains(CapRights::READ),
             self.size(),
             self.is_device(),
         )
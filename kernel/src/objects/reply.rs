@@ -21,8 +21,7 @@ impl ReplyObj {
 
         if let Some(_) = receiver.fault.take() {
             receiver.set_state(ThreadState::Ready);
-            crate::SCHEDULER.get_mut().push(receiver);
-            sender.set_reply(None);
+            crate::SCHEDULER.push(receiver);
             sender.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
         } else {
             let recv_info = receiver.get_msginfo().unwrap();
@@ -30,8 +29,7 @@ impl ReplyObj {
 
             receiver.set_state(ThreadState::Ready);
             receiver.set_sending_badge(0);
-            crate::SCHEDULER.get_mut().push(receiver);
-            sender.set_reply(None);
+            crate::SCHEDULER.push(receiver);
             if !will_recv {
                 sender.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
             } else {
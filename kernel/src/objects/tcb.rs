@@ -3,14 +3,19 @@ use core::fmt::{Debug, Error, Formatter};
 use core::mem::size_of;
 
 use sysapi::fault::Fault;
+use sysapi::ipc::IpcBuffer;
+use sysapi::vspace::{FRAME_BIT_SIZE, FRAME_SIZE};
 
 use super::*;
 use crate::arch::trapframe::TrapFrame;
 use crate::cspace::CSpace;
-use crate::objects::{EndpointCap, NullCap};
+use crate::objects::{EndpointCap, NotificationCap, NullCap};
+use crate::scheduler::DEFAULT_PRIORITY;
 use crate::syscall::{MsgInfo, RespInfo};
+use crate::utils::kernel_lock::KERNEL_LOCK;
 use crate::utils::tcb_queue::TcbQueueNode;
 
+use crate::asid::ASID_ALLOCATOR;
 use crate::vspace::{Aarch64TopLevel, PageGlobalDirectory, VSpace, VirtAddr};
 use vspace::Level;
 
@@ -19,7 +24,10 @@ pub enum ThreadState {
     Ready,
     Sending,
     Receiving,
+    BlockedOnReply,
+    WaitingNotification,
     Fault,
+    Inactive,
 }
 
 impl core::default::Default for ThreadState {
@@ -30,18 +38,27 @@ impl core::default::Default for ThreadState {
 
 #[repr(C)]
 #[repr(align(1024))]
-#[derive(Default)]
 pub struct TcbObj {
     pub tf: TrapFrame,
     cspace: CNodeEntry,
     vspace: CNodeEntry,
     reply_cap: CNodeEntry,
+    reply_master: CNodeEntry,
     fault_handler_ep: CNodeEntry,
+    bound_notification: CNodeEntry,
+    ipc_buffer: CNodeEntry,
     pub fault: Cell<Option<Fault>>,
     time_slice: Cell<usize>,
+    time_slice_len: Cell<usize>,
+    priority: Cell<usize>,
+    mcp: Cell<usize>,
+    affinity: Cell<usize>,
+    cpu: Cell<usize>,
     state: Cell<ThreadState>,
     sending_badge: Cell<usize>,
+    deadline: Cell<u64>,
     pub node: TcbQueueNode,
+    pub timeout_node: TcbQueueNode,
 }
 
 impl Debug for TcbObj {
@@ -51,16 +68,25 @@ impl Debug for TcbObj {
             .field("cspace", &self.cspace)
             .field("vspace", &self.vspace)
             .field("time_slice", &self.time_slice.get())
+            .field("priority", &self.priority.get())
+            .field("affinity", &self.affinity.get())
             .field("state", &self.state.get())
             .field("queue node", &self.node)
             .finish()
     }
 }
 
+impl Default for TcbObj {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub const TCB_OBJ_SZ: usize = size_of::<TcbObj>().next_power_of_two();
 pub const TCB_OBJ_BIT_SZ: usize = TCB_OBJ_SZ.trailing_zeros() as usize;
 const_assert_eq!(TCB_OBJ_SZ, crate::objects::TCB_OBJ_SZ);
 const_assert_eq!(TCB_OBJ_BIT_SZ, crate::objects::TCB_OBJ_BIT_SZ);
+const_assert!(size_of::<IpcBuffer>() <= FRAME_SIZE);
 
 pub type TcbCap<'a> = CapRef<'a, TcbObj>;
 
@@ -71,17 +97,30 @@ impl TcbObj {
             cspace: Cell::new(NullCap::mint()),
             vspace: Cell::new(NullCap::mint()),
             reply_cap: Cell::new(NullCap::mint()),
+            reply_master: Cell::new(NullCap::mint()),
             fault_handler_ep: Cell::new(NullCap::mint()),
+            bound_notification: Cell::new(NullCap::mint()),
+            ipc_buffer: Cell::new(NullCap::mint()),
             fault: Cell::new(None),
             time_slice: Cell::new(0),
+            time_slice_len: Cell::new(crate::TIME_SLICE as usize),
+            priority: Cell::new(DEFAULT_PRIORITY),
+            mcp: Cell::new(DEFAULT_PRIORITY),
+            affinity: Cell::new(0),
+            cpu: Cell::new(0),
             state: Cell::new(ThreadState::Ready),
             sending_badge: Cell::new(0),
+            deadline: Cell::new(0),
             node: TcbQueueNode::new(),
+            timeout_node: TcbQueueNode::new(),
         }
     }
 
     pub fn configure_idle_thread(&mut self) {
-        self.tf.configure_idle_thread()
+        self.tf.configure_idle_thread();
+        /* Only runs when nothing else is ready */
+        self.priority.set(0);
+        self.affinity.set(crate::arch::cpuid());
     }
 
     pub fn install_cspace(&self, cspace: &CNodeCap) -> SysResult<()> {
@@ -89,15 +128,13 @@ impl TcbObj {
     }
 
     pub fn install_vspace(&mut self, vspace: VTableCap) {
-        let asid = (vspace.paddr().0 >> 12) & MASK!(16);
-        vspace.set_mapped_vaddr_asid(0, asid, 4);
-        let raw = vspace.raw();
-        self.vspace.set(raw);
+        vspace.set_mapped_vaddr_root(0, vspace.paddr(), Aarch64TopLevel::LEVEL);
+        cnode_entry_copy(vspace.raw, &self.vspace);
     }
 
-    pub fn cspace(&self) -> SysResult<CSpace<'static>> {
+    pub fn cspace(&self) -> SysResult<CSpace> {
         let cap = CNodeCap::try_from(&self.cspace).map_err(|_| SysError::CSpaceNotFound)?;
-        Ok(CSpace(cap.as_object_mut()))
+        Ok(CSpace::new(cap.raw()))
     }
 
     pub fn vspace(&self) -> Option<VSpace> {
@@ -112,13 +149,60 @@ impl TcbObj {
         EndpointCap::try_from(&self.fault_handler_ep).ok()
     }
 
+    /*
+     * Bind `ntfn` to this thread, so that signals also wake it up from EndpointRecv. A thread has
+     * at most one bound notification, and a notification is bound to at most one thread. The
+     * thread keeps a copy of the capability as a child of `ntfn`, which keeps the notification
+     * alive while it is bound. It carries the badge of `ntfn` so that revoking `ntfn` reaches it.
+     */
+    pub fn bind_notification(&self, ntfn: Option<NotificationCap>) -> SysResult<()> {
+        if let Some(ntfn) = &ntfn {
+            if ntfn.bound_tcb().is_some() {
+                return Err(SysError::InvalidValue);
+            }
+        }
+
+        if let Ok(old) = NotificationCap::try_from(&self.bound_notification) {
+            old.bind_tcb(None);
+        }
+        cap_delete(&self.bound_notification)?;
+
+        if let Some(ntfn) = ntfn {
+            ntfn.bind_tcb(Some(self));
+            let badge = ntfn.badge().unwrap_or(0);
+            let cap = NotificationCap::mint(ntfn.paddr().0, badge, CapRights::ALL);
+            self.bound_notification.set(cap);
+            cnode_entry_append_next(ntfn.raw, &self.bound_notification);
+        }
+
+        Ok(())
+    }
+
+    pub fn bound_notification(&self) -> CapRaw {
+        self.bound_notification.get()
+    }
+
+    pub fn holds_bound_notification(&self, slot: &CNodeEntry) -> bool {
+        core::ptr::eq(&self.bound_notification, slot)
+    }
+
     pub unsafe fn switch_vspace(&self) -> SysResult<()> {
-        let pgd_cap = VTableCap::try_from(&self.vspace)?;
-        let asid = self.asid()?;
+        let pgd_cap = match VTableCap::try_from(&self.vspace) {
+            Ok(cap) => cap,
+            Err(e) => {
+                ASID_ALLOCATOR.lock().deactivate(crate::arch::cpuid());
+                return Err(e);
+            }
+        };
+        let asid = ASID_ALLOCATOR.lock().activate(
+            crate::arch::cpuid(),
+            pgd_cap.paddr(),
+            pgd_cap.asid_hint(),
+        );
+        pgd_cap.set_asid_hint(asid);
         let root_vaddr: VirtAddr = pgd_cap.paddr().into();
         let vspace = VSpace::from_vaddr(root_vaddr.0 as *mut u8);
         vspace.install_user_vspace(asid);
-        vspace.invalidate_tlb_by_asid(asid);
         Ok(())
     }
 
@@ -127,6 +211,9 @@ impl TcbObj {
             let cpuid = crate::arch::cpuid() << 48;
             asm!("msr tpidrro_el0, {cpuid}", cpuid = in(reg) (cpuid | self.thread_id()), options(nomem));
             self.switch_vspace().unwrap_or(()); // explicitly ignore error for idle thread
+            crate::arch::set_single_step(self.tf.single_step());
+            crate::scheduler::enter_thread(self);
+            KERNEL_LOCK.unlock();
             self.tf.restore();
         }
     }
@@ -155,13 +242,23 @@ impl TcbObj {
         self.tf.set_respinfo(respinfo)
     }
 
+    /*
+     * Handing out a reply capability to `reply` leaves that thread blocked until it is used. The
+     * capability is derived from the reply master of `reply`, so that it can be revoked when that
+     * thread goes away.
+     */
     pub fn set_reply(&self, reply: Option<&TcbObj>) {
-        match reply {
-            None => self.reply_cap.set(NullCap::mint()),
-            Some(tcb) => {
-                let cap = ReplyCap::mint(tcb as *const _ as usize - crate::prelude::KERNEL_OFFSET);
-                self.reply_cap.set(cap)
+        cnode_entry_unlink(&self.reply_cap);
+        self.reply_cap.set(NullCap::mint());
+
+        if let Some(tcb) = reply {
+            let paddr = tcb as *const _ as usize - crate::prelude::KERNEL_OFFSET;
+            if tcb.reply_master.get().cap_type() == ObjType::NullObj {
+                tcb.reply_master.set(ReplyCap::mint(paddr).into_revocable());
             }
+            tcb.set_state(ThreadState::BlockedOnReply);
+            self.reply_cap.set(ReplyCap::mint(paddr));
+            cnode_entry_append_next(&tcb.reply_master, &self.reply_cap);
         }
     }
 
@@ -170,22 +267,28 @@ impl TcbObj {
         Some(ReplyObj(cap.waiting_tcb()))
     }
 
-    pub fn asid(&self) -> SysResult<usize> {
-        // use PGD[28:12] bits as asid
-        let pgd_cap = VTableCap::try_from(&self.vspace)?;
-        Ok((pgd_cap.paddr().0 >> 12) & MASK!(16))
+    /*
+     * Move the pending reply capability into `slot`, so that the thread can receive the next
+     * call before answering this one.
+     */
+    pub fn save_caller(&self, slot: &CNodeEntry) -> SysResult<()> {
+        let dst = NullCap::try_from(slot)?;
+        ReplyCap::try_from(&self.reply_cap).map_err(|_| SysError::LookupError)?;
+        cnode_entry_move(&self.reply_cap, dst.raw);
+        Ok(())
     }
 
     pub fn configure(
-        &self,
+        &mut self,
         cspace: Option<CNodeCap>,
         vspace: Option<VTableCap>,
         fault_handler_ep: Option<EndpointCap>,
+        ipc_buffer: Option<(RamCap, usize)>,
     ) -> SysResult<()> {
         if let Some(vs) = vspace {
             let dst_vspace = NullCap::try_from(&self.vspace)?;
+            vs.set_mapped_vaddr_root(0, vs.paddr(), Aarch64TopLevel::LEVEL);
             vs.derive(&dst_vspace)?;
-            vs.set_mapped_vaddr_asid(0, dst_vspace.paddr().0 >> 12, Aarch64TopLevel::LEVEL);
         }
 
         if let Some(cs) = cspace {
@@ -198,9 +301,40 @@ impl TcbObj {
             ep.derive(&thread_handler)?;
         }
 
+        if let Some((buf, vaddr)) = ipc_buffer {
+            self.set_ipc_buffer(buf, vaddr)?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Use the frame behind `buf`, which the thread sees at `vaddr`, for the message words that do
+     * not fit in registers. The thread finds the buffer through tpidr_el0.
+     */
+    fn set_ipc_buffer(&mut self, buf: RamCap, vaddr: usize) -> SysResult<()> {
+        if buf.size() < FRAME_BIT_SIZE {
+            return Err(SysError::SizeTooSmall);
+        }
+        if !buf.rights().contains(CapRights::READ | CapRights::WRITE) {
+            return Err(SysError::InsufficientRights);
+        }
+        if vaddr == 0 || vaddr % FRAME_SIZE != 0 {
+            return Err(SysError::InvalidValue);
+        }
+
+        NullCap::try_from(&self.ipc_buffer)?;
+        self.ipc_buffer.set(buf.derive(CapRights::ALL));
+        cnode_entry_append_next(buf.raw, &self.ipc_buffer);
+        self.tf.set_tpidr(vaddr);
         Ok(())
     }
 
+    pub fn ipc_buffer(&self) -> Option<&'static mut IpcBuffer> {
+        let buf = RamCap::try_from(&self.ipc_buffer).ok()?;
+        Some(unsafe { &mut *(buf.vaddr() as *mut IpcBuffer) })
+    }
+
     pub fn set_state(&self, state: ThreadState) {
         self.state.set(state)
     }
@@ -223,6 +357,161 @@ impl TcbObj {
         self.set_timeslice(ts);
     }
 
+    /* Length of the time slice the thread gets every time its turn comes */
+    pub fn timeslice_len(&self) -> usize {
+        self.time_slice_len.get()
+    }
+
+    pub fn set_timeslice_len(&self, len: usize) {
+        self.time_slice_len.set(len)
+    }
+
+    pub fn priority(&self) -> usize {
+        self.priority.get()
+    }
+
+    /* Make a new or suspended thread runnable. Threads that already are stay as they are */
+    pub fn resume(&self) {
+        match self.state() {
+            ThreadState::Inactive => {}
+            ThreadState::Ready if !self.node.is_attached() => {}
+            _ => return,
+        }
+        self.set_state(ThreadState::Ready);
+        crate::SCHEDULER.push(self);
+    }
+
+    /*
+     * Take the thread off its run queue, or out of the endpoint or notification it is blocked on,
+     * until it is resumed. A system call it is blocked in fails with IpcAborted, and a pending
+     * call is cancelled by revoking the reply capability the callee holds.
+     */
+    pub fn suspend(&mut self) {
+        match self.state() {
+            ThreadState::Inactive => return,
+            ThreadState::Ready => {
+                /* It may be running on another core right now */
+                self.detach();
+                crate::scheduler::stop_thread(self);
+            }
+            _ => self.cancel_ipc(SysError::IpcAborted),
+        }
+
+        self.cancel_timeout();
+        self.set_state(ThreadState::Inactive);
+    }
+
+    /* Take the thread out of the IPC it is blocked in, which then fails with `err` */
+    fn cancel_ipc(&mut self, err: SysError) {
+        self.fault.set(None);
+        self.set_sending_badge(0);
+        self.set_respinfo(RespInfo::new_syscall_resp(err, 0));
+        cap_revoke(&self.reply_master).unwrap();
+        self.detach();
+    }
+
+    /* The endpoint or notification the thread is blocked on is being deleted */
+    pub fn abort_ipc(&mut self) {
+        self.cancel_ipc(SysError::IpcAborted);
+        self.cancel_timeout();
+        self.set_state(ThreadState::Ready);
+        crate::SCHEDULER.push(self);
+    }
+
+    /* The deadline of a blocking Recv or Call has passed */
+    pub fn timeout(&mut self) {
+        self.cancel_ipc(SysError::Timeout);
+        self.set_state(ThreadState::Ready);
+        crate::SCHEDULER.push(self);
+    }
+
+    pub fn deadline(&self) -> u64 {
+        self.deadline.get()
+    }
+
+    pub fn set_deadline(&self, deadline: u64) {
+        self.deadline.set(deadline)
+    }
+
+    pub fn cancel_timeout(&self) {
+        self.timeout_node.detach()
+    }
+
+    /*
+     * Called when the last capability to the thread is deleted. Nothing may refer to the object
+     * afterwards: the thread is suspended, a caller waiting for its reply is released with
+     * IpcAborted, and the capabilities it holds are deleted.
+     */
+    pub fn finalize(&mut self) {
+        self.suspend();
+        crate::scheduler::forget_thread(self);
+
+        if let Some(reply) = self.reply_cap() {
+            let caller = reply.waiting_tcb();
+            caller.set_respinfo(RespInfo::new_syscall_resp(SysError::IpcAborted, 0));
+            caller.set_state(ThreadState::Ready);
+            crate::SCHEDULER.push(caller);
+        }
+        self.set_reply(None);
+
+        self.bind_notification(None).unwrap();
+        cnode_entry_unlink(&self.reply_master);
+        self.reply_master.set(NullCap::mint());
+
+        /* None of these is a mapped frame, so deleting them cannot fail */
+        let slots = [
+            &self.cspace,
+            &self.vspace,
+            &self.fault_handler_ep,
+            &self.ipc_buffer,
+        ];
+        for slot in slots.iter() {
+            cap_delete(slot).unwrap();
+        }
+    }
+
+    /*
+     * A ready thread moves over to the run queue of its new priority. It stays on the same core,
+     * as it may be running there right now.
+     */
+    pub fn set_priority(&self, prio: usize) {
+        let queued = self.state() == ThreadState::Ready && self.node.is_attached();
+        if queued {
+            self.detach();
+        }
+        self.priority.set(prio);
+        if queued {
+            crate::SCHEDULER.push_to(self.cpu(), self);
+        }
+    }
+
+    /* Maximum controlled priority: the highest priority this thread may give to any thread */
+    pub fn mcp(&self) -> usize {
+        self.mcp.get()
+    }
+
+    pub fn set_mcp(&self, mcp: usize) {
+        self.mcp.set(mcp)
+    }
+
+    /* The core the thread runs on. Queued threads are moved over the next time it is their turn */
+    pub fn affinity(&self) -> usize {
+        self.affinity.get()
+    }
+
+    pub fn set_affinity(&self, cpu: usize) {
+        self.affinity.set(cpu)
+    }
+
+    /* The core whose run queue the thread was last put on */
+    pub fn cpu(&self) -> usize {
+        self.cpu.get()
+    }
+
+    pub fn set_cpu(&self, cpu: usize) {
+        self.cpu.set(cpu)
+    }
+
     pub fn sending_badge(&self) -> Option<usize> {
         let badge = self.sending_badge.get();
         if badge == 0 {
@@ -43,3 +43,7 @@ impl KernelObject for MonitorObj {
 impl KernelObject for InterruptObj {
     const obj_type: ObjType = ObjType::Interrupt;
 }
+
+impl KernelObject for NotificationObj {
+    const obj_type: ObjType = ObjType::Notification;
+}
@@ -1,6 +1,8 @@
 use core::cell::Cell;
 
 use super::*;
+use sysapi::objects::{CNODE_DEPTH, RETYPE_CNODE_GUARD, RETYPE_CNODE_GUARD_SHIFT};
+use sysapi::vspace::FRAME_BIT_SIZE;
 
 #[derive(Debug)]
 pub struct UntypedObj {}
@@ -52,11 +54,58 @@ impl<'a> CapRef<'a, UntypedObj> {
         self.raw.get().arg1 != 0
     }
 
+    /*
+     * Bit size of each object when retyping into `obj_type`. Fixed sized objects always use
+     * their own size, variable sized ones use `bit_size` once it is large enough to hold the
+     * smallest object of that type.
+     */
+    fn object_bit_size(obj_type: ObjType, bit_size: usize) -> SysResult<usize> {
+        let min_bit_size = match obj_type {
+            ObjType::Tcb => return Ok(TCB_OBJ_BIT_SZ),
+            ObjType::Endpoint => return Ok(ENDPOINT_OBJ_BIT_SZ),
+            ObjType::Notification => return Ok(NOTIFICATION_OBJ_BIT_SZ),
+            ObjType::VTable => return Ok(FRAME_BIT_SIZE),
+            ObjType::Untyped => Self::MIN_BIT_SIZE,
+            ObjType::CNode => CNODE_ENTRY_BIT_SZ + 1,
+            ObjType::Ram => FRAME_BIT_SIZE,
+            _ => return Err(SysError::InvalidValue),
+        };
+
+        if bit_size < min_bit_size {
+            Err(SysError::SizeTooSmall)
+        } else if bit_size >= CNODE_DEPTH {
+            Err(SysError::InvalidValue)
+        } else {
+            Ok(bit_size)
+        }
+    }
+
+    /*
+     * Bit size, guard and guard length of the CNodes of a retype. With RETYPE_CNODE_GUARD set,
+     * the guard data above RETYPE_CNODE_GUARD_SHIFT is laid out like a CapMutate on a CNode.
+     * Without it the new CNodes are roots, whose zero guard takes all the bits the radix does
+     * not, so a lookup through one resolves a whole cptr.
+     */
+    fn cnode_bit_size(size: usize) -> SysResult<(usize, usize, usize)> {
+        let bit_size = Self::object_bit_size(ObjType::CNode, size & (RETYPE_CNODE_GUARD - 1))?;
+        let radix_sz = bit_size - super::CNODE_ENTRY_BIT_SZ;
+        if size & RETYPE_CNODE_GUARD == 0 {
+            return Ok((bit_size, 0, CNODE_DEPTH - radix_sz));
+        }
+
+        let data = size >> RETYPE_CNODE_GUARD_SHIFT;
+        let (guard, guard_bits) = (data >> 6, data & MASK!(6));
+        if !guard_is_valid(radix_sz, guard, guard_bits) {
+            return Err(SysError::InvalidValue);
+        }
+        Ok((bit_size, guard, guard_bits))
+    }
+
     /*
      * Allocate `slots.len()` objects of type `obj_type`. putting to `slots`
      *
      * `size`: for variable sized caps, `size` is the size of each new object. ignored for constant
-     * sized objects.
+     * sized objects. CNodes may also take their guard from it, see `cnode_bit_size`.
      * `slots`: a range of slots to put new objects. need to check if empty
      */
     pub fn retype(
@@ -69,16 +118,24 @@ impl<'a> CapRef<'a, UntypedObj> {
             return Err(SysError::SlotNotEmpty);
         }
 
-        if bit_size > 64 {
-            return Err(SysError::InvalidValue);
+        /* Copies would hand out the same memory again */
+        if cap_object_peers(self.raw).next().is_some() {
+            return Err(SysError::RevokeFirst);
         }
 
+        let (bit_size, guard, guard_bits) = match obj_type {
+            ObjType::CNode => Self::cnode_bit_size(bit_size)?,
+            _ => (Self::object_bit_size(obj_type, bit_size)?, 0, 0),
+        };
         let count = slots.len();
-        let obj_size = 1 << bit_size; //TODO: determine size by type;
-        let tot_size = count * obj_size;
+        let obj_size = 1 << bit_size;
+        let tot_size = count.checked_mul(obj_size).ok_or(SysError::InvalidValue)?;
         let free_offset = ALIGNUP!(self.free_offset(), bit_size);
 
-        if self.size() < tot_size + free_offset {
+        if free_offset
+            .checked_add(tot_size)
+            .map_or(true, |end| end > self.size())
+        {
             return Err(SysError::InvalidValue);
         }
 
@@ -89,18 +146,19 @@ impl<'a> CapRef<'a, UntypedObj> {
                 ObjType::CNode => {
                     let radix_sz = bit_size - super::CNODE_ENTRY_BIT_SZ;
 
-                    CapRef::<CNodeObj>::mint(addr, radix_sz, 64 - radix_sz, 0)
+                    CapRef::<CNodeObj>::mint(addr, radix_sz, guard_bits, guard)
                 }
                 ObjType::Tcb => CapRef::<TcbObj>::mint(addr),
                 ObjType::Ram => {
                     CapRef::<RamObj>::mint(addr, true, true, bit_size, self.is_device())
                 }
                 ObjType::VTable => CapRef::<VTableObj>::mint(addr),
-                ObjType::Endpoint => CapRef::<EndpointObj>::mint(addr, 0),
+                ObjType::Endpoint => CapRef::<EndpointObj>::mint(addr, 0, CapRights::ALL),
+                ObjType::Notification => CapRef::<NotificationObj>::mint(addr, 0, CapRights::ALL),
                 _ => return Err(SysError::InvalidValue),
             };
 
-            slot.set(cap);
+            slot.set(cap.into_revocable());
             self.append_next(slot);
 
             match obj_type {
@@ -111,6 +169,7 @@ impl<'a> CapRef<'a, UntypedObj> {
                 ObjType::Ram => RamCap::try_from(slot).unwrap().init(),
                 ObjType::VTable => VTableCap::try_from(slot).unwrap().init(),
                 ObjType::Endpoint => EndpointCap::try_from(slot).unwrap().init(),
+                ObjType::Notification => NotificationCap::try_from(slot).unwrap().init(),
                 _ => {}
             }
         }
@@ -118,6 +177,28 @@ impl<'a> CapRef<'a, UntypedObj> {
         Ok(())
     }
 
+    /*
+     * Give all memory of this untyped back so it can be retyped again. Every object retyped out
+     * of it has to be deleted first, which the derivation list tells us.
+     */
+    pub fn reset(&self) -> SysResult<()> {
+        if cap_has_children(self.raw) {
+            return Err(SysError::RevokeFirst);
+        }
+
+        if !self.is_device() {
+            let mem = unsafe {
+                core::slice::from_raw_parts_mut(self.vaddr() as *mut u8, self.free_offset())
+            };
+            for byte in mem {
+                *byte = 0u8;
+            }
+        }
+
+        self.set_free_offset(0);
+        Ok(())
+    }
+
     pub fn identify(&self, tcb: &mut TcbObj) -> usize {
         tcb.set_mr(1, self.cap_type() as usize);
         tcb.set_mr(2, self.paddr().0);
@@ -1,20 +1,24 @@
 use super::*;
 use core::convert::TryFrom;
 
-use crate::vspace::{Aarch64PageTableEntry, Table, VSpace, VirtAddr};
+use crate::asid::ASID_ALLOCATOR;
+use crate::vspace::{vspace_of_root, Aarch64PageTableEntry, Table, VSpace, VirtAddr, VSPACE_ROOTS};
 use vspace::{
     arch::{Level2, Level3, Level4},
-    TableLevel,
+    Entry, Level, PhysAddr, TableLevel,
 };
 
 /* Capability Entry Field Definition
  * -------------------------------------------------
- * |                  paddr        |      recv     |
- * |                               |       12      |
- * -------------------------------------------------
- * |   ASID  |     mapped_vaddr    |      recv     |
+ * |ASID hint|     mapped_vaddr    |      recv     |
  * |    16   |         36          |       12      |
  * -------------------------------------------------
+ * |   recv  |     mapped_root     | recv  | level |
+ * |    16   |         36          |   8   |   4   |
+ * -------------------------------------------------
+ *
+ * A root table records itself as its mapped root. Its ASID hint is where the ASID allocator
+ * last had it.
  */
 pub struct VTableObj([()]); // Make a RamObj not Sized
 
@@ -24,10 +28,10 @@ impl<'a> VTableCap<'a> {
         CapRaw::new(paddr, 0, 0, None, None, ObjType::VTable)
     }
 
-    pub fn set_mapped_vaddr_asid(&self, vaddr: usize, asid: usize, level: usize) {
+    pub fn set_mapped_vaddr_root(&self, vaddr: usize, root: PhysAddr, level: usize) {
         let mut raw = self.raw();
-        raw.arg1 = (asid << 48) | (vaddr & MASK!(48));
-        raw.arg2 = level;
+        raw.arg1 = (raw.arg1 & !MASK!(48)) | (vaddr & MASK!(48));
+        raw.arg2 = (root.0 & MASK!(48) & !MASK!(12)) | (level & MASK!(4));
         self.raw.replace(raw);
     }
 
@@ -35,12 +39,30 @@ impl<'a> VTableCap<'a> {
         self.raw().arg1 & MASK!(48)
     }
 
+    pub fn mapped_root(&self) -> PhysAddr {
+        PhysAddr(self.raw().arg2 & MASK!(48) & !MASK!(12))
+    }
+
+    pub fn mapped_level(&self) -> usize {
+        self.raw().arg2 & MASK!(4)
+    }
+
+    /* The ASID the VSpace of the table runs with at the moment, 0 if it has none */
     pub fn mapped_asid(&self) -> usize {
+        ASID_ALLOCATOR
+            .lock()
+            .lookup(self.mapped_root(), self.asid_hint())
+            .unwrap_or(0)
+    }
+
+    pub fn asid_hint(&self) -> usize {
         self.raw().arg1 >> 48
     }
 
-    pub fn mapped_level(&self) -> usize {
-        self.raw().arg2
+    pub fn set_asid_hint(&self, asid: usize) {
+        let mut raw = self.raw();
+        raw.arg1 = (asid << 48) | (raw.arg1 & MASK!(48));
+        self.raw.replace(raw);
     }
 
     pub fn debug_formatter(f: &mut core::fmt::DebugStruct, cap: &CapRaw) {
@@ -50,6 +72,7 @@ impl<'a> VTableCap<'a> {
     }
 
     pub fn map_vtable(&self, vspace: &mut VSpace, vaddr: VirtAddr, level: usize) -> SysResult<()> {
+        VSPACE_ROOTS.lock().insert(vspace.root_paddr())?;
         match level {
             4 => vspace
                 .map_entry::<Level4>(vaddr, Aarch64PageTableEntry::table_entry(self.paddr()))
@@ -63,14 +86,78 @@ impl<'a> VTableCap<'a> {
             _ => Err(SysError::InvalidValue),
         }?;
 
-        let asid = (vspace.root_paddr().0 >> 12) & MASK!(16);
-        self.set_mapped_vaddr_asid(vaddr.0, asid, level - 1);
+        self.set_mapped_vaddr_root(vaddr.0, vspace.root_paddr(), level - 1);
+
+        Ok(())
+    }
+
+    /*
+     * Take the table out of the table it is mapped in. Like frames, the entry is left alone if it
+     * no longer refers to this table or its root is gone. The table keeps its own entries and
+     * level.
+     */
+    pub fn unmap_vtable(&self) -> SysResult<()> {
+        match self.mapped_level() + 1 {
+            4 => self.unmap_entry::<Level4>(),
+            3 => self.unmap_entry::<Level3>(),
+            2 => self.unmap_entry::<Level2>(),
+            _ => Err(SysError::InvalidValue),
+        }
+    }
+
+    fn unmap_entry<L: TableLevel<EntryType = Aarch64PageTableEntry>>(&self) -> SysResult<()> {
+        let root = self.mapped_root();
+
+        let mut vspace = vspace_of_root(root);
+        let slot = vspace.as_mut().and_then(|vspace| {
+            vspace
+                .lookup_slot_mut::<L>(VirtAddr(self.mapped_vaddr()))
+                .ok()
+        });
+        if let Some(slot) = slot {
+            if slot.is_table_entry() && slot.paddr().0 == self.paddr().0 {
+                *slot = Entry::invalid_entry();
+
+                crate::arch::dc_clean_by_va_PoU(slot as *const _ as usize);
+                crate::arch::dmb();
+
+                /* Everything below the table goes with it, on every core */
+                if let Some(asid) = ASID_ALLOCATOR.lock().lookup(root, 0) {
+                    ::vspace::arch::mmu::invalidate_tlb_asid_is(asid);
+                }
+            }
+        }
+
+        self.set_mapped_vaddr_root(0, PhysAddr(0), self.mapped_level());
+        Ok(())
+    }
+
+    /*
+     * Invalidate every mapping of the VSpace this table is the root of, at every level. The
+     * capabilities of its tables and frames stay valid and can be deleted or mapped again.
+     */
+    pub fn teardown(&self) -> SysResult<()> {
+        if self.mapped_vaddr() != 0 {
+            return Err(SysError::VSpaceCapMapped);
+        }
+        /* Unmapped tables other than roots remember their level */
+        let level = self.mapped_level();
+        if level != 0 && level != Level4::LEVEL {
+            return Err(SysError::InvalidValue);
+        }
+
+        let mut vspace = VSpace::from_root(self.as_table_mut());
+        vspace.teardown();
+        crate::arch::dmb();
+        if let Some(asid) = ASID_ALLOCATOR.lock().lookup(self.paddr(), self.asid_hint()) {
+            ::vspace::arch::mmu::invalidate_tlb_asid_is(asid);
+        }
 
         Ok(())
     }
 
     pub fn derive(&self, dst: &NullCap) -> SysResult<()> {
-        dst.raw.set(self.raw());
+        cnode_entry_copy(self.raw, dst.raw);
         Ok(())
     }
 
@@ -0,0 +1,27 @@
+use crate::prelude::*;
+
+use pi::core_mailbox::CoreMailbox;
+
+const CORE_MAILBOX_BASE: usize = KERNEL_OFFSET + 0x40000000;
+
+/* Mailbox 0 of every core is reserved for kernel IPIs */
+const IPI_MAILBOX: usize = 0;
+const IPI_RESCHEDULE: u32 = 1 << 0;
+
+pub fn init(cpu: usize) {
+    CoreMailbox::new(CORE_MAILBOX_BASE).enable_irq(cpu, IPI_MAILBOX);
+}
+
+/* Ask `cpu` to pick up threads that were made runnable on its queues */
+pub fn send_reschedule(cpu: usize) {
+    CoreMailbox::new(CORE_MAILBOX_BASE).send(cpu, IPI_MAILBOX, IPI_RESCHEDULE);
+}
+
+pub fn is_pending(cpu: usize) -> bool {
+    CoreMailbox::new(CORE_MAILBOX_BASE).is_pending(cpu, IPI_MAILBOX)
+}
+
+/* Acknowledge the IPIs pending on `cpu`. Rescheduling is the only request so far */
+pub fn take(cpu: usize) -> u32 {
+    CoreMailbox::new(CORE_MAILBOX_BASE).take(cpu, IPI_MAILBOX)
+}
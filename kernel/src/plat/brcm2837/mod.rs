@@ -1,2 +1,3 @@
 pub mod interrupt;
+pub mod ipi;
 pub mod uart;
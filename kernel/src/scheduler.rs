@@ -1,57 +1,191 @@
+use crate::arch::cpuid;
 use crate::objects::TcbObj;
+use crate::utils::kernel_lock::KERNEL_LOCK;
 use crate::utils::percore::PerCore;
 use crate::utils::tcb_queue::TcbQueue;
 use crate::NCPU;
-use core::cell::UnsafeCell;
+use core::cell::{Cell, UnsafeCell};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use log::warn;
 
 const DEFAULT_SCHEDULER: UnsafeCell<Scheduler> = UnsafeCell::new(Scheduler::new());
 pub static SCHEDULER: PerCore<Scheduler, NCPU> = PerCore([DEFAULT_SCHEDULER; NCPU]);
 
+/*
+ * The thread each core runs in user mode, by address, or 0. A core sets it right before leaving
+ * the kernel and clears it on its way back in, once the registers of the thread are saved and
+ * before it waits for the kernel lock. A core holding the lock can thus wait for another one to
+ * stop running a thread.
+ */
+const NO_THREAD: AtomicUsize = AtomicUsize::new(0);
+static USER_THREAD: [AtomicUsize; NCPU] = [NO_THREAD; NCPU];
+
+/*
+ * The thread each core last left the kernel to, only touched under the kernel lock. A deleted
+ * thread is cleared here, so that a core which trapped out of it while the deletion went on
+ * does not touch the object any more once it gets the lock.
+ */
+const NO_CURRENT: UnsafeCell<Cell<usize>> = UnsafeCell::new(Cell::new(0));
+static CURRENT_THREAD: PerCore<Cell<usize>, NCPU> = PerCore([NO_CURRENT; NCPU]);
+
+/* Called with the kernel lock held, right before `tcb` runs on this core */
+pub fn enter_thread(tcb: &TcbObj) {
+    let addr = tcb as *const _ as usize;
+    CURRENT_THREAD.get().set(addr);
+    USER_THREAD[cpuid()].store(addr, Ordering::Relaxed);
+}
+
+/* Called on every trap from user mode, before taking the kernel lock */
+pub fn leave_thread() {
+    USER_THREAD[cpuid()].store(0, Ordering::Release);
+}
+
+/* Whether the thread this core trapped out of is still alive, with the kernel lock held */
+pub fn is_current(tcb: &TcbObj) -> bool {
+    CURRENT_THREAD.get().get() == tcb as *const _ as usize
+}
+
+/*
+ * Make sure `tcb` does not run in user mode on another core any more, which then goes through
+ * the scheduler. The IPI is acknowledged as soon as that core enters the kernel, so this does
+ * not need it to get the kernel lock.
+ */
+pub fn stop_thread(tcb: &TcbObj) {
+    let cpu = tcb.cpu();
+    let addr = tcb as *const _ as usize;
+    if cpu == cpuid() || USER_THREAD[cpu].load(Ordering::Acquire) != addr {
+        return;
+    }
+    crate::plat::ipi::send_reschedule(cpu);
+    while USER_THREAD[cpu].load(Ordering::Acquire) == addr {
+        core::hint::spin_loop();
+    }
+}
+
+/* `tcb` is being deleted, the core it last ran on must forget about it */
+pub fn forget_thread(tcb: &TcbObj) {
+    let current = unsafe { CURRENT_THREAD.get_unsafe(tcb.cpu()) };
+    if current.get() == tcb as *const _ as usize {
+        current.set(0);
+    }
+}
+
+pub const NUM_PRIORITIES: usize = 256;
+pub const DEFAULT_PRIORITY: usize = 128;
+const READY_WORDS: usize = NUM_PRIORITIES / 64;
+
+/*
+ * One run queue per priority, and a bitmap of the priorities which have ready threads. The
+ * running thread stays at the head of its queue until it blocks or its time slice runs out.
+ */
 #[derive(Debug)]
 pub struct Scheduler {
-    queue: TcbQueue,
+    queues: [TcbQueue; NUM_PRIORITIES],
+    ready: [Cell<u64>; READY_WORDS],
 }
 
 impl Scheduler {
     pub const fn new() -> Self {
+        const EMPTY_QUEUE: TcbQueue = TcbQueue::new();
+        const EMPTY_WORD: Cell<u64> = Cell::new(0);
         Self {
-            queue: TcbQueue::new(),
+            queues: [EMPTY_QUEUE; NUM_PRIORITIES],
+            ready: [EMPTY_WORD; READY_WORDS],
         }
     }
 
+    /*
+     * Threads which block leave their run queue without telling the scheduler, so a set bit only
+     * means the queue may be non-empty. Stale bits are cleared here.
+     */
+    fn highest_priority(&self) -> Option<usize> {
+        for word in (0..READY_WORDS).rev() {
+            let mut bits = self.ready[word].get();
+            while bits != 0 {
+                let prio = word * 64 + 63 - bits.leading_zeros() as usize;
+                if !self.queues[prio].is_empty() {
+                    return Some(prio);
+                }
+                bits &= !(1 << (prio % 64));
+                self.ready[word].set(bits);
+            }
+        }
+        None
+    }
+
     pub fn push(&self, tcb: &TcbObj) {
-        self.queue.enqueue(tcb)
+        let prio = tcb.priority();
+        self.queues[prio].enqueue(tcb);
+        let word = &self.ready[prio / 64];
+        word.set(word.get() | 1 << (prio % 64));
     }
 
     pub fn head(&self) -> Option<&TcbObj> {
-        self.queue.head()
+        self.highest_priority()
+            .and_then(|prio| self.queues[prio].head())
     }
 
     pub fn head_mut(&self) -> Option<&mut TcbObj> {
-        self.queue.head_mut()
+        self.highest_priority()
+            .and_then(|prio| self.queues[prio].head_mut())
     }
 
     pub fn pop(&self) -> Option<&'static mut TcbObj> {
-        self.queue.dequeue()
+        self.highest_priority()
+            .and_then(|prio| self.queues[prio].dequeue())
     }
 
     pub fn activate(&self) -> ! {
-        if let Some(head) = self.head() {
-            if (head.timeslice()) == 0 {
-                let tcb = self.pop().unwrap();
-                self.push(tcb);
-
-                self.head()
-                    .unwrap()
-                    .set_timeslice(crate::TIME_SLICE as usize);
+        let cpuid = cpuid();
+        while let Some(prio) = self.highest_priority() {
+            let queue = &self.queues[prio];
+            let head = queue.head().unwrap();
+
+            /*
+             * The thread was moved to another core while queued here. It is not running now that
+             * this core is in the kernel, so it is safe to hand it over.
+             */
+            if head.affinity() != cpuid {
+                crate::SCHEDULER.push(queue.dequeue().unwrap());
+                continue;
             }
-            self.head_mut().unwrap().activate();
-        } else {
-            warn!("not schedulable TCB. wait for interrupt!");
-            loop {
-                crate::arch::wfe()
+
+            if head.timeslice() == 0 {
+                let tcb = queue.dequeue().unwrap();
+                queue.enqueue(tcb);
+
+                let head = queue.head().unwrap();
+                head.set_timeslice(head.timeslice_len());
+                continue;
             }
+
+            queue.head_mut().unwrap().activate();
+        }
+
+        warn!("not schedulable TCB. wait for interrupt!");
+        KERNEL_LOCK.unlock();
+        loop {
+            crate::arch::wfe()
+        }
+    }
+}
+
+impl PerCore<Scheduler, NCPU> {
+    /* Make `tcb` runnable on the core it has affinity to */
+    pub fn push(&self, tcb: &TcbObj) {
+        self.push_to(tcb.affinity(), tcb)
+    }
+
+    /*
+     * A remote core is interrupted so that it notices the new thread. A thread that was woken up
+     * before its deadline stops waiting for it.
+     */
+    pub fn push_to(&self, cpu: usize, tcb: &TcbObj) {
+        tcb.cancel_timeout();
+        tcb.set_cpu(cpu);
+        unsafe { self.get_unsafe(cpu) }.push(tcb);
+        if cpu != cpuid() {
+            crate::plat::ipi::send_reschedule(cpu);
         }
     }
 }
@@ -1,13 +1,81 @@
+use core::cell::Cell;
 use core::num::NonZeroUsize;
 
+use crate::arch::cpuid;
+use crate::arch::generic_timer::Timer;
+use crate::cspace::CSpace;
 use crate::objects::*;
 use crate::prelude::*;
+use crate::scheduler::NUM_PRIORITIES;
+use crate::timeout::TIMEOUT_QUEUE;
 
 pub use sysapi::syscall::{MsgInfo, RespInfo, SyscallOp};
-use vspace::{arch::Level1, VirtAddr};
+use vspace::VirtAddr;
 
 use core::convert::TryFrom;
 
+/*
+ * Resolve `index` with `depth` bits in the CNode that `root_cptr` names in the caller's CSpace.
+ */
+fn lookup_cnode_slot(
+    cspace: &CSpace,
+    root_cptr: usize,
+    index: usize,
+    depth: usize,
+) -> SysResult<&'static CNodeEntry> {
+    let root_slot = cspace.lookup_slot(root_cptr)?;
+    let root = CNodeCap::try_from(root_slot)?;
+    Ok(root.resolve_address(index, depth)?)
+}
+
+/*
+ * A thread can only hand out priorities up to its own maximum controlled priority.
+ */
+fn check_priority(tcb: &TcbObj, prio: usize) -> SysResult<()> {
+    if prio >= NUM_PRIORITIES {
+        Err(SysError::InvalidValue)
+    } else if prio > tcb.mcp() {
+        Err(SysError::InsufficientRights)
+    } else {
+        Ok(())
+    }
+}
+
+/*
+ * Destination and source slots of a CNode operation. MR0 and MR1 are the destination root CNode
+ * and the index in it, MR2 and MR3 the same for the source. MR4 holds the destination depth in
+ * bits 0..8, the source depth in bits 8..16 and operation specific bits above, which are returned.
+ */
+fn cnode_op_slots(tcb: &TcbObj) -> SysResult<(&'static CNodeEntry, &'static CNodeEntry, usize)> {
+    let cspace = tcb.cspace()?;
+    let depths = tcb.get_mr(4);
+
+    let dst_slot = lookup_cnode_slot(&cspace, tcb.get_mr(0), tcb.get_mr(1), depths & MASK!(8))?;
+    let src_slot = lookup_cnode_slot(
+        &cspace,
+        tcb.get_mr(2),
+        tcb.get_mr(3),
+        (depths >> 8) & MASK!(8),
+    )?;
+    if src_slot.get().cap_type() == ObjType::NullObj {
+        return Err(SysError::SlotEmpty);
+    }
+
+    Ok((dst_slot, src_slot, depths >> 16))
+}
+
+/*
+ * A Recv or Call that had to block gives up once its timeout passes. A Call keeps counting while
+ * it waits for the reply.
+ */
+fn start_timeout(tcb: &TcbObj, msginfo: MsgInfo) {
+    let ticks = msginfo.get_timeout();
+    if ticks != 0 && tcb.state() != ThreadState::Ready {
+        let now = Timer::new().counter();
+        TIMEOUT_QUEUE.get().insert(tcb, now + ticks as u64);
+    }
+}
+
 fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
     use num_traits::FromPrimitive;
 
@@ -43,6 +111,7 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
                 ObjType::Reply => ReplyCap::try_from(cap).unwrap().identify(tcb),
                 ObjType::Monitor => MonitorCap::try_from(cap).unwrap().identify(tcb),
                 ObjType::Interrupt => InterruptCap::try_from(cap).unwrap().identify(tcb),
+                ObjType::Notification => NotificationCap::try_from(cap).unwrap().identify(tcb),
             };
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, ret_num));
@@ -50,25 +119,94 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
             Ok(())
         }
         SyscallOp::CapCopy => {
-            let cspace = tcb.cspace()?;
-
-            let dst_croot_cptr = tcb.get_mr(0);
-            let dst_croot_slot = cspace.lookup_slot(dst_croot_cptr)?;
-            let dst_croot_cap = CNodeCap::try_from(dst_croot_slot)?;
+            if msginfo.get_length() < 5 {
+                return Err(SysError::InvalidValue);
+            }
 
-            let dst_offset = tcb.get_mr(1);
-            let dst_slot = dst_croot_cap.lookup_slot(dst_offset)?;
+            let (dst_slot, src_slot, _) = cnode_op_slots(tcb)?;
             let dst_cap = NullCap::try_from(dst_slot)?;
 
-            let cap_idx = tcb.get_mr(2);
-            let cap_slot = cspace.lookup_slot(cap_idx)?;
+            let derived_raw = cap_derive(&src_slot, CapRights::ALL, None)?;
+            dst_cap.insert_raw(derived_raw);
+            cnode_entry_append_next(&src_slot, dst_cap.raw);
 
-            let badge = tcb.get_mr(3);
-            let badge = NonZeroUsize::new(badge);
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::CapMint => {
+            if msginfo.get_length() < 6 {
+                return Err(SysError::InvalidValue);
+            }
 
-            let derived_raw = cap_derive(&cap_slot, badge)?;
+            let (dst_slot, src_slot, extra) = cnode_op_slots(tcb)?;
+            let dst_cap = NullCap::try_from(dst_slot)?;
+
+            let rights = CapRights::from_bits(extra);
+            let badge = NonZeroUsize::new(tcb.get_mr(5));
+
+            let derived_raw = cap_derive(&src_slot, rights, badge)?;
             dst_cap.insert_raw(derived_raw);
-            cnode_entry_append_next(&cap_slot, dst_cap.raw);
+            cnode_entry_append_next(&src_slot, dst_cap.raw);
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::CapMove => {
+            if msginfo.get_length() < 5 {
+                return Err(SysError::InvalidValue);
+            }
+
+            let (dst_slot, src_slot, _) = cnode_op_slots(tcb)?;
+            NullCap::try_from(dst_slot)?;
+
+            cnode_entry_move(src_slot, dst_slot);
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::CapMutate => {
+            if msginfo.get_length() < 6 {
+                return Err(SysError::InvalidValue);
+            }
+
+            let (dst_slot, src_slot, _) = cnode_op_slots(tcb)?;
+            NullCap::try_from(dst_slot)?;
+
+            cap_mutate(src_slot, tcb.get_mr(5))?;
+            cnode_entry_move(src_slot, dst_slot);
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::CapRotate => {
+            if msginfo.get_length() < 6 {
+                return Err(SysError::InvalidValue);
+            }
+
+            let (dst_slot, src_slot, pivot_depth) = cnode_op_slots(tcb)?;
+
+            let cspace = tcb.cspace()?;
+            let pivot_slot = lookup_cnode_slot(
+                &cspace,
+                tcb.get_mr(0),
+                tcb.get_mr(5),
+                pivot_depth & MASK!(8),
+            )?;
+            if pivot_slot.get().cap_type() == ObjType::NullObj {
+                return Err(SysError::SlotEmpty);
+            }
+            if core::ptr::eq(pivot_slot, src_slot) || core::ptr::eq(pivot_slot, dst_slot) {
+                return Err(SysError::InvalidValue);
+            }
+
+            /* With the same source and destination the two caps are swapped */
+            if core::ptr::eq(dst_slot, src_slot) {
+                cnode_entry_swap(pivot_slot, src_slot);
+            } else {
+                NullCap::try_from(dst_slot)?;
+                cnode_entry_move(pivot_slot, dst_slot);
+                cnode_entry_move(src_slot, pivot_slot);
+            }
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
             Ok(())
@@ -82,13 +220,27 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
                 return Err(SysError::CapabilityTypeError);
             }
 
-            //TODO: check children etc.
-            //TODO: changing next and prev cap ptr.
-            cap_slot.set(NullCap::mint());
+            cap_delete(cap_slot)?;
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::CapRevoke => {
+            let cspace = tcb.cspace()?;
+
+            let cptr = tcb.get_mr(0);
+            let cap_slot = cspace.lookup_slot(cptr)?;
+            if cap_slot.get().cap_type() == ObjType::NullObj {
+                return Err(SysError::CapabilityTypeError);
+            }
+
+            cap_revoke(cap_slot)?;
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
             Ok(())
         }
         SyscallOp::Retype => {
-            if msginfo.get_length() < 4 {
+            if msginfo.get_length() < 6 {
                 return Err(SysError::InvalidValue);
             }
             let cap_idx = tcb.get_mr(0);
@@ -98,16 +250,38 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
 
             let obj_type = ObjType::from_usize(tcb.get_mr(1)).ok_or(SysError::InvalidValue)?;
             let bit_size = tcb.get_mr(2);
-            let slot_start = tcb.get_mr(3);
-            let _slot_len = tcb.get_mr(4); //TODO: find some way to do batch op
-            let slots = &cspace[slot_start];
 
-            cap.retype(obj_type, bit_size, core::slice::from_ref(slots))?;
+            let dst_cnode_cptr = tcb.get_mr(3);
+            let dst_cnode_slot = cspace.lookup_slot(dst_cnode_cptr)?;
+            let dst_cnode = CNodeCap::try_from(dst_cnode_slot)?;
+
+            let slot_start = tcb.get_mr(4);
+            let slot_len = tcb.get_mr(5);
+            if slot_len == 0 {
+                return Err(SysError::InvalidValue);
+            }
+            let slots = slot_start
+                .checked_add(slot_len)
+                .and_then(|slot_end| dst_cnode.as_object().get(slot_start..slot_end))
+                .ok_or(SysError::LookupError)?;
+
+            cap.retype(obj_type, bit_size, slots)?;
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
 
             Ok(())
         }
+        SyscallOp::UntypedReset => {
+            let cap_idx = tcb.get_mr(0);
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(cap_idx)?;
+            let cap = UntypedCap::try_from(cap_slot)?;
+
+            cap.reset()?;
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
         SyscallOp::TcbConfigure => {
             if msginfo.get_length() < 3 {
                 return Err(SysError::InvalidValue);
@@ -115,7 +289,7 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
             let cap_idx = tcb.get_mr(0);
             let host_cspace = tcb.cspace()?;
             let cap_slot = host_cspace.lookup_slot(cap_idx)?;
-            let cap = TcbCap::try_from(cap_slot)?;
+            let mut cap = TcbCap::try_from(cap_slot)?;
 
             let vspace_cap_idx = tcb.get_mr(1);
             let vspace_cap = if vspace_cap_idx != 0 {
@@ -141,30 +315,138 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
                 None
             };
 
-            cap.configure(cspace_cap, vspace_cap, fault_ep_cap)?;
+            let ipc_buf_cap_idx = tcb.get_mr(4);
+            let ipc_buf = if ipc_buf_cap_idx != 0 {
+                let buf_slot = host_cspace.lookup_slot(ipc_buf_cap_idx)?;
+                Some((RamCap::try_from(buf_slot)?, tcb.get_mr(5)))
+            } else {
+                None
+            };
+
+            cap.configure(cspace_cap, vspace_cap, fault_ep_cap, ipc_buf)?;
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
             Ok(())
         }
-        SyscallOp::TcbSetRegisters => {
-            if msginfo.get_length() < 3 {
+        SyscallOp::TcbReadRegisters => {
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let cap = TcbCap::try_from(cap_slot)?;
+            let buf = tcb.ipc_buffer().ok_or(SysError::InvalidValue)?;
+
+            cap.tf.get_context(buf.user_context());
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::TcbWriteRegisters => {
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let mut cap = TcbCap::try_from(cap_slot)?;
+            let buf = tcb.ipc_buffer().ok_or(SysError::InvalidValue)?;
+
+            cap.tf.set_context(buf.user_context());
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::TcbBindNotification => {
+            if msginfo.get_length() < 2 {
                 return Err(SysError::InvalidValue);
             }
 
-            let cap_idx = tcb.get_mr(0);
             let cspace = tcb.cspace()?;
-            let cap_slot = cspace.lookup_slot(cap_idx)?;
-            let mut cap = TcbCap::try_from(cap_slot)?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let cap = TcbCap::try_from(cap_slot)?;
+
+            /* A null notification cptr unbinds */
+            let ntfn_idx = tcb.get_mr(1);
+            let ntfn = if ntfn_idx != 0 {
+                let ntfn_slot = cspace.lookup_slot(ntfn_idx)?;
+                let ntfn = NotificationCap::try_from(ntfn_slot)?;
+                ntfn.check_rights(CapRights::READ)?;
+                Some(ntfn)
+            } else {
+                None
+            };
 
-            let reg_flags = tcb.get_mr(1);
-            if reg_flags & 0b1000 == 0b1000 {
-                let elr = tcb.get_mr(2);
-                cap.tf.set_elr(elr);
+            cap.bind_notification(ntfn)?;
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::TcbSetPriority => {
+            if msginfo.get_length() < 2 {
+                return Err(SysError::InvalidValue);
             }
 
-            if reg_flags & 0b0100 == 0b0100 {
-                let sp = tcb.get_mr(3);
-                cap.tf.set_sp(sp);
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let cap = TcbCap::try_from(cap_slot)?;
+
+            let prio = tcb.get_mr(1);
+            check_priority(tcb, prio)?;
+            cap.set_priority(prio);
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::TcbSetMaxControlledPriority => {
+            if msginfo.get_length() < 2 {
+                return Err(SysError::InvalidValue);
+            }
+
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let cap = TcbCap::try_from(cap_slot)?;
+
+            let mcp = tcb.get_mr(1);
+            check_priority(tcb, mcp)?;
+            cap.set_mcp(mcp);
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::TcbSetTimeSlice => {
+            if msginfo.get_length() < 2 {
+                return Err(SysError::InvalidValue);
+            }
+
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let cap = TcbCap::try_from(cap_slot)?;
+
+            /* In microseconds, used up one timer tick at a time */
+            let len = tcb.get_mr(1);
+            if len == 0 {
+                return Err(SysError::InvalidValue);
+            }
+            cap.set_timeslice_len(len);
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::TcbSetAffinity => {
+            if msginfo.get_length() < 2 {
+                return Err(SysError::InvalidValue);
+            }
+
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let cap = TcbCap::try_from(cap_slot)?;
+
+            let cpu = tcb.get_mr(1);
+            if cpu >= crate::NCPU {
+                return Err(SysError::InvalidValue);
+            }
+            cap.set_affinity(cpu);
+
+            /* Whichever core has the thread queued hands it over once it reschedules */
+            if cap.state() == ThreadState::Ready && cap.node.is_attached() {
+                let owner = cap.cpu();
+                if owner != cpuid() {
+                    crate::plat::ipi::send_reschedule(owner);
+                }
             }
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
@@ -176,28 +458,73 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
             let cap_slot = cspace.lookup_slot(cap_idx)?;
 
             let cap = TcbCap::try_from(cap_slot)?;
-            crate::SCHEDULER.get_mut().push(&cap);
+            cap.resume();
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
             Ok(())
         }
-        SyscallOp::EndpointSend => {
+        SyscallOp::TcbSuspend => {
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let mut cap = TcbCap::try_from(cap_slot)?;
+
+            /* Suspending itself, the caller still gets its answer once resumed */
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            cap.suspend();
+
+            Ok(())
+        }
+        SyscallOp::ThreadExit => {
+            tcb.suspend();
+            Ok(())
+        }
+        SyscallOp::EndpointSend | SyscallOp::EndpointNBSend => {
             let cap_idx = tcb.get_mr(0);
             let cspace = tcb.cspace()?;
             let cap_slot = cspace.lookup_slot(cap_idx)?;
 
             let cap = EndpointCap::try_from(cap_slot)?;
+            cap.check_rights(CapRights::WRITE)?;
+            if msginfo.caps != 0 {
+                cap.check_rights(CapRights::GRANT)?;
+            }
+            /* Without a receiver waiting the non-blocking send times out right away */
+            if msginfo.get_label() == SyscallOp::EndpointNBSend && cap.state() != EpState::Receiving
+            {
+                return Err(SysError::Timeout);
+            }
             cap.handle_send(msginfo, tcb)?;
 
             Ok(())
         }
-        SyscallOp::EndpointRecv => {
+        SyscallOp::EndpointRecv | SyscallOp::EndpointNBRecv => {
             let cap_idx = tcb.get_mr(0);
             let cspace = tcb.cspace()?;
             let cap_slot = cspace.lookup_slot(cap_idx)?;
 
             let cap = EndpointCap::try_from(cap_slot)?;
+            cap.check_rights(CapRights::READ)?;
+
+            /* A signal pending on the bound notification is received instead of a message */
+            let bound = Cell::new(tcb.bound_notification());
+            let ntfn = NotificationCap::try_from(&bound).ok();
+            if let Some(signal) = ntfn.as_ref().and_then(|n| n.poll()) {
+                tcb.set_mr(1, signal);
+                tcb.set_respinfo(RespInfo::new_notification());
+                return Ok(());
+            }
+
+            if msginfo.get_label() == SyscallOp::EndpointNBRecv && cap.state() != EpState::Sending {
+                return Err(SysError::Timeout);
+            }
             cap.handle_recv(msginfo, tcb)?;
+            start_timeout(tcb, msginfo);
+
+            if let Some(n) = ntfn {
+                if tcb.state() == ThreadState::Receiving {
+                    n.listen_irq();
+                }
+            }
 
             Ok(())
         }
@@ -207,13 +534,31 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
             let cap_slot = cspace.lookup_slot(cap_idx)?;
 
             let cap = EndpointCap::try_from(cap_slot)?;
+            cap.check_rights(CapRights::WRITE)?;
+            if msginfo.caps != 0 {
+                cap.check_rights(CapRights::GRANT)?;
+            }
             cap.handle_call(msginfo, tcb)?;
+            start_timeout(tcb, msginfo);
 
             Ok(())
         }
         SyscallOp::EndpointReply => {
-            let reply = tcb.reply_cap().ok_or(SysError::LookupError)?;
-            reply.handle_reply(msginfo, tcb, false)?;
+            /* MR0 names a reply cap saved by SaveCaller, 0 uses the pending one */
+            let reply_cptr = tcb.get_mr(0);
+            if reply_cptr == 0 {
+                let reply = tcb.reply_cap().ok_or(SysError::LookupError)?;
+                reply.handle_reply(msginfo, tcb, false)?;
+                tcb.set_reply(None);
+            } else {
+                let cspace = tcb.cspace()?;
+                let reply_slot = cspace.lookup_slot(reply_cptr)?;
+                let reply_cap = ReplyCap::try_from(reply_slot)?;
+                let reply = ReplyObj(reply_cap.waiting_tcb());
+                reply.handle_reply(msginfo, tcb, false)?;
+                /* A reply cap can be used only once */
+                cap_delete(reply_slot)?;
+            }
             // tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
 
             Ok(())
@@ -221,6 +566,7 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
         SyscallOp::EndpointReplyRecv => {
             let reply = tcb.reply_cap().ok_or(SysError::LookupError)?;
             reply.handle_reply(msginfo, tcb, true)?;
+            tcb.set_reply(None);
 
             // let cap_idx = tcb.get_mr(0);
             // let cspace = tcb.cspace()?;
@@ -231,6 +577,46 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
 
             Ok(())
         }
+        SyscallOp::SaveCaller => {
+            let cspace = tcb.cspace()?;
+            let slot = cspace.lookup_slot(tcb.get_mr(0))?;
+
+            tcb.save_caller(slot)?;
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::NotificationSignal => {
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let cap = NotificationCap::try_from(cap_slot)?;
+            cap.check_rights(CapRights::WRITE)?;
+
+            cap.signal(cap.signal_bits());
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::NotificationWait => {
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let cap = NotificationCap::try_from(cap_slot)?;
+            cap.check_rights(CapRights::READ)?;
+
+            cap.wait(tcb);
+
+            Ok(())
+        }
+        SyscallOp::NotificationPoll => {
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(tcb.get_mr(0))?;
+            let cap = NotificationCap::try_from(cap_slot)?;
+            cap.check_rights(CapRights::READ)?;
+
+            tcb.set_mr(1, cap.poll().unwrap_or(0));
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 1));
+            Ok(())
+        }
         SyscallOp::RamMap => {
             use vspace::VSpace;
 
@@ -251,12 +637,13 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
             let vspace_cap_idx = tcb.get_mr(1);
             let vaddr = tcb.get_mr(2);
             let rights = tcb.get_mr(3).into();
+            cap.check_permission(rights)?;
 
             let vspace_cap_slot = cspace.lookup_slot(vspace_cap_idx)?;
             let vspace_cap = VTableCap::try_from(vspace_cap_slot)?;
             let mut vspace = VSpace::from_root(vspace_cap.as_table_mut());
 
-            cap.map_page::<Level1>(&mut vspace, vaddr, rights)?;
+            cap.map(&mut vspace, vaddr, rights)?;
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
             Ok(())
@@ -277,6 +664,29 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
             Ok(())
         }
+        SyscallOp::RamRemap => {
+            if msginfo.get_length() < 1 {
+                return Err(SysError::InvalidValue);
+            }
+
+            let cap_idx = tcb.get_mr(0);
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(cap_idx)?;
+
+            let cap = RamCap::try_from(cap_slot)?;
+
+            if cap.mapped_vaddr() == 0 {
+                return Err(SysError::VSpaceCapNotMapped);
+            }
+
+            let rights = tcb.get_mr(1).into();
+            cap.check_permission(rights)?;
+
+            cap.remap(rights)?;
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
         SyscallOp::VTableMap => {
             use vspace::VSpace;
 
@@ -307,6 +717,33 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
             Ok(())
         }
+        SyscallOp::VTableUnmap => {
+            let cap_idx = tcb.get_mr(0);
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(cap_idx)?;
+
+            let cap = VTableCap::try_from(cap_slot)?;
+
+            if cap.mapped_vaddr() == 0 {
+                return Err(SysError::VSpaceCapNotMapped);
+            }
+
+            cap.unmap_vtable()?;
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
+        SyscallOp::VTableTeardown => {
+            let cap_idx = tcb.get_mr(0);
+            let cspace = tcb.cspace()?;
+            let cap_slot = cspace.lookup_slot(cap_idx)?;
+
+            let cap = VTableCap::try_from(cap_slot)?;
+            cap.teardown()?;
+
+            tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
+            Ok(())
+        }
         SyscallOp::MonitorMintUntyped => {
             if msginfo.get_length() < 4 {
                 return Err(SysError::InvalidValue);
@@ -324,15 +761,15 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
 
             let cap = cspace.lookup_slot(slot)?;
 
-            NullCap::try_from(cap)?
-                .insert::<UntypedObj>(UntypedCap::mint(paddr, bit_size, is_device));
+            NullCap::try_from(cap)?.insert::<UntypedObj>(
+                UntypedCap::mint(paddr, bit_size, is_device).into_revocable(),
+            );
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
 
             Ok(())
         }
         SyscallOp::MonitorInsertTcbToCpu => {
-            use crate::scheduler::SCHEDULER;
             // if msginfo.get_length() < 4 {
             //     return Err(SysError::InvalidValue);
             // }
@@ -348,35 +785,36 @@ fn _handle_syscall(tcb: &mut TcbObj) -> SysResult<()> {
             let tcb_slot = cspace.lookup_slot(tcb_idx)?;
 
             let tcb_cap = TcbCap::try_from(tcb_slot)?;
-            unsafe {
-                SCHEDULER.get_unsafe(cpu).push(&tcb_cap);
+            if cpu >= crate::NCPU {
+                return Err(SysError::InvalidValue);
             }
+            tcb_cap.set_affinity(cpu);
+            crate::SCHEDULER.push(&tcb_cap);
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
 
             Ok(())
         }
         SyscallOp::InterruptAttachIrq => {
-            use core::cell::Cell;
-
             let cap_idx = tcb.get_mr(0);
             let cspace = tcb.cspace()?;
             let cap_slot = cspace.lookup_slot(cap_idx)?;
 
             InterruptCap::try_from(cap_slot)?;
 
-            let ep = tcb.get_mr(1);
+            let ntfn = tcb.get_mr(1);
             let irq = tcb.get_mr(2);
 
-            let ep_slot = cspace.lookup_slot(ep)?;
-            let ep_cap = EndpointCap::try_from(ep_slot)?;
+            let ntfn_slot = cspace.lookup_slot(ntfn)?;
+            let ntfn_cap = NotificationCap::try_from(ntfn_slot)?;
+            ntfn_cap.check_rights(CapRights::WRITE)?;
 
-            ep_cap.set_attach(crate::objects::Attach::Irq(irq));
+            ntfn_cap.attach_irq(irq);
 
             unsafe {
                 crate::interrupt::INTERRUPT_CONTROLLER
                     .lock()
-                    .attach_irq(irq, Cell::new(ep_cap.raw()));
+                    .attach_irq(irq, Cell::new(ntfn_cap.raw()));
             }
 
             tcb.set_respinfo(RespInfo::new_syscall_resp(SysError::OK, 0));
@@ -0,0 +1,61 @@
+use crate::objects::TcbObj;
+use crate::utils::percore::PerCore;
+use crate::utils::tcb_queue::TcbQueueNode;
+use crate::NCPU;
+use core::cell::UnsafeCell;
+
+const DEFAULT_TIMEOUT_QUEUE: UnsafeCell<TimeoutQueue> = UnsafeCell::new(TimeoutQueue::new());
+pub static TIMEOUT_QUEUE: PerCore<TimeoutQueue, NCPU> = PerCore([DEFAULT_TIMEOUT_QUEUE; NCPU]);
+
+/*
+ * Threads blocked with a deadline, earliest first. They are linked through their timeout node,
+ * so a thread sits in an endpoint queue and in here at the same time. Every core checks its own
+ * queue on each timer tick.
+ */
+#[derive(Debug)]
+pub struct TimeoutQueue {
+    node: TcbQueueNode,
+}
+
+impl TimeoutQueue {
+    pub const fn new() -> Self {
+        Self {
+            node: TcbQueueNode::new(),
+        }
+    }
+
+    pub fn insert(&self, tcb: &TcbObj, deadline: u64) {
+        tcb.cancel_timeout();
+        tcb.set_deadline(deadline);
+
+        let mut cur = self.node.get_next();
+        while let Some(node) = cur {
+            if core::ptr::eq(node, &self.node) {
+                break;
+            }
+            if unsafe { node.tcb() }.deadline() > deadline {
+                node.prepend(&tcb.timeout_node);
+                return;
+            }
+            cur = node.get_next();
+        }
+        self.node.prepend(&tcb.timeout_node)
+    }
+
+    fn pop_expired<'a>(&self, now: u64) -> Option<&'a mut TcbObj> {
+        let node = self.node.get_next()?;
+        let tcb = unsafe { node.tcb_mut() };
+        if tcb.deadline() > now {
+            return None;
+        }
+        node.detach();
+        Some(tcb)
+    }
+
+    /* Fail the IPC of every thread whose deadline is at or before `now` */
+    pub fn expire(&self, now: u64) {
+        while let Some(tcb) = self.pop_expired(now) {
+            tcb.timeout();
+        }
+    }
+}
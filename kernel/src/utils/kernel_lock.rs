@@ -0,0 +1,37 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/*
+ * The big kernel lock. A core takes it when entering the kernel and drops it right before going
+ * back to a thread, so kernel objects are only touched by one core at a time.
+ *
+ * It is not a guard based lock because the paths leaving the kernel never return.
+ */
+pub struct KernelLock {
+    locked: AtomicBool,
+}
+
+impl KernelLock {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    pub fn lock(&self) {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn unlock(&self) {
+        self.locked.store(false, Ordering::Release)
+    }
+}
+
+pub static KERNEL_LOCK: KernelLock = KernelLock::new();
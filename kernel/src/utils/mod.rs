@@ -1,2 +1,3 @@
+pub mod kernel_lock;
 pub mod percore;
 pub mod tcb_queue;
@@ -78,6 +78,10 @@ impl TcbQueueNode {
         })
     }
 
+    pub fn is_attached(&self) -> bool {
+        self.prev.get().is_some()
+    }
+
     pub fn detach(&self) {
         if self.prev.get().is_none() {
             return;
@@ -108,7 +112,6 @@ impl TcbQueue {
         }
     }
 
-    #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
         self.node.next.get().is_none()
     }
@@ -117,6 +120,22 @@ impl TcbQueue {
         self.node.prepend(&tcb.node)
     }
 
+    /* Queue `tcb` behind every thread of the same or a higher priority */
+    pub fn enqueue_by_priority(&self, tcb: &TcbObj) {
+        let mut cur = self.node.get_next();
+        while let Some(node) = cur {
+            if core::ptr::eq(node, &self.node) {
+                break;
+            }
+            if unsafe { node.tcb() }.priority() < tcb.priority() {
+                node.prepend(&tcb.node);
+                return;
+            }
+            cur = node.get_next();
+        }
+        self.enqueue(tcb)
+    }
+
     pub fn dequeue<'a>(&self) -> Option<&'a mut TcbObj> {
         unsafe { self.node.pop_next().map(|x| x.tcb_mut()) }
     }
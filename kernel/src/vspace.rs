@@ -1,6 +1,65 @@
-use crate::prelude::KERNEL_OFFSET;
+use crate::prelude::*;
+use spin::Mutex;
 
 pub use vspace::arch::*;
 
 pub type VSpace<'a> = vspace::arch::VSpace::<'a, KERNEL_OFFSET>;
-pub type VirtAddr = vspace::VirtAddr<KERNEL_OFFSET>;
\ No newline at end of file
+pub type VirtAddr = vspace::VirtAddr<KERNEL_OFFSET>;
+
+/* RAM ends where the peripherals begin */
+const ROOT_BITMAP_WORDS: usize = (PHYS_IO_BASE >> 12) / 64;
+
+pub static VSPACE_ROOTS: Mutex<VSpaceRoots> = Mutex::new(VSpaceRoots::new());
+
+/*
+ * The root tables of the VSpaces in use, one bit per frame of RAM. Frames and tables only record
+ * the address of the root they are mapped in, which is walked only while the root is in here:
+ * once the last capability to a root is deleted, its memory may be reset and retyped into
+ * anything.
+ *
+ * A root that comes back at the same address is walked again for the mappings recorded before,
+ * which only ever touch entries that still refer to the same frame or table.
+ */
+pub struct VSpaceRoots([u64; ROOT_BITMAP_WORDS]);
+
+impl VSpaceRoots {
+    pub const fn new() -> Self {
+        Self([0; ROOT_BITMAP_WORDS])
+    }
+
+    /* Device memory holds no root table */
+    fn bit_of(root: vspace::PhysAddr) -> SysResult<(usize, u64)> {
+        let frame = root.0 >> 12;
+        if frame >= ROOT_BITMAP_WORDS * 64 {
+            return Err(SysError::InvalidValue);
+        }
+        Ok((frame / 64, 1 << (frame % 64)))
+    }
+
+    pub fn insert(&mut self, root: vspace::PhysAddr) -> SysResult<()> {
+        let (word, bit) = Self::bit_of(root)?;
+        self.0[word] |= bit;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, root: vspace::PhysAddr) {
+        if let Ok((word, bit)) = Self::bit_of(root) {
+            self.0[word] &= !bit;
+        }
+    }
+
+    pub fn contains(&self, root: vspace::PhysAddr) -> bool {
+        Self::bit_of(root).map_or(false, |(word, bit)| self.0[word] & bit != 0)
+    }
+}
+
+/*
+ * The VSpace rooted at `root_paddr`, as recorded in the capabilities of what is mapped in it, or
+ * `None` if that root is gone
+ */
+pub fn vspace_of_root(root_paddr: vspace::PhysAddr) -> Option<VSpace<'static>> {
+    if !VSPACE_ROOTS.lock().contains(root_paddr) {
+        return None;
+    }
+    Some(unsafe { VSpace::from_vaddr(VirtAddr::from(root_paddr).0 as *mut u8) })
+}
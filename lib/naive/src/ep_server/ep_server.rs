@@ -5,9 +5,11 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use hashbrown::HashMap;
 use spin::RwLock;
 
-use crate::ipc::{FaultMessage, IpcMessage, Message};
-use crate::objects::{EpCap};
-use crate::space_manager::{copy_cap_badged, gsm};
+use rustyl4api::objects::CapRights;
+
+use crate::ipc::{FaultMessage, IpcMessage, Message, IPC_MAX_CAPS};
+use crate::objects::{EpCap, NotificationCap, ReplyCap};
+use crate::space_manager::{copy_cap_badged, gsm, mint_cap, ROOT_TCB_CAP};
 
 pub struct BadgedEp {
     ep: EpCap,
@@ -73,13 +75,15 @@ pub struct EpServer {
     ntf_handler: RwLock<[Option<Box<dyn NotificationHandler>>; 64]>,
     fault_handlers: RwLock<HashMap<usize, Box<dyn FaultHandler>>>,
     ep: Ep,
+    ntfn: NotificationCap,
 }
 
 impl EpServer {
-    pub fn new(ep: EpCap) -> Self {
+    pub fn new(ep: EpCap, ntfn: NotificationCap) -> Self {
         const INIT_NTF_HANDLER: Option<Box<dyn NotificationHandler>> = None;
         Self {
             ep: Ep::from_unbadged(ep),
+            ntfn,
             msg_handlers: RwLock::new(HashMap::new()),
             ntf_handler: RwLock::new([INIT_NTF_HANDLER; 64]),
             fault_handlers: RwLock::new(HashMap::new()),
@@ -98,8 +102,9 @@ impl EpServer {
         Some(badged)
     }
 
-    pub fn handle_notification<T: 'static + NotificationHandler>(&self, ntf: usize, cb: T) -> Option<BadgedEp> {
-        let badged = self.ep.derive_badged_cap()?;
+    /// Signalling the returned capability runs `cb` with `ntf` in the server loop.
+    pub fn handle_notification<T: 'static + NotificationHandler>(&self, ntf: usize, cb: T) -> Option<NotificationCap> {
+        let badged = mint_cap(&self.ntfn, CapRights::WRITE, NonZeroUsize::new(1 << ntf))?;
         self.ntf_handler.write()[ntf] = Some(Box::new(cb));
         Some(badged)
     }
@@ -126,7 +131,13 @@ impl EpServer {
 
     fn handle_ipc(&self, ipc_msg: IpcMessage) {
         match ipc_msg {
-            IpcMessage::Message(msg) => {
+            IpcMessage::Message(mut msg) => {
+                /* Keep the caller around so that handlers can answer after the next receive */
+                if msg.need_reply {
+                    msg.reply = gsm!()
+                        .cspace_alloc()
+                        .and_then(|slot| ReplyCap::save_caller(slot).ok());
+                }
                 if let Some(b) = msg.badge {
                     if let Some(handler) = self.msg_handlers.read().get(&b) {
                         handler.handle_message(self, b, msg);
@@ -166,10 +177,18 @@ impl EpServer {
         }
     }
 
+    /// Serve messages and notifications forever. Must be called from the main thread, which the
+    /// server notification gets bound to.
     pub fn run(&self) {
+        if let Err(e) = ROOT_TCB_CAP.bind_notification(Some(&self.ntfn)) {
+            log::error!("Binding notification error {:?}", e);
+        }
+
         loop {
-            let recv_slot = gsm!().cspace_alloc().unwrap();
-            let ret = self.ep.ep.receive(Some(recv_slot));
+            let recv_slots = (0..IPC_MAX_CAPS)
+                .map(|_| gsm!().cspace_alloc().unwrap())
+                .collect();
+            let ret = self.ep.ep.receive(recv_slots);
             if let Ok(r) = ret {
                 self.handle_ipc(r);
             } else if let Err(e) = ret {
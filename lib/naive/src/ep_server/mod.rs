@@ -10,9 +10,10 @@ pub use msg_receiver::MsgReceiver;
 lazy_static! {
     pub static ref EP_SERVER: EpServer = {
         use crate::space_manager::gsm;
-        use crate::objects::EndpointObj;
+        use crate::objects::{EndpointObj, NotificationObj};
 
         let ep = gsm!().alloc_object::<EndpointObj>(12).unwrap();
-        EpServer::new(ep)
+        let ntfn = gsm!().alloc_object::<NotificationObj>(12).unwrap();
+        EpServer::new(ep, ntfn)
     };
 }
\ No newline at end of file
@@ -3,7 +3,7 @@ use core::future::Future;
 use core::ops::Drop;
 use core::pin::Pin;
 use core::task::{Context, Poll, Waker};
-use crossbeam_queue::{ArrayQueue, SegQueue};
+use crossbeam_queue::SegQueue;
 
 use crate::ep_server::{EpServer, MessageHandler};
 use crate::ipc::Message;
@@ -12,12 +12,12 @@ use crate::Result;
 
 struct MsgHandler {
     waker: Arc<SegQueue<Waker>>,
-    buf: Arc<ArrayQueue<Message>>,
+    buf: Arc<SegQueue<Message>>,
 }
 
 impl MessageHandler for MsgHandler {
     fn handle_message(&self, _ep_server: &EpServer, _badge: usize, msg: Message) {
-        self.buf.push(msg).unwrap();
+        self.buf.push(msg);
         while let Some(waker) = self.waker.pop() {
             waker.wake();
         }
@@ -28,13 +28,14 @@ pub struct MsgReceiver {
     badge: usize,
     ep_server: &'static EpServer,
     waker: Arc<SegQueue<Waker>>,
-    buf: Arc<ArrayQueue<Message>>,
+    buf: Arc<SegQueue<Message>>,
 }
 
 impl MsgReceiver {
     pub fn new(ep_server: &'static EpServer) -> Self {
         let waker = Arc::new(SegQueue::new());
-        let buf = Arc::new(ArrayQueue::new(10));
+        /* A long lmp message arrives as several messages in a row */
+        let buf = Arc::new(SegQueue::new());
         let handler = MsgHandler {
             waker: waker.clone(),
             buf: buf.clone(),
@@ -1,7 +1,9 @@
+use alloc::vec::Vec;
+
 pub use rustyl4api::fault::Fault;
 pub use rustyl4api::ipc::*;
 
-use crate::objects::CapSlot;
+use crate::objects::{CapSlot, ReplyCap};
 
 #[derive(Debug)]
 pub enum IpcMessage {
@@ -23,11 +25,12 @@ impl IpcMessage {
 
 #[derive(Debug)]
 pub struct Message {
-    pub payload: [usize; IPC_MAX_ARGS],
-    pub payload_len: usize,
+    pub payload: Vec<usize>,
     pub need_reply: bool,
-    pub cap_transfer: Option<CapSlot>,
+    pub caps: Vec<CapSlot>,
     pub badge: Option<usize>,
+    /// Saved reply capability of a call, see `EpServer`
+    pub reply: Option<ReplyCap>,
 }
 
 #[derive(Debug)]
@@ -32,11 +32,13 @@ pub mod ns;
 pub mod objects;
 pub mod os_str;
 mod os_str_bytes;
+pub mod pager;
 mod panic;
 pub mod path;
 pub mod process;
 pub mod rpc;
 pub mod rt;
+pub mod shm;
 pub mod space_manager;
 mod spaceman;
 pub mod task;
@@ -45,6 +47,9 @@ pub mod time;
 mod vm_allocator;
 
 pub use error::{Error, Result};
+pub use space_manager::untyped_stats;
+pub use spaceman::utspace_man::UntypedStats;
+pub use vm_allocator::{heap_stats, HeapStats};
 
 pub use naive_attributes::main;
 
@@ -1,132 +1,110 @@
 use alloc::vec::Vec;
+use core::mem::size_of;
 
 use crate::{
     ep_server::MsgReceiver,
-    objects::{EpCap, RamObj},
-    space_manager::{copy_cap, gsm},
+    ipc::{IPC_MAX_CAPS, IPC_MAX_MSG_LEN},
+    objects::EpCap,
     Result,
     Error,
 };
 
-use super::{ArgumentBuffer, LmpMessage};
+use super::LmpMessage;
 
+/// Bytes of a message that fit in one IPC, after the total length and the count of fragments
+/// still to come.
+const FRAGMENT_BYTES: usize = (IPC_MAX_MSG_LEN - 2) * size_of::<usize>();
+
+/// Messages travel in the IPC buffers, split over as many IPCs as they need.
 pub struct LmpChannel {
     remote_ntf_ep: EpCap,
     receiver: MsgReceiver,
-    argbuf: ArgumentBuffer,
-    role: Role,
-}
-
-pub enum Role {
-    Server,
-    Client,
 }
 
 impl LmpChannel {
-    pub fn new(
-        remote_ntf_ep: EpCap,
-        receiver: MsgReceiver,
-        argbuf: ArgumentBuffer,
-        role: Role,
-    ) -> Self {
+    pub fn new(remote_ntf_ep: EpCap, receiver: MsgReceiver) -> Self {
         Self {
             remote_ntf_ep,
             receiver,
-            argbuf,
-            role,
         }
     }
 
     pub async fn connect(server_ep: &EpCap, receiver: MsgReceiver) -> Result<Self> {
-        use rustyl4api::vspace::Permission;
-
         /* Connect by sending client notification ep */
         let ntf_ep = receiver.badged_ep();
-        server_ep.send(&[], Some(ntf_ep.into_slot())).unwrap();
+        let left = server_ep
+            .send(&[], alloc::vec![ntf_ep.into_slot()])
+            .unwrap();
+        if let Some(slot) = left.into_iter().next() {
+            drop(EpCap::new(slot));
+            return Err(Error::ProtocolError);
+        }
 
         let s_ntf_msg = receiver.receive().await.unwrap();
-        let svr_ntf_ep = s_ntf_msg.cap_transfer.ok_or(Error::ProtocolError)?;
+        let svr_ntf_ep = s_ntf_msg
+            .caps
+            .into_iter()
+            .next()
+            .ok_or(Error::ProtocolError)?;
         let svr_ntf_ep = EpCap::new(svr_ntf_ep);
 
-        /* Generate buffer cap and Derive a copy of buffer cap */
-        let buf_cap = gsm!().alloc_object::<RamObj>(12).unwrap();
-        let copied_cap = copy_cap(&buf_cap).unwrap();
-
-        /* service event notification */
-        let buf_ptr = gsm!().insert_ram_at(buf_cap, 0, Permission::writable());
-        let argbuf = unsafe { ArgumentBuffer::new(buf_ptr as *mut usize, 4096) };
-
-        /* send buffer cap to server */
-        svr_ntf_ep.send(&[], Some(copied_cap.into_slot())).unwrap();
-
-        Ok(Self::new(svr_ntf_ep, receiver, argbuf, Role::Client))
+        Ok(Self::new(svr_ntf_ep, receiver))
     }
 
-    fn send_channel(&mut self) -> &mut [u8] {
-        let argbuf_size = self.argbuf.len();
-        if let Role::Server = self.role {
-            &mut self.argbuf[0..argbuf_size / 2]
-        } else {
-            &mut self.argbuf[argbuf_size / 2..]
+    /// A message goes out in as many fragments as its bytes or its caps need. Caps the
+    /// receiver had no slot for are left in `msg.caps`, and the send fails.
+    fn send_message(&mut self, msg: &mut LmpMessage) -> Result<()> {
+        let byte_frags = (msg.msg.len() + FRAGMENT_BYTES - 1) / FRAGMENT_BYTES;
+        let cap_frags = (msg.caps.len() + IPC_MAX_CAPS - 1) / IPC_MAX_CAPS;
+        let nfrags = byte_frags.max(cap_frags).max(1);
+
+        let mut chunks = msg.msg.chunks(FRAGMENT_BYTES);
+        let mut left = Vec::new();
+        for i in 0..nfrags {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let mut words = Vec::with_capacity(IPC_MAX_MSG_LEN);
+            words.push(msg.msg.len());
+            words.push(nfrags - i - 1);
+            words.extend(chunk.chunks(size_of::<usize>()).map(|bytes| {
+                let mut word = [0; size_of::<usize>()];
+                word[..bytes.len()].copy_from_slice(bytes);
+                usize::from_le_bytes(word)
+            }));
+
+            let ncaps = msg.caps.len().min(IPC_MAX_CAPS);
+            let caps = msg.caps.drain(..ncaps).collect();
+            left.extend(self.remote_ntf_ep.send(&words, caps).unwrap());
         }
-    }
 
-    fn recv_channel(&mut self) -> &mut [u8] {
-        let argbuf_size = self.argbuf.len();
-        if let Role::Client = self.role {
-            &mut self.argbuf[0..argbuf_size / 2]
+        if left.is_empty() {
+            Ok(())
         } else {
-            &mut self.argbuf[argbuf_size / 2..]
+            msg.caps = left;
+            Err(Error::ProtocolError)
         }
     }
 
-    fn send_message(&mut self, msg: &mut LmpMessage) {
-        //TODO: handle msg > 2048. now panics.
-        let chan = self.send_channel();
-        chan[0] = 1;
-        chan[1] = msg.msg.len() as u8;
-        chan[2] = (msg.msg.len() >> 8) as u8;
-        chan[3..3 + msg.msg.len()].copy_from_slice(&msg.msg);
-        let cap_slot = msg.caps.pop();
-        self.remote_ntf_ep.send(&[], cap_slot).unwrap();
-    }
-
-    fn recv_message(&mut self) -> Option<LmpMessage> {
-        let chan = self.recv_channel();
-        if chan[0] == 0 {
-            return None;
-        }
-        let arglen = ((chan[2] as usize) << 8) | chan[1] as usize;
-        let msg = LmpMessage {
-            msg: chan[3..3 + arglen].to_vec(),
-            caps: Vec::new(),
-        };
-        chan[0] = 0;
-        Some(msg)
-    }
-
-    fn can_send(&mut self) -> bool {
-        self.send_channel()[0] == 0
-    }
-
-    // fn can_recv(&mut self) -> bool {
-    //     self.recv_channel()[0] == 0
-    // }
-
     pub async fn poll_send<'a>(&'a mut self, msg: &'a mut LmpMessage) -> Result<()> {
-        assert!(self.can_send());
-
-        self.send_message(msg);
-
-        Ok(())
+        self.send_message(msg)
     }
 
     pub async fn poll_recv(&mut self) -> Result<LmpMessage> {
-        let ep_msg = self.receiver.receive().await?;
-        let mut msg = self.recv_message().unwrap();
-        if let Some(cap) = ep_msg.cap_transfer {
-            msg.caps.push(cap);
+        let mut msg = LmpMessage::default();
+        loop {
+            let ep_msg = self.receiver.receive().await?;
+            if ep_msg.payload.len() < 2 {
+                return Err(Error::ProtocolError);
+            }
+            let (len, frags_left) = (ep_msg.payload[0], ep_msg.payload[1]);
+            for word in &ep_msg.payload[2..] {
+                msg.msg.extend_from_slice(&word.to_le_bytes());
+            }
+            msg.caps.extend(ep_msg.caps);
+            if frags_left == 0 {
+                /* Drop the padding of the last word */
+                msg.msg.truncate(len);
+                return Ok(msg);
+            }
         }
-        Ok(msg)
     }
 }
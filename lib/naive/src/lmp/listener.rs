@@ -12,12 +12,11 @@ use futures_util::stream::Stream;
 
 use crate::{
     ep_server::{EP_SERVER, MsgReceiver},
-    objects::{EpCap, RamCap},
-    space_manager::gsm,
+    objects::EpCap,
     Result, Error,
 };
 
-use super::{ArgumentBuffer, LmpChannel, Role};
+use super::LmpChannel;
 
 #[derive(Clone)]
 pub struct LmpListener {
@@ -30,28 +29,25 @@ impl LmpListener {
     }
 
     pub async fn accept(&self) -> Result<LmpChannel> {
-        use rustyl4api::vspace::Permission;
-
         let conn_msg = self.receiver.receive().await?;
-        let c_ntf_ep = conn_msg.cap_transfer.ok_or(Error::ProtocolError)?;
+        let c_ntf_ep = conn_msg
+            .caps
+            .into_iter()
+            .next()
+            .ok_or(Error::ProtocolError)?;
         let c_ntf_ep = EpCap::new(c_ntf_ep);
 
         let receiver = MsgReceiver::new(&EP_SERVER);
         let s_ntf_ep = receiver.badged_ep();
-        c_ntf_ep.send(&[], Some(s_ntf_ep.into_slot())).unwrap();
-
-        let shm_msg = receiver.receive().await?;
-        let buf_cap = shm_msg.cap_transfer.ok_or(Error::ProtocolError)?;
-        let buf_cap = RamCap::new(buf_cap);
-        let buf_ptr = gsm!().insert_ram_at(buf_cap, 0, Permission::writable());
-
-        let argbuf = unsafe { ArgumentBuffer::new(buf_ptr as *mut usize, 4096) };
-        Ok(LmpChannel::new(
-            c_ntf_ep,
-            receiver,
-            argbuf,
-            Role::Server,
-        ))
+        let left = c_ntf_ep
+            .send(&[], alloc::vec![s_ntf_ep.into_slot()])
+            .unwrap();
+        if let Some(slot) = left.into_iter().next() {
+            drop(EpCap::new(slot));
+            return Err(Error::ProtocolError);
+        }
+
+        Ok(LmpChannel::new(c_ntf_ep, receiver))
     }
 
     pub fn incoming(&mut self) -> IncomingFuture {
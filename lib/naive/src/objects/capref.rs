@@ -21,6 +21,11 @@ impl<T: KernelObject> CapRef<T> {
     pub fn from_slot_num(slot: usize) -> CapRef<T> {
         Self::from_slot(CapSlot::new(slot))
     }
+
+    /// The capability, if this is the last reference to it.
+    pub fn try_unwrap(self) -> Result<Capability<T>, CapRef<T>> {
+        Arc::try_unwrap(self.0).map_err(Self)
+    }
 }
 
 impl<T: KernelObject> Deref for CapRef<T> {
@@ -1,11 +1,13 @@
 use core::num::NonZeroUsize;
 
 use rustyl4api::error::SysResult;
+use rustyl4api::objects::CapRights;
 use rustyl4api::syscall::{syscall, MsgInfo, SyscallOp};
 
 use super::{Capability, KernelObject, ObjType};
 
 pub use rustyl4api::objects::{CNODE_DEPTH, CNODE_ENTRY_BIT_SZ, CNODE_ENTRY_SZ};
+use rustyl4api::objects::{RETYPE_CNODE_GUARD, RETYPE_CNODE_GUARD_SHIFT};
 
 #[derive(Debug, Clone)]
 pub enum CNodeObj {}
@@ -18,28 +20,117 @@ impl KernelObject for CNodeObj {
     }
 }
 
+/// CNode operations address slots by their index in a CNode, resolved with all `CNODE_DEPTH`
+/// bits. The invoked CNode is always the destination.
 impl CNodeCap {
-    pub fn cap_copy(&self, dst_slot: usize, src_slot: usize) -> SysResult<()> {
-        self.cap_copy_badged(dst_slot, src_slot, None)
-    }
-
-    pub fn cap_copy_badged(
+    fn cnode_op(
         &self,
+        op: SyscallOp,
         dst_slot: usize,
+        src_cnode: &CNodeCap,
         src_slot: usize,
-        badge: Option<NonZeroUsize>,
+        extra: usize,
+        data: usize,
     ) -> SysResult<()> {
-        let info = MsgInfo::new(SyscallOp::CapCopy, 3);
+        let info = MsgInfo::new(op, 6);
 
+        let depths = CNODE_DEPTH | CNODE_DEPTH << 8 | extra << 16;
         let mut args = [
             self.slot(),
             dst_slot,
+            src_cnode.slot(),
             src_slot,
-            badge.map(|b| b.get()).unwrap_or(0),
-            0,
-            0,
+            depths,
+            data,
         ];
 
         syscall(info, &mut args).map(|_| ())
     }
+
+    /// Copy the capability in `src_slot` of `src_cnode` into `dst_slot`.
+    pub fn cap_copy(
+        &self,
+        dst_slot: usize,
+        src_cnode: &CNodeCap,
+        src_slot: usize,
+    ) -> SysResult<()> {
+        self.cnode_op(SyscallOp::CapCopy, dst_slot, src_cnode, src_slot, 0, 0)
+    }
+
+    /// Copy `src_slot` of `src_cnode` into `dst_slot`, keeping only the rights in `rights` and
+    /// badging endpoints with `badge`.
+    pub fn cap_mint(
+        &self,
+        dst_slot: usize,
+        src_cnode: &CNodeCap,
+        src_slot: usize,
+        rights: CapRights,
+        badge: Option<NonZeroUsize>,
+    ) -> SysResult<()> {
+        self.cnode_op(
+            SyscallOp::CapMint,
+            dst_slot,
+            src_cnode,
+            src_slot,
+            rights.bits(),
+            badge.map(|b| b.get()).unwrap_or(0),
+        )
+    }
+
+    /// Move the capability in `src_slot` of `src_cnode` into `dst_slot`, leaving the source empty.
+    pub fn cap_move(
+        &self,
+        dst_slot: usize,
+        src_cnode: &CNodeCap,
+        src_slot: usize,
+    ) -> SysResult<()> {
+        self.cnode_op(SyscallOp::CapMove, dst_slot, src_cnode, src_slot, 0, 0)
+    }
+
+    /// Move a capability like `cap_move` and change it on the way. An unbadged endpoint gets
+    /// `data` as its badge. A CNode gets a new guard, see `guard_data`.
+    ///
+    /// A thread keeps its own copy of its root CNode cap from `TcbCap::configure`. Mutating
+    /// the root's guard does not change that copy, so configure the thread again with the
+    /// mutated cap.
+    pub fn cap_mutate(
+        &self,
+        dst_slot: usize,
+        src_cnode: &CNodeCap,
+        src_slot: usize,
+        data: usize,
+    ) -> SysResult<()> {
+        self.cnode_op(SyscallOp::CapMutate, dst_slot, src_cnode, src_slot, 0, data)
+    }
+
+    /// Move the capability in `pivot_slot` of this CNode to `dst_slot`, then the one in
+    /// `src_slot` of `src_cnode` to `pivot_slot`. When source and destination are the same
+    /// slot the two capabilities are swapped.
+    pub fn cap_rotate(
+        &self,
+        dst_slot: usize,
+        pivot_slot: usize,
+        src_cnode: &CNodeCap,
+        src_slot: usize,
+    ) -> SysResult<()> {
+        self.cnode_op(
+            SyscallOp::CapRotate,
+            dst_slot,
+            src_cnode,
+            src_slot,
+            CNODE_DEPTH,
+            pivot_slot,
+        )
+    }
+}
+
+/// Data word of a `cap_mutate` giving a CNode a guard of `guard_bits` bits.
+pub const fn guard_data(guard: usize, guard_bits: usize) -> usize {
+    guard << 6 | guard_bits
+}
+
+/// Size argument of a retype into CNodes of `bit_size` bits guarded by `guard`, `guard_bits`
+/// long. A plain bit size makes root CNodes, which cannot be nested under another CNode.
+pub const fn guarded_cnode_size(bit_size: usize, guard: usize, guard_bits: usize) -> usize {
+    bit_size | RETYPE_CNODE_GUARD | guard_data(guard, guard_bits) << RETYPE_CNODE_GUARD_SHIFT
 }
@@ -1,4 +1,8 @@
-use crate::ipc::{self, IpcMessage, IpcMessageType, IPC_MAX_ARGS};
+use alloc::vec::Vec;
+
+use crate::ipc::{
+    self, ipc_buffer, IpcMessage, IpcMessageType, IPC_MAX_ARGS, IPC_MAX_CAPS, IPC_MAX_MSG_LEN,
+};
 use crate::objects::ObjType;
 use rustyl4api::error::SysResult;
 use rustyl4api::fault::Fault;
@@ -17,54 +21,93 @@ impl KernelObject for EndpointObj {
 }
 
 impl Capability<EndpointObj> {
-    pub fn send(&self, message: &[usize], cap: Option<CapSlot>) -> SysResult<()> {
+    /// Capabilities the receiver had no slot for stay with the sender. Their slots are handed
+    /// back, in order, the ones that went over are freed.
+    pub fn send(&self, message: &[usize], caps: Vec<CapSlot>) -> SysResult<Vec<CapSlot>> {
+        self.do_send(SyscallOp::EndpointSend, message, caps)
+    }
+
+    /// Send only if a receiver is already waiting, failing with `Timeout` otherwise.
+    pub fn try_send(&self, message: &[usize], caps: Vec<CapSlot>) -> SysResult<Vec<CapSlot>> {
+        self.do_send(SyscallOp::EndpointNBSend, message, caps)
+    }
+
+    fn do_send(
+        &self,
+        op: SyscallOp,
+        message: &[usize],
+        mut caps: Vec<CapSlot>,
+    ) -> SysResult<Vec<CapSlot>> {
         let mut args = [self.slot(), 0, 0, 0, 0, 0];
-        let len = copy_massge_payload(&mut args, message, &cap);
-        let info = MsgInfo::new_ipc(SyscallOp::EndpointSend, len, cap.is_some());
+        let len = copy_massge_payload(&mut args, message);
+        let ncaps = copy_cap_slots(&mut args, &caps);
+        let info = MsgInfo::new_ipc(op, len, ncaps);
+
+        let (respinfo, _, _) = syscall(info, &mut args)?;
+        Ok(caps.split_off(respinfo.caps.min(caps.len())))
+    }
+
+    /// Capabilities that come with the message are put in `caps`, in order.
+    pub fn receive(&self, caps: Vec<CapSlot>) -> SysResult<IpcMessage> {
+        self.receive_timeout(caps, 0)
+    }
+
+    /// Like `receive`, but fails with `Timeout` after `ticks` generic timer ticks.
+    pub fn receive_timeout(&self, caps: Vec<CapSlot>, ticks: usize) -> SysResult<IpcMessage> {
+        let info = MsgInfo::new_ipc(SyscallOp::EndpointRecv, 0, 0).with_timeout(ticks);
+        self.do_receive(info, caps)
+    }
 
-        //TODO handle send fail and return cap
-        let ret = syscall(info, &mut args);
-        return ret.map(|_| ());
+    /// Receive only if a message is already waiting, failing with `Timeout` otherwise.
+    pub fn try_receive(&self, caps: Vec<CapSlot>) -> SysResult<IpcMessage> {
+        let info = MsgInfo::new_ipc(SyscallOp::EndpointNBRecv, 0, 0);
+        self.do_receive(info, caps)
     }
 
-    pub fn receive(&self, cap: Option<CapSlot>) -> SysResult<IpcMessage> {
-        let info = MsgInfo::new_ipc(SyscallOp::EndpointRecv, 0, cap.is_some());
-        let mut args = [
-            self.slot(),
-            0,
-            0,
-            0,
-            0,
-            cap.as_ref().map(|c| c.slot()).unwrap_or(0),
-        ];
+    fn do_receive(&self, mut info: MsgInfo, caps: Vec<CapSlot>) -> SysResult<IpcMessage> {
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        info.caps = copy_cap_slots(&mut args, &caps);
         let (retinfo, retbuf, badge) = syscall(info, &mut args)?;
 
-        handle_receive_return(retinfo, retbuf, badge, cap)
+        handle_receive_return(retinfo, retbuf, badge, caps)
     }
 
+    /// The slots of `caps` are empty once the reply is out, and take the capabilities that
+    /// come with the next message.
     pub fn reply_receive<'a, 'b>(
         &'a self,
         buf: &'b [usize],
-        cap: Option<CapSlot>,
+        caps: Vec<CapSlot>,
     ) -> SysResult<IpcMessage> {
         let mut args = [self.slot(), 0, 0, 0, 0, 0];
-        let has_cap = cap.is_some();
-        let len = copy_massge_payload(&mut args, buf, &cap);
-        let info = MsgInfo::new_ipc(SyscallOp::EndpointReplyRecv, len, has_cap);
+        let len = copy_massge_payload(&mut args, buf);
+        let ncaps = copy_cap_slots(&mut args, &caps);
+        let info = MsgInfo::new_ipc(SyscallOp::EndpointReplyRecv, len, ncaps);
 
         let (respinfo, retbuf, badge) = syscall(info, &mut args)?;
 
-        handle_receive_return(respinfo, retbuf, badge, cap)
+        handle_receive_return(respinfo, retbuf, badge, caps)
+    }
+
+    /// The slots of `caps` are reused for the capabilities that come with the reply.
+    pub fn call(&self, message: &[usize], caps: Vec<CapSlot>) -> SysResult<IpcMessage> {
+        self.call_timeout(message, caps, 0)
     }
 
-    pub fn call(&self, message: &[usize], cap: Option<CapSlot>) -> SysResult<IpcMessage> {
+    /// Like `call`, but fails with `Timeout` if no reply came within `ticks` generic timer ticks.
+    pub fn call_timeout(
+        &self,
+        message: &[usize],
+        caps: Vec<CapSlot>,
+        ticks: usize,
+    ) -> SysResult<IpcMessage> {
         let mut args = [self.slot(), 0, 0, 0, 0, 0];
-        let has_cap = cap.is_some();
-        let len = copy_massge_payload(&mut args, message, &cap);
-        let info = MsgInfo::new_ipc(SyscallOp::EndpointCall, len, has_cap);
+        let len = copy_massge_payload(&mut args, message);
+        let ncaps = copy_cap_slots(&mut args, &caps);
+        let info = MsgInfo::new_ipc(SyscallOp::EndpointCall, len, ncaps).with_timeout(ticks);
 
         let (respinfo, retbuf, badge) = syscall(info, &mut args)?;
-        handle_receive_return(respinfo, retbuf, badge, cap)
+        handle_receive_return(respinfo, retbuf, badge, caps)
     }
 }
 
@@ -72,20 +115,24 @@ fn handle_receive_return(
     respinfo: RespInfo,
     msgbuf: &[usize],
     badge: usize,
-    trans_capslot: Option<CapSlot>,
+    mut caps: Vec<CapSlot>,
 ) -> SysResult<IpcMessage> {
     Ok(match respinfo.msgtype {
         IpcMessageType::Message => {
-            let mut real_msgbuf = [0; 4];
             let badge = if respinfo.badged { Some(badge) } else { None };
-            let payload_len = msgbuf.len();
-            real_msgbuf[..payload_len].copy_from_slice(msgbuf);
+            let len = respinfo.get_length();
+            let mut payload = msgbuf[..len.min(IPC_MAX_ARGS)].to_vec();
+            if len > IPC_MAX_ARGS {
+                payload.extend_from_slice(&ipc_buffer().unwrap().msg[IPC_MAX_ARGS..len]);
+            }
+            /* Slots left over are given back */
+            caps.truncate(respinfo.caps);
             IpcMessage::Message(ipc::Message {
-                payload: real_msgbuf,
-                payload_len: payload_len,
+                payload: payload,
                 need_reply: respinfo.need_reply,
-                cap_transfer: respinfo.cap_transfer.then_some(trans_capslot.unwrap()),
+                caps: caps,
                 badge: badge,
+                reply: None,
             })
         }
         IpcMessageType::Fault => {
@@ -109,15 +156,35 @@ fn handle_receive_return(
     })
 }
 
-pub(crate) fn copy_massge_payload(
-    buf: &mut [usize; 6],
-    src: &[usize],
-    cap_slot: &Option<CapSlot>,
-) -> usize {
-    let len = src.len().min(IPC_MAX_ARGS);
-
-    buf[1..len + 1].copy_from_slice(&src[..len]);
-    buf[5] = cap_slot.as_ref().map(|c| c.slot()).unwrap_or(0);
+/// The first `IPC_MAX_ARGS` words go in registers and the rest in the IPC buffer. A thread
+/// without one can only send that many.
+pub(crate) fn copy_massge_payload(buf: &mut [usize; 6], src: &[usize]) -> usize {
+    let reg_len = src.len().min(IPC_MAX_ARGS);
+    buf[1..reg_len + 1].copy_from_slice(&src[..reg_len]);
+
+    match ipc_buffer() {
+        Some(ipc_buf) => {
+            let len = src.len().min(IPC_MAX_MSG_LEN);
+            ipc_buf.msg[reg_len..len].copy_from_slice(&src[reg_len..len]);
+            len
+        }
+        None => reg_len,
+    }
+}
 
-    len
+/// Capability slots are laid out like message words: the first in a register, the rest in the
+/// IPC buffer.
+pub(crate) fn copy_cap_slots(buf: &mut [usize; 6], caps: &[CapSlot]) -> usize {
+    buf[5] = caps.first().map(|c| c.slot()).unwrap_or(0);
+
+    match ipc_buffer() {
+        Some(ipc_buf) => {
+            let ncaps = caps.len().min(IPC_MAX_CAPS);
+            for i in 1..ncaps {
+                ipc_buf.caps[i] = caps[i].slot();
+            }
+            ncaps
+        }
+        None => caps.len().min(1),
+    }
 }
@@ -30,6 +30,7 @@ pub enum IdentifyResult {
     Reply,
     Monitor,
     Interrupt,
+    Notification,
 }
 
 pub fn cap_identify(cap_slot: usize) -> SysResult<IdentifyResult> {
@@ -68,5 +69,6 @@ pub fn cap_identify(cap_slot: usize) -> SysResult<IdentifyResult> {
         ObjType::Monitor => IdentifyResult::Monitor,
         ObjType::Interrupt => IdentifyResult::Interrupt,
         ObjType::Reply => IdentifyResult::Reply,
+        ObjType::Notification => IdentifyResult::Notification,
     })
 }
@@ -15,10 +15,10 @@ impl KernelObject for InterruptObj {
 }
 
 impl Capability<InterruptObj> {
-    pub fn attach_ep_to_irq(&self, ep_slot: usize, irq: usize) -> SysResult<()> {
-        let info = MsgInfo::new(SyscallOp::InterruptAttachIrq, 2);
+    pub fn attach_irq(&self, ntfn_slot: usize, irq: usize) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::InterruptAttachIrq, 3);
 
-        let mut args = [self.slot(), ep_slot, irq, 0, 0, 0];
+        let mut args = [self.slot(), ntfn_slot, irq, 0, 0, 0];
         syscall(info, &mut args).map(|_| ())
     }
 }
@@ -1,5 +1,6 @@
 use core::marker::PhantomData;
 
+use rustyl4api::error::SysResult;
 use rustyl4api::syscall::{syscall, MsgInfo, SyscallOp};
 
 mod cap_slot;
@@ -9,6 +10,7 @@ pub mod endpoint;
 pub mod identify;
 pub mod interrupt;
 pub mod monitor;
+pub mod notification;
 pub mod ram;
 pub mod reply;
 pub mod tcb;
@@ -21,6 +23,7 @@ pub use cnode::{CNodeCap, CNodeObj, CNODE_DEPTH};
 pub use endpoint::{EndpointObj, EpCap};
 pub use interrupt::{InterruptCap, InterruptObj};
 pub use monitor::{MonitorCap, MonitorObj};
+pub use notification::{NotificationCap, NotificationObj};
 pub use ram::{RamCap, RamObj};
 pub use reply::{ReplyCap, ReplyObj};
 pub use rustyl4api::objects::ObjType;
@@ -58,6 +61,13 @@ impl<T: KernelObject> Capability<T> {
         let mut args = [self.slot(), 0, 0, 0, 0, 0];
         syscall(info, &mut args).map(|_| ()).unwrap();
     }
+
+    /// Delete every capability derived from this one, keeping this one.
+    pub fn revoke(&self) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::CapRevoke, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
 }
 
 impl<T: KernelObject> core::ops::Drop for Capability<T> {
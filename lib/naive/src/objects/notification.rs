@@ -0,0 +1,40 @@
+use crate::objects::ObjType;
+use rustyl4api::error::SysResult;
+use rustyl4api::syscall::{syscall, MsgInfo, SyscallOp};
+
+use super::{Capability, KernelObject};
+
+#[derive(Debug, Clone)]
+pub struct NotificationObj {}
+pub type NotificationCap = Capability<NotificationObj>;
+
+impl KernelObject for NotificationObj {
+    fn obj_type() -> ObjType {
+        ObjType::Notification
+    }
+}
+
+impl Capability<NotificationObj> {
+    /// OR the badge of this capability into the notification word.
+    pub fn signal(&self) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::NotificationSignal, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
+
+    /// Block until the notification word is non-zero, then return and clear it.
+    pub fn wait(&self) -> SysResult<usize> {
+        let info = MsgInfo::new(SyscallOp::NotificationWait, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args)?;
+        Ok(args[0])
+    }
+
+    /// Return and clear the notification word without blocking. 0 means nothing was signalled.
+    pub fn poll(&self) -> SysResult<usize> {
+        let info = MsgInfo::new(SyscallOp::NotificationPoll, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args)?;
+        Ok(args[0])
+    }
+}
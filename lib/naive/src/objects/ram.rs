@@ -23,6 +23,13 @@ impl Capability<RamObj> {
         syscall(info, &mut args).map(|_| ())
     }
 
+    /// Change the permission of the frame's current mapping.
+    pub fn remap(&self, rights: Permission) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::RamRemap, 1);
+        let mut args = [self.slot(), rights.into(), 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
+
     pub fn unmap(&self) -> SysResult<()> {
         let info = MsgInfo::new(SyscallOp::RamUnmap, 0);
         let mut args = [self.slot(), 0, 0, 0, 0, 0];
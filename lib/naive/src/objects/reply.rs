@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 use crate::objects::{CapSlot, ObjType};
 use rustyl4api::error::SysResult;
 use rustyl4api::syscall::{syscall, MsgInfo, SyscallOp};
@@ -15,11 +17,26 @@ impl KernelObject for ReplyObj {
 }
 
 impl Capability<ReplyObj> {
-    pub fn reply(&self, message: &[usize], cap: Option<CapSlot>) -> SysResult<()> {
+    /// Move the reply capability of the last call received into `slot`, so that more calls can
+    /// be received before this one is answered.
+    pub fn save_caller(slot: CapSlot) -> SysResult<Self> {
+        let info = MsgInfo::new(SyscallOp::SaveCaller, 1);
+        let mut args = [slot.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args)?;
+        Ok(Self::new(slot))
+    }
+
+    /// Answer the call. A reply capability in slot 0 stands for the last call received.
+    pub fn reply(self, message: &[usize], caps: Vec<CapSlot>) -> SysResult<()> {
         let mut args = [self.slot(), 0, 0, 0, 0, 0];
-        let len = super::endpoint::copy_massge_payload(&mut args, message, &cap);
-        let info = MsgInfo::new_ipc(SyscallOp::EndpointReply, len, cap.is_some());
+        let len = super::endpoint::copy_massge_payload(&mut args, message);
+        let ncaps = super::endpoint::copy_cap_slots(&mut args, &caps);
+        let info = MsgInfo::new_ipc(SyscallOp::EndpointReply, len, ncaps);
         let ret = syscall(info, &mut args);
+        if ret.is_ok() {
+            /* The kernel consumed the reply capability, only the slot is left */
+            drop(self.into_slot());
+        }
         return ret.map(|_| ());
     }
 }
@@ -1,8 +1,10 @@
 use crate::objects::ObjType;
-use rustyl4api::error::SysResult;
+use rustyl4api::error::{SysError, SysResult};
+use rustyl4api::ipc::ipc_buffer;
 use rustyl4api::syscall::{syscall, MsgInfo, SyscallOp};
+use rustyl4api::thread::UserContext;
 
-use super::{CNodeCap, Capability, EpCap, KernelObject, VTableCap};
+use super::{CNodeCap, Capability, EpCap, KernelObject, NotificationCap, RamCap, VTableCap};
 
 pub use rustyl4api::objects::{TCB_OBJ_BIT_SZ, TCB_OBJ_SZ};
 
@@ -17,27 +19,90 @@ impl KernelObject for TcbObj {
 }
 
 impl Capability<TcbObj> {
+    /// `ipc_buffer` is a frame and the address the thread has it mapped at. It carries the
+    /// message words that do not fit in registers.
     pub fn configure(
         &self,
         vspace_cap: Option<&VTableCap>,
         cspace_cap: Option<&CNodeCap>,
         fault_handler_ep_cap: Option<&EpCap>,
+        ipc_buffer: Option<(&RamCap, usize)>,
     ) -> SysResult<()> {
-        let info = MsgInfo::new(SyscallOp::TcbConfigure, 3);
+        let info = MsgInfo::new(SyscallOp::TcbConfigure, 5);
         let mut args = [
             self.slot(),
             vspace_cap.map(|c| c.slot()).unwrap_or(0),
             cspace_cap.map(|c| c.slot()).unwrap_or(0),
             fault_handler_ep_cap.map(|c| c.slot()).unwrap_or(0),
-            0,
-            0,
+            ipc_buffer.map(|(c, _)| c.slot()).unwrap_or(0),
+            ipc_buffer.map(|(_, vaddr)| vaddr).unwrap_or(0),
         ];
         syscall(info, &mut args).map(|_| ())
     }
 
-    pub fn set_registers(&self, flags: usize, elr: usize, sp: usize) -> SysResult<()> {
-        let info = MsgInfo::new(SyscallOp::TcbSetRegisters, 3);
-        let mut args = [self.slot(), flags, elr, sp, 0, 0];
+    /// Registers of a thread that is not running, e.g. suspended or blocked on a fault. The copy
+    /// goes through the caller's IPC buffer.
+    pub fn read_registers(&self) -> SysResult<UserContext> {
+        let buf = ipc_buffer().ok_or(SysError::InvalidValue)?;
+        let info = MsgInfo::new(SyscallOp::TcbReadRegisters, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args)?;
+        Ok(*buf.user_context())
+    }
+
+    pub fn write_registers(&self, ctx: &UserContext) -> SysResult<()> {
+        let buf = ipc_buffer().ok_or(SysError::InvalidValue)?;
+        *buf.user_context() = *ctx;
+        let info = MsgInfo::new(SyscallOp::TcbWriteRegisters, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
+
+    /// Have the thread start at `entry` on stack `sp`, with `args` in x0-x7.
+    pub fn set_entry(&self, entry: usize, sp: usize, args: &[usize]) -> SysResult<()> {
+        if args.len() > 8 {
+            return Err(SysError::InvalidValue);
+        }
+
+        let mut ctx = self.read_registers()?;
+        ctx.elr = entry;
+        ctx.sp = sp;
+        ctx.x[..args.len()].copy_from_slice(args);
+        self.write_registers(&ctx)
+    }
+
+    /// Let signals on `ntfn` wake the thread up from endpoint receives. `None` unbinds.
+    pub fn bind_notification(&self, ntfn: Option<&NotificationCap>) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::TcbBindNotification, 2);
+        let mut args = [self.slot(), ntfn.map(|c| c.slot()).unwrap_or(0), 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
+
+    /// Priorities go from 0 to 255, higher runs first. The caller can hand out priorities up to
+    /// its own maximum controlled priority.
+    pub fn set_priority(&self, prio: usize) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::TcbSetPriority, 2);
+        let mut args = [self.slot(), prio, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
+
+    pub fn set_max_controlled_priority(&self, mcp: usize) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::TcbSetMaxControlledPriority, 2);
+        let mut args = [self.slot(), mcp, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
+
+    /// Set the time slice of the thread in microseconds.
+    pub fn set_timeslice(&self, us: usize) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::TcbSetTimeSlice, 2);
+        let mut args = [self.slot(), us, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
+
+    /// Pin the thread to core `cpu`. A running thread moves over at its next trip into the kernel.
+    pub fn set_affinity(&self, cpu: usize) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::TcbSetAffinity, 2);
+        let mut args = [self.slot(), cpu, 0, 0, 0, 0];
         syscall(info, &mut args).map(|_| ())
     }
 
@@ -46,4 +111,11 @@ impl Capability<TcbObj> {
         let mut args = [self.slot(), 0, 0, 0, 0, 0];
         syscall(info, &mut args).map(|_| ())
     }
+
+    /// Stop the thread until it is resumed. An IPC it is blocked in fails with `IpcAborted`.
+    pub fn suspend(&self) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::TcbSuspend, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
 }
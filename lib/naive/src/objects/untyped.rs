@@ -1,7 +1,8 @@
 use rustyl4api::error::SysResult;
 use rustyl4api::syscall::{syscall, MsgInfo, SyscallOp};
 
-use super::{CapSlot, Capability, KernelObject, ObjType};
+use super::{CNodeCap, CapSlot, Capability, KernelObject, ObjType};
+use crate::space_manager::ROOT_CNODE_CAP;
 
 #[derive(Debug)]
 pub struct UntypedObj {}
@@ -18,17 +19,18 @@ impl Capability<UntypedObj> {
         &self,
         objtype: ObjType,
         bit_size: usize,
+        dst_cnode: &CNodeCap,
         slot_start: usize,
         slot_len: usize,
     ) -> SysResult<()> {
-        let info = MsgInfo::new(SyscallOp::Retype, 4);
+        let info = MsgInfo::new(SyscallOp::Retype, 6);
         let mut args = [
             self.slot(),
             objtype as usize,
             bit_size,
+            dst_cnode.slot(),
             slot_start,
             slot_len,
-            0,
         ];
         syscall(info, &mut args).map(|_| ())
     }
@@ -38,7 +40,15 @@ impl Capability<UntypedObj> {
         bit_sz: usize,
         slot: CapSlot,
     ) -> SysResult<Capability<T>> {
-        self.retype(T::obj_type(), bit_sz, slot.slot(), 1)
+        self.retype(T::obj_type(), bit_sz, &ROOT_CNODE_CAP, slot.slot(), 1)
             .map(|_| Capability::new(slot))
     }
+
+    /// Make the whole untyped available again. Fails with `RevokeFirst` while any object
+    /// retyped out of it is still alive.
+    pub fn reset(&self) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::UntypedReset, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
 }
@@ -22,6 +22,16 @@ impl Capability<VTableObj> {
     }
 
     pub fn unmap(&self) -> SysResult<()> {
-        unimplemented!()
+        let info = MsgInfo::new(SyscallOp::VTableUnmap, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
+    }
+
+    /// Invalidate every mapping of the VSpace this table is the root of. The tables and frames
+    /// of the VSpace are left to be deleted or reused.
+    pub fn teardown(&self) -> SysResult<()> {
+        let info = MsgInfo::new(SyscallOp::VTableTeardown, 1);
+        let mut args = [self.slot(), 0, 0, 0, 0, 0];
+        syscall(info, &mut args).map(|_| ())
     }
 }
@@ -0,0 +1,192 @@
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use spin::Mutex;
+
+use rustyl4api::fault::{Fault, VmFaultKind};
+use rustyl4api::process::{
+    PROCESS_MAIN_THREAD_STACK_MAX_PAGES, PROCESS_MAIN_THREAD_STACK_PAGES,
+    PROCESS_MAIN_THREAD_STACK_TOP,
+};
+use rustyl4api::vspace::{Permission, FRAME_SIZE};
+
+use crate::ipc::IpcMessage;
+use crate::objects::{EndpointObj, EpCap, ReplyCap};
+use crate::space_manager::{copy_cap, gsm, ROOT_TCB_CAP};
+use crate::thread::{self, Thread};
+use crate::utils::{align_down, align_up};
+
+#[derive(Debug, Clone, Copy)]
+enum Backing {
+    /* Backed with a fresh frame on the first touch of each page */
+    Lazy(Permission),
+    /* Touching it is fatal */
+    Guard,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    start: usize,
+    end: usize,
+    backing: Backing,
+}
+
+/// Serves the VM faults of the threads of this process that have its endpoint as their fault
+/// handler. It runs on a thread of its own, whose stack is mapped up front.
+///
+/// The pager maps frames through the space manager, so a fault taken while the faulting
+/// thread holds one of its locks cannot be served.
+struct Pager {
+    ep: EpCap,
+    regions: Mutex<Vec<Region>>,
+    _thread: Thread,
+}
+
+lazy_static! {
+    static ref PAGER: Pager = {
+        let ep = gsm!().alloc_object::<EndpointObj>(12).unwrap();
+        Pager {
+            ep,
+            regions: Mutex::new(Vec::new()),
+            _thread: thread::spawn_fixed(pager_thread),
+        }
+    };
+}
+
+impl Pager {
+    fn insert(&self, start: usize, size: usize, backing: Backing) {
+        let end = start + align_up(size, FRAME_SIZE);
+        let mut regions = self.regions.lock();
+        assert!(
+            regions.iter().all(|r| r.end <= start || end <= r.start),
+            "pager regions overlap at {:x}",
+            start
+        );
+        regions.push(Region {
+            start,
+            end,
+            backing,
+        });
+    }
+
+    fn backing_of(&self, vaddr: usize) -> Option<Backing> {
+        self.regions
+            .lock()
+            .iter()
+            .find(|r| r.start <= vaddr && vaddr < r.end)
+            .map(|r| r.backing)
+    }
+
+    fn handle_fault(&self, fault: &Fault) -> Result<(), &'static str> {
+        let info = match fault {
+            Fault::DataFault(info) | Fault::PrefetchFault(info) => info,
+            _ => return Err("not a memory fault"),
+        };
+        if let VmFaultKind::Translation = info.kind {
+        } else {
+            return Err("not a missing page");
+        }
+
+        let vaddr = info.address as usize;
+        let perm = match self.backing_of(vaddr) {
+            Some(Backing::Lazy(perm)) => perm,
+            Some(Backing::Guard) => return Err("guard page"),
+            None => return Err("outside of the reserved regions"),
+        };
+
+        /* Threads faulting on the same page wait in turn, the first one gets it mapped */
+        let page = align_down(vaddr, FRAME_SIZE);
+        let mapped = gsm!()
+            .vspace_man
+            .lookup_entry(page, 0)
+            .map_or(false, |entry| entry.as_frame_node().is_some());
+        if !mapped {
+            gsm!()
+                .map_frame_at(0, page, FRAME_SIZE, perm)
+                .map_err(|_| "out of memory")?;
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Faults that cannot be served are logged and never answered, which leaves the faulting thread
+ * blocked for good.
+ */
+fn pager_thread() -> ! {
+    loop {
+        let msg = match PAGER.ep.receive(Vec::new()) {
+            Ok(IpcMessage::Fault(msg)) => msg,
+            Ok(msg) => {
+                log::warn!("pager received a message that is not a fault: {:?}", msg);
+                continue;
+            }
+            Err(e) => {
+                log::error!("pager receive error {:?}", e);
+                continue;
+            }
+        };
+        let reply = gsm!()
+            .cspace_alloc()
+            .and_then(|slot| ReplyCap::save_caller(slot).ok());
+
+        match (PAGER.handle_fault(&msg.info), reply) {
+            (Ok(()), Some(reply)) => reply.reply(&[], Vec::new()).unwrap_or(()),
+            (Ok(()), None) => log::error!("pager cannot answer fault {:x?}", msg.info),
+            (Err(reason), _) => log::error!("fatal fault {:x?}: {}", msg.info, reason),
+        }
+    }
+}
+
+/// A copy of the pager's endpoint, to be the fault handler of a thread.
+pub fn fault_ep() -> EpCap {
+    copy_cap(&PAGER.ep).unwrap()
+}
+
+/// Reserve `size` bytes anywhere, backed with `perm` page by page as they are first touched.
+pub fn reserve(size: usize, perm: Permission) -> *mut u8 {
+    let size = align_up(size, FRAME_SIZE);
+    let layout = Layout::from_size_align(size, FRAME_SIZE).unwrap();
+    let vaddr = gsm!().vspace_alloc(layout).unwrap();
+    reserve_at(vaddr, size, perm);
+    vaddr as *mut u8
+}
+
+/// Like `reserve`, at `vaddr`, which must be page aligned.
+pub fn reserve_at(vaddr: usize, size: usize, perm: Permission) {
+    PAGER.insert(vaddr, size, Backing::Lazy(perm));
+}
+
+/// Make touching `[vaddr, vaddr + size)` a fatal error rather than an unhandled fault.
+pub fn guard(vaddr: usize, size: usize) {
+    PAGER.insert(vaddr, size, Backing::Guard);
+}
+
+/// Forget the regions starting in `[vaddr, vaddr + size)`, unmap the pages backing them and
+/// give the address range back. Every frame mapped in the range goes back to its untyped, so
+/// besides the pager's ones it may only hold memory from `map_frame_at`, e.g. the part of a
+/// stack mapped up front.
+pub fn release(vaddr: usize, size: usize) {
+    let end = vaddr + size;
+    PAGER
+        .regions
+        .lock()
+        .retain(|r| r.start < vaddr || r.start >= end);
+
+    gsm!().memory_free(vaddr as *mut u8, size);
+}
+
+/// Let the stack of the main thread grow down to `PROCESS_MAIN_THREAD_STACK_MAX_PAGES`, with a
+/// guard page below. Nothing happens if the parent handles the faults of the main thread, e.g.
+/// a debugger.
+pub fn grow_main_stack() {
+    let ep = fault_ep();
+    if ROOT_TCB_CAP.configure(None, None, Some(&ep), None).is_err() {
+        return;
+    }
+
+    let limit = PROCESS_MAIN_THREAD_STACK_TOP - PROCESS_MAIN_THREAD_STACK_MAX_PAGES * FRAME_SIZE;
+    let mapped = PROCESS_MAIN_THREAD_STACK_TOP - PROCESS_MAIN_THREAD_STACK_PAGES * FRAME_SIZE;
+    guard(limit - FRAME_SIZE, FRAME_SIZE);
+    reserve_at(limit, mapped - limit, Permission::writable());
+}
@@ -1,8 +1,12 @@
+use alloc::vec::Vec;
+use core::arch::asm;
+
 use elfloader::{ElfBinary, ElfLoader, Flags, LoadableHeaders, Rela, VAddr, P64};
 
+use rustyl4api::error::SysError;
 use rustyl4api::process::{
-    ProcessCSpace, PROCESS_MAIN_THREAD_STACK_PAGES, PROCESS_MAIN_THREAD_STACK_TOP,
-    PROCESS_ROOT_CNODE_SIZE,
+    ProcessCSpace, PROCESS_MAIN_THREAD_IPC_BUFFER, PROCESS_MAIN_THREAD_STACK_PAGES,
+    PROCESS_MAIN_THREAD_STACK_TOP, PROCESS_ROOT_CNODE_SIZE, PROCESS_SHM_BASE,
 };
 use rustyl4api::vspace::Permission;
 use rustyl4api::vspace::{FRAME_BIT_SIZE, FRAME_SIZE};
@@ -10,10 +14,12 @@ use rustyl4api::vspace::{FRAME_BIT_SIZE, FRAME_SIZE};
 use crate::objects::cnode::CNODE_ENTRY_SZ;
 use crate::objects::tcb::TCB_OBJ_BIT_SZ;
 use crate::objects::{
-    CNodeObj, CNodeRef, EpCap, RamObj, TcbCap, TcbObj, UntypedCap, UntypedObj, VTableObj, VTableRef,
+    CNodeObj, CNodeRef, EpCap, RamCap, RamObj, TcbCap, TcbObj, UntypedCap, UntypedObj, VTableObj,
+    VTableRef,
 };
+use crate::shm::SharedRegion;
 use crate::space_manager::copy_cap;
-use crate::space_manager::gsm;
+use crate::space_manager::{gsm, ROOT_CNODE_CAP};
 use crate::spaceman::vspace_man::{VSpaceEntry, VSpaceMan, VSpaceManError};
 use crate::utils::align_down;
 
@@ -23,12 +29,45 @@ struct ProcessElfLoader<'a> {
     cur_free: &'a mut usize,
 }
 
-fn map_page(vspace: &VSpaceMan, root_cn: &CNodeRef, cur_free: &mut usize, page_base: usize, perm: Permission) {
-    let frame_cap = gsm!()
-        .alloc_object::<RamObj>(FRAME_BIT_SIZE)
-        .unwrap()
-        .into();
-    let mut frame_entry = VSpaceEntry::new_frame(frame_cap, page_base, perm, 0);
+fn map_pages(
+    vspace: &VSpaceMan,
+    root_cn: &CNodeRef,
+    cur_free: &mut usize,
+    base: usize,
+    count: usize,
+    perm: Permission,
+) -> Result<(), ()> {
+    let mut page = 0;
+    let mut batch = count;
+    while page < count {
+        batch = batch.min(count - page);
+        /* Halve the batch when no untyped or slot range is large enough for it */
+        let frames = match gsm!().alloc_objects::<RamObj>(FRAME_BIT_SIZE, batch) {
+            Some(frames) => frames,
+            None if batch > 1 => {
+                batch /= 2;
+                continue;
+            }
+            None => return Err(()),
+        };
+        for frame_cap in frames {
+            let vaddr = base + page * FRAME_SIZE;
+            map_page(vspace, root_cn, cur_free, frame_cap, vaddr, perm);
+            page += 1;
+        }
+    }
+    Ok(())
+}
+
+fn map_page(
+    vspace: &VSpaceMan,
+    root_cn: &CNodeRef,
+    cur_free: &mut usize,
+    frame_cap: RamCap,
+    page_base: usize,
+    perm: Permission,
+) {
+    let mut frame_entry = VSpaceEntry::new_frame(frame_cap.into(), page_base, perm, 0);
     while let Err((e, ent)) = vspace.install_entry(frame_entry, true) {
         frame_entry = ent;
         match e {
@@ -39,7 +78,7 @@ fn map_page(vspace: &VSpaceMan, root_cn: &CNodeRef, cur_free: &mut usize, page_b
                     VSpaceEntry::new_table(vtable_cap.clone(), page_base, level);
                 vspace.install_entry(vtable_entry, true).unwrap();
                 root_cn
-                    .cap_copy(*cur_free, vtable_cap.slot.slot())
+                    .cap_copy(*cur_free, &ROOT_CNODE_CAP, vtable_cap.slot.slot())
                     .unwrap();
                 *cur_free += 1;
             }
@@ -54,12 +93,17 @@ impl<'a> ElfLoader for ProcessElfLoader<'a> {
     fn allocate(&mut self, load_headers: LoadableHeaders) -> Result<(), &'static str> {
         for header in load_headers {
             let flags = header.flags();
-            let perm = Permission::new(flags.is_read(), flags.is_write(), flags.is_execute());
+            /* W^X: an executable segment is never mapped writable */
+            let perm = Permission::new(
+                flags.is_read(),
+                flags.is_write() && !flags.is_execute(),
+                flags.is_execute(),
+            );
             let base = align_down(header.virtual_addr() as usize, FRAME_SIZE);
             let top = (header.virtual_addr() + header.mem_size()) as usize;
-            for page_base in (base..top).step_by(FRAME_SIZE) {
-                map_page(self.vspace, self.child_root_cn, self.cur_free, page_base, perm)
-            }
+            let count = (top - base + FRAME_SIZE - 1) / FRAME_SIZE;
+            map_pages(self.vspace, self.child_root_cn, self.cur_free, base, count, perm)
+                .map_err(|_| "out of memory")?;
         }
         Ok(())
     }
@@ -76,7 +120,9 @@ impl<'a> ElfLoader for ProcessElfLoader<'a> {
         while region_offset < region.len() {
             let frame = self.vspace.lookup_entry(vaddr, 0).unwrap();
             let frame_parent_cap = copy_cap(&frame.as_frame_node().unwrap().cap).unwrap();
-            let frame_addr = gsm!().insert_ram_at(frame_parent_cap, 0, Permission::writable());
+            let frame_addr = gsm!()
+                .insert_ram_at(frame_parent_cap, 0, Permission::writable())
+                .map_err(|_| "cannot map a segment frame")?;
             let frame = unsafe { core::slice::from_raw_parts_mut(frame_addr, FRAME_SIZE) };
             let copy_len = (region.len() - region_offset).min(FRAME_SIZE) - frame_offset;
             frame[frame_offset..frame_offset + copy_len]
@@ -98,18 +144,124 @@ pub struct ProcessBuilder<'a> {
     stdout: Option<EpCap>,
     stderr: Option<EpCap>,
     name_server: Option<EpCap>,
+    fault_handler: Option<EpCap>,
+    priority: Option<usize>,
+    suspended: bool,
+    shared_regions: Vec<(SharedRegion, usize, Permission)>,
 }
 
 #[allow(dead_code)]
 pub struct Child {
     vspace: VSpaceMan,
     tcb: TcbCap,
-    stdin: EpCap,
-    stdout: EpCap,
-    stderr: EpCap,
     rootcn: CNodeRef,
+    /* The slot the child's InitUntyped was allocated in. The cap itself lives in the child's
+     * CSpace and comes back here when the child is killed */
     init_untyped: UntypedCap,
-    name_server: EpCap,
+    /* Keep the regions mapped in the child alive as long as it is */
+    shared_regions: Vec<SharedRegion>,
+}
+
+impl Child {
+    pub fn tcb(&self) -> &TcbCap {
+        &self.tcb
+    }
+
+    /// Stop the child for good and give everything it was given back to the untyped it came
+    /// from: its InitUntyped with every object the child made out of it, its page tables and
+    /// frames, its TCB and its CSpace.
+    pub fn kill(self) -> Result<(), ()> {
+        self.tcb.suspend().map_err(|_| ())?;
+
+        /* Free everything even if some of it fails, and report the failure at the end */
+        let mut ret = ROOT_CNODE_CAP.cap_move(
+            self.init_untyped.slot.slot(),
+            &self.rootcn,
+            ProcessCSpace::InitUntyped as usize,
+        );
+        ret = ret.and(gsm!().free_object(self.init_untyped));
+
+        /*
+         * The child's CSpace holds copies of its TCB and root CNode, the latter a reference to
+         * itself, so neither would ever be final. Freeing them revokes those copies first.
+         */
+        ret = ret.and(gsm!().free_object(self.tcb));
+        match self.rootcn.try_unwrap() {
+            Ok(rootcn) => ret = ret.and(gsm!().free_object(rootcn)),
+            Err(_) => ret = Err(SysError::InvalidValue),
+        }
+
+        let (frames, tables) = self.vspace.teardown().map_err(|_| ())?;
+        for frame in frames {
+            if let Ok(frame) = frame.try_unwrap() {
+                ret = ret.and(gsm!().free_object(frame));
+            }
+        }
+        for table in tables {
+            if let Ok(table) = table.try_unwrap() {
+                ret = ret.and(gsm!().free_object(table));
+            }
+        }
+        ret.map_err(|_| ())
+    }
+
+    /// Copy the child's memory at `vaddr` into `buf`. Fails if any page of it is not mapped.
+    pub fn read_memory(&self, vaddr: usize, buf: &mut [u8]) -> Result<(), ()> {
+        self.access_memory(vaddr, buf.len(), |frame, offset| {
+            buf[offset..offset + frame.len()].copy_from_slice(frame)
+        })
+    }
+
+    /// Copy `buf` into the child's memory at `vaddr`, whatever the permission of the pages is.
+    /// The instruction cache is kept in sync, so this can patch code too.
+    pub fn write_memory(&self, vaddr: usize, buf: &[u8]) -> Result<(), ()> {
+        self.access_memory(vaddr, buf.len(), |frame, offset| {
+            frame.copy_from_slice(&buf[offset..offset + frame.len()]);
+            sync_icache(frame);
+        })
+    }
+
+    /* Map the child's frames in turn and hand `f` the part of each that falls in the range */
+    fn access_memory<F>(&self, vaddr: usize, len: usize, mut f: F) -> Result<(), ()>
+    where
+        F: FnMut(&mut [u8], usize),
+    {
+        let mut offset = 0;
+        while offset < len {
+            let addr = vaddr + offset;
+            let entry = self.vspace.lookup_entry(addr, 0).map_err(|_| ())?;
+            let frame_node = entry.as_frame_node().ok_or(())?;
+            let frame_offset = addr - frame_node.vaddr();
+            let copy_len = (frame_node.size() - frame_offset).min(len - offset);
+
+            let frame_cap = copy_cap(&frame_node.cap).ok_or(())?;
+            let frame_addr = gsm!()
+                .insert_frame_at(frame_cap, frame_node.bit_size(), 0, Permission::writable())
+                .map_err(|_| ())?;
+            let frame = unsafe { core::slice::from_raw_parts_mut(frame_addr, frame_node.size()) };
+            f(&mut frame[frame_offset..frame_offset + copy_len], offset);
+            gsm!().memory_unmap(frame_addr, frame_node.size());
+
+            offset += copy_len;
+        }
+        Ok(())
+    }
+}
+
+/* Write back the data cache and drop stale instructions, both by VA */
+fn sync_icache(buf: &[u8]) {
+    const CACHE_LINE_SIZE: usize = 64;
+
+    let start = align_down(buf.as_ptr() as usize, CACHE_LINE_SIZE);
+    let end = buf.as_ptr() as usize + buf.len();
+    for line in (start..end).step_by(CACHE_LINE_SIZE) {
+        unsafe { asm!("dc cvau, {line}", line = in(reg) line, options(nostack)) }
+    }
+    unsafe { asm!("dsb ish", options(nostack)) }
+    for line in (start..end).step_by(CACHE_LINE_SIZE) {
+        unsafe { asm!("ic ivau, {line}", line = in(reg) line, options(nostack)) }
+    }
+    unsafe { asm!("dsb ish", "isb", options(nostack)) }
 }
 
 impl<'a> ProcessBuilder<'a> {
@@ -120,6 +272,10 @@ impl<'a> ProcessBuilder<'a> {
             stdout: None,
             stderr: None,
             name_server: None,
+            fault_handler: None,
+            priority: None,
+            suspended: false,
+            shared_regions: Vec::new(),
         }
     }
 
@@ -143,6 +299,32 @@ impl<'a> ProcessBuilder<'a> {
         self
     }
 
+    /// Faults of the child's main thread are sent to `ep`.
+    pub fn fault_handler(mut self, ep: EpCap) -> Self {
+        self.fault_handler = Some(ep);
+        self
+    }
+
+    pub fn priority(mut self, prio: usize) -> Self {
+        self.priority = Some(prio);
+        self
+    }
+
+    /// Leave the child's main thread suspended at its entry point, e.g. for a debugger to
+    /// resume.
+    pub fn suspended(mut self) -> Self {
+        self.suspended = true;
+        self
+    }
+
+    /// Map `region` in the child at `vaddr`. Regions with a `vaddr` of 0 are laid out from
+    /// `PROCESS_SHM_BASE` up in the order they are added, a guard page apart, so the child
+    /// finds them there.
+    pub fn shared_region(mut self, region: &SharedRegion, vaddr: usize, perm: Permission) -> Self {
+        self.shared_regions.push((region.clone(), vaddr, perm));
+        self
+    }
+
     pub fn spawn(self) -> Result<Child, ()> {
         let rootcn_bitsz = (PROCESS_ROOT_CNODE_SIZE * CNODE_ENTRY_SZ).trailing_zeros() as usize;
         let child_tcb = gsm!().alloc_object::<TcbObj>(TCB_OBJ_BIT_SZ).unwrap();
@@ -163,78 +345,126 @@ impl<'a> ProcessBuilder<'a> {
 
         let child_elf = ElfBinary::new("process", self.elf).unwrap();
 
-        child_elf.load(&mut process_elf_loader).unwrap();
-        for i in 1..PROCESS_MAIN_THREAD_STACK_PAGES + 1 {
-            let page_base = PROCESS_MAIN_THREAD_STACK_TOP - i * FRAME_SIZE;
-            let perm = Permission::writable();
-            map_page(&vspace, &child_root_cn, &mut cur_free, page_base, perm)
-        }
+        child_elf.load(&mut process_elf_loader).map_err(|_| ())?;
+        map_pages(
+            &vspace,
+            &child_root_cn,
+            &mut cur_free,
+            PROCESS_MAIN_THREAD_STACK_TOP - PROCESS_MAIN_THREAD_STACK_PAGES * FRAME_SIZE,
+            PROCESS_MAIN_THREAD_STACK_PAGES,
+            Permission::writable(),
+        )?;
         let entry = child_elf.entry_point() as usize;
 
+        let mut shm_vaddr = PROCESS_SHM_BASE;
+        for (region, vaddr, perm) in &self.shared_regions {
+            region.check_permission(*perm).map_err(|_| ())?;
+            let vaddr = if *vaddr == 0 {
+                let vaddr = shm_vaddr;
+                shm_vaddr += region.size() + FRAME_SIZE;
+                vaddr
+            } else {
+                *vaddr
+            };
+            for (i, frame) in region.frames().iter().enumerate() {
+                let frame = copy_cap(frame).ok_or(())?;
+                map_page(
+                    &vspace,
+                    &child_root_cn,
+                    &mut cur_free,
+                    frame,
+                    vaddr + i * FRAME_SIZE,
+                    *perm,
+                );
+            }
+        }
+
+        let ipc_buf = gsm!().alloc_object::<RamObj>(FRAME_BIT_SIZE).unwrap();
         child_tcb
-            .configure(Some(&child_root_vn), Some(&child_root_cn), None)
+            .configure(
+                Some(&child_root_vn),
+                Some(&child_root_cn),
+                self.fault_handler.as_ref(),
+                Some((&ipc_buf, PROCESS_MAIN_THREAD_IPC_BUFFER)),
+            )
             .expect("Error Configuring TCB");
+        map_page(
+            &vspace,
+            &child_root_cn,
+            &mut cur_free,
+            ipc_buf,
+            PROCESS_MAIN_THREAD_IPC_BUFFER,
+            Permission::writable(),
+        );
         child_tcb
-            .set_registers(0b1100, entry as usize, PROCESS_MAIN_THREAD_STACK_TOP)
+            .set_entry(entry as usize, PROCESS_MAIN_THREAD_STACK_TOP, &[])
             .expect("Error Setting Registers");
         child_root_cn
-            .cap_copy(ProcessCSpace::TcbCap as usize, child_tcb.slot.slot())
+            .cap_copy(
+                ProcessCSpace::TcbCap as usize,
+                &ROOT_CNODE_CAP,
+                child_tcb.slot.slot(),
+            )
             .map_err(|_| ())?;
         child_root_cn
             .cap_copy(
                 ProcessCSpace::RootCNodeCap as usize,
+                &ROOT_CNODE_CAP,
                 child_root_cn.slot.slot(),
             )
             .map_err(|_| ())?;
         child_root_cn
             .cap_copy(
                 ProcessCSpace::RootVNodeCap as usize,
+                &ROOT_CNODE_CAP,
                 child_root_vn.slot.slot(),
             )
             .map_err(|_| ())?;
-        child_root_cn
-            .cap_copy(
-                ProcessCSpace::Stdin as usize,
-                self.stdin.as_ref().unwrap().slot.slot(),
-            )
-            .map_err(|_| ())?;
-        child_root_cn
-            .cap_copy(
-                ProcessCSpace::Stdout as usize,
-                self.stdout.as_ref().unwrap().slot.slot(),
-            )
-            .map_err(|_| ())?;
-        child_root_cn
-            .cap_copy(
-                ProcessCSpace::Stderr as usize,
-                self.stderr.as_ref().unwrap().slot.slot(),
-            )
-            .map_err(|_| ())?;
-        child_root_cn
-            .cap_copy(
-                ProcessCSpace::NameServer as usize,
-                self.name_server.as_ref().unwrap().slot.slot(),
-            )
-            .map_err(|_| ())?;
+
+        /* The child owns its stdio and name server endpoints, so move them rather than copy */
+        let endpoints = alloc::vec![
+            (ProcessCSpace::Stdin, self.stdin),
+            (ProcessCSpace::Stdout, self.stdout),
+            (ProcessCSpace::Stderr, self.stderr),
+            (ProcessCSpace::NameServer, self.name_server),
+        ];
+        for (dst, ep) in endpoints {
+            let ep = ep.ok_or(())?;
+            child_root_cn
+                .cap_move(dst as usize, &ROOT_CNODE_CAP, ep.slot.slot())
+                .map_err(|_| ())?;
+            /* The slot is empty now, give it back without deleting anything */
+            drop(ep.into_slot());
+        }
+
+        /* Allocated here so that it is tracked, then handed to the child */
         let init_untyped = gsm!().alloc_object::<UntypedObj>(18).ok_or(())?;
         child_root_cn
-            .cap_copy(
+            .cap_move(
                 ProcessCSpace::InitUntyped as usize,
+                &ROOT_CNODE_CAP,
                 init_untyped.slot.slot(),
             )
             .map_err(|_| ())?;
 
-        child_tcb.resume().expect("Error Resuming TCB");
+        if let Some(prio) = self.priority {
+            child_tcb.set_priority(prio).map_err(|_| ())?;
+        }
+
+        if !self.suspended {
+            child_tcb.resume().expect("Error Resuming TCB");
+        }
 
         Ok(Child {
             vspace: vspace,
             tcb: child_tcb,
-            stdin: self.stdin.unwrap(),
-            stdout: self.stdout.unwrap(),
-            stderr: self.stderr.unwrap(),
             rootcn: child_root_cn,
-            init_untyped,
-            name_server: self.name_server.unwrap(),
+            init_untyped: init_untyped,
+            shared_regions: self
+                .shared_regions
+                .into_iter()
+                .map(|(region, _, _)| region)
+                .collect(),
         })
     }
 }
@@ -79,6 +79,8 @@ pub fn _start() -> ! {
 
     initialize_vmspace();
 
+    crate::pager::grow_main_stack();
+
     unsafe {
         main();
     }
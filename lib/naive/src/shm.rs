@@ -0,0 +1,198 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use rustyl4api::objects::CapRights;
+use rustyl4api::vspace::{Permission, FRAME_BIT_SIZE, FRAME_SIZE};
+
+use crate::objects::{CapSlot, RamCap, RamObj};
+use crate::space_manager::{copy_cap, gsm, mint_cap};
+use crate::{Error, Result};
+
+#[derive(Debug)]
+struct RegionInner {
+    frames: Vec<RamCap>,
+    writable: bool,
+    /* Allocated by this process, rather than received from another one */
+    owned: bool,
+}
+
+impl Drop for RegionInner {
+    fn drop(&mut self) {
+        if self.owned {
+            for frame in self.frames.drain(..) {
+                if let Err(e) = gsm!().free_object(frame) {
+                    log::warn!("cannot free a shared frame: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Memory shared between processes, made of 4K frames. The creator hands copies of the frame
+/// caps to other processes, e.g. in an `LmpMessage`, which rebuild the region with `from_caps`
+/// and map it with `map`. A parent can also map it in a child with
+/// `ProcessBuilder::shared_region`.
+///
+/// Clones refer to the same frames, which stay alive as long as a handle or a mapping of the
+/// region does. When the creator lets go of it, the frames go back to their untyped and the
+/// copies handed out stop working.
+#[derive(Debug, Clone)]
+pub struct SharedRegion {
+    inner: Arc<RegionInner>,
+}
+
+impl SharedRegion {
+    /// Allocate a zeroed region of `pages` 4K pages.
+    pub fn new(pages: usize) -> Result<Self> {
+        if pages == 0 {
+            return Err(Error::Invalid);
+        }
+        let frames = gsm!()
+            .alloc_objects::<RamObj>(FRAME_BIT_SIZE, pages)
+            .ok_or(Error::NoMemory)?;
+        Ok(Self::from_frames(frames, true, true))
+    }
+
+    /// Rebuild a region from caps handed out by `share`, in the order they came in.
+    /// `writable` tells whether they were shared writable.
+    pub fn from_caps(caps: Vec<CapSlot>, writable: bool) -> Result<Self> {
+        if caps.is_empty() {
+            return Err(Error::Invalid);
+        }
+        let frames = caps.into_iter().map(RamCap::new).collect();
+        Ok(Self::from_frames(frames, writable, false))
+    }
+
+    fn from_frames(frames: Vec<RamCap>, writable: bool, owned: bool) -> Self {
+        Self {
+            inner: Arc::new(RegionInner {
+                frames,
+                writable,
+                owned,
+            }),
+        }
+    }
+
+    pub fn pages(&self) -> usize {
+        self.inner.frames.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.pages() * FRAME_SIZE
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.inner.writable
+    }
+
+    pub(crate) fn frames(&self) -> &[RamCap] {
+        &self.inner.frames
+    }
+
+    /// Whether mappings of the region may use `perm`. Shared memory is never executable.
+    pub fn check_permission(&self, perm: Permission) -> Result<()> {
+        if perm.is_executable() || (perm.is_writable() && !self.is_writable()) {
+            return Err(Error::Invalid);
+        }
+        Ok(())
+    }
+
+    /// Copies of the frame caps for another process, read-only unless `writable` is set.
+    pub fn share(&self, writable: bool) -> Result<Vec<CapSlot>> {
+        let rights = if writable {
+            if !self.is_writable() {
+                return Err(Error::Invalid);
+            }
+            CapRights::READ | CapRights::WRITE
+        } else {
+            CapRights::READ
+        };
+        self.frames()
+            .iter()
+            .map(|frame| {
+                mint_cap(frame, rights, None)
+                    .map(|cap| cap.into_slot())
+                    .ok_or(Error::NoMemory)
+            })
+            .collect()
+    }
+
+    /// Map the region at `vaddr`, or anywhere if `vaddr` is 0. It is unmapped when the
+    /// mapping is dropped.
+    pub fn map(&self, vaddr: usize, perm: Permission) -> Result<SharedMapping> {
+        self.check_permission(perm)?;
+        if vaddr % FRAME_SIZE != 0 {
+            return Err(Error::Invalid);
+        }
+
+        /* Each mapping needs caps of its own, a cap only maps once */
+        let copies = self
+            .frames()
+            .iter()
+            .map(copy_cap)
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::NoMemory)?;
+
+        let base = if vaddr == 0 {
+            let layout = Layout::from_size_align(self.size(), FRAME_SIZE).unwrap();
+            gsm!().vspace_alloc(layout).ok_or(Error::NoMemory)?
+        } else {
+            gsm!()
+                .vspace_reserve(vaddr, self.size())
+                .ok_or(Error::Invalid)?
+        };
+        for (i, frame) in copies.into_iter().enumerate() {
+            if gsm!()
+                .insert_ram_at(frame, base + i * FRAME_SIZE, perm)
+                .is_err()
+            {
+                gsm!().memory_unmap(base as *mut u8, self.size());
+                return Err(Error::NoMemory);
+            }
+        }
+
+        Ok(SharedMapping {
+            region: self.clone(),
+            base: base as *mut u8,
+        })
+    }
+}
+
+/// A region mapped in the address space of this process.
+#[derive(Debug)]
+pub struct SharedMapping {
+    region: SharedRegion,
+    base: *mut u8,
+}
+
+impl SharedMapping {
+    pub fn region(&self) -> &SharedRegion {
+        &self.region
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.base
+    }
+
+    pub fn len(&self) -> usize {
+        self.region.size()
+    }
+
+    /// The mapped memory. Other processes may change it at any time, so the caller has to
+    /// agree with them on how it is accessed.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        core::slice::from_raw_parts(self.base, self.len())
+    }
+
+    /// Like `as_slice`. The mapping must be writable.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        core::slice::from_raw_parts_mut(self.base, self.len())
+    }
+}
+
+impl Drop for SharedMapping {
+    fn drop(&mut self) {
+        gsm!().memory_unmap(self.base, self.len());
+    }
+}
@@ -1,9 +1,11 @@
 use core::num::NonZeroUsize;
 
+use rustyl4api::objects::CapRights;
 use rustyl4api::process::{ProcessCSpace, PROCESS_ROOT_CNODE_SIZE};
 
 use crate::objects::KernelObject;
-use crate::objects::{CNodeRef, Capability, EpRef, VTableRef};
+use crate::objects::{CNodeRef, Capability, EpRef, TcbRef, VTableRef};
+use crate::spaceman::utspace_man::UntypedStats;
 use crate::spaceman::SpaceManager;
 
 lazy_static! {
@@ -28,6 +30,11 @@ pub macro gsm() {{
     &GLOBAL_SPACEMAN
 }}
 
+/// How much untyped memory the objects of this process take.
+pub fn untyped_stats() -> UntypedStats {
+    GLOBAL_SPACEMAN.untyped_stats()
+}
+
 pub fn copy_cap<T: KernelObject>(src: &Capability<T>) -> Option<Capability<T>> {
     copy_cap_badged(src, None)
 }
@@ -35,11 +42,25 @@ pub fn copy_cap<T: KernelObject>(src: &Capability<T>) -> Option<Capability<T>> {
 pub fn copy_cap_badged<T: KernelObject>(
     src: &Capability<T>,
     badge: Option<NonZeroUsize>,
+) -> Option<Capability<T>> {
+    mint_cap(src, CapRights::ALL, badge)
+}
+
+pub fn mint_cap<T: KernelObject>(
+    src: &Capability<T>,
+    rights: CapRights,
+    badge: Option<NonZeroUsize>,
 ) -> Option<Capability<T>> {
     let copy_slot = gsm!().cspace_alloc()?;
-    gsm!()
-        .root_cnode()
-        .cap_copy_badged(copy_slot.slot(), src.slot.slot(), badge)
+    let root_cnode = gsm!().root_cnode();
+    root_cnode
+        .cap_mint(
+            copy_slot.slot(),
+            &root_cnode,
+            src.slot.slot(),
+            rights,
+            badge,
+        )
         .ok()?;
     Some(Capability::<T>::new(copy_slot))
 }
@@ -54,4 +75,5 @@ lazy_static! {
         VTableRef::from_slot_num(ProcessCSpace::RootVNodeCap as usize);
     pub static ref ROOT_CNODE_CAP: CNodeRef =
         CNodeRef::from_slot_num(ProcessCSpace::RootCNodeCap as usize);
+    pub static ref ROOT_TCB_CAP: TcbRef = TcbRef::from_slot_num(ProcessCSpace::TcbCap as usize);
 }
@@ -1,6 +1,7 @@
 use core::ops::Range;
 
 use alloc::collections::LinkedList;
+use alloc::vec::Vec;
 
 use spin::Mutex;
 
@@ -54,6 +55,25 @@ impl CNodeBlock {
         Some(CapSlot::new(ret_slot))
     }
 
+    pub fn alloc_range(&self, count: usize) -> Option<Vec<CapSlot>> {
+        let mut free_slots_guard = self.free_slots.lock();
+        let mut cur = free_slots_guard.cursor_front_mut();
+
+        while let Some(range) = cur.current() {
+            if range.len() >= count {
+                let start = range.start;
+                range.start += count;
+                if range.is_empty() {
+                    cur.remove_current();
+                }
+                return Some((start..start + count).map(CapSlot::new).collect());
+            }
+            cur.move_next();
+        }
+
+        None
+    }
+
     pub fn alloc_at(&self, slot: usize) -> Option<CapSlot> {
         let mut free_slots_guard = self.free_slots.lock();
         let mut cur = free_slots_guard.cursor_front_mut();
@@ -160,6 +180,10 @@ impl CSpaceMan {
         self.root_cn_block.alloc()
     }
 
+    pub fn allocate_slots(&self, count: usize) -> Option<Vec<CapSlot>> {
+        self.root_cn_block.alloc_range(count)
+    }
+
     pub fn free_slot(&self, slot: usize) {
         self.root_cn_block.free(slot)
     }
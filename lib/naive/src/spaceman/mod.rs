@@ -1,7 +1,9 @@
 extern crate alloc;
 
 use crate::objects::KernelObject;
-use rustyl4api::vspace::Permission;
+use rustyl4api::vspace::{
+    Permission, FRAME_BIT_SIZE, FRAME_SIZE, HUGE_FRAME_BIT_SIZE, LARGE_FRAME_BIT_SIZE,
+};
 
 pub mod cspace_man;
 pub mod utspace_man;
@@ -12,12 +14,19 @@ use core::alloc::Layout;
 
 use crate::objects::identify::IdentifyResult;
 use crate::objects::{
-    CNodeRef, CapSlot, Capability, RamCap, RamObj, UntypedCap, VTableCap, VTableObj, VTableRef,
+    CNodeCap, CNodeRef, CapSlot, Capability, RamCap, RamObj, UntypedCap, VTableCap, VTableObj,
+    VTableRef,
 };
+use alloc::vec::Vec;
+use rustyl4api::error::SysResult;
 
-use vspace_man::{VSpaceEntry, VSpaceManError};
+pub use vmspace_man::{FitPolicy, VmArea};
+use vspace_man::{frame_level, VSpaceEntry, VSpaceManError};
 use log::info;
 
+/* Frame sizes `map_frame_at` backs memory with, largest first */
+const FRAME_BIT_SIZES: [usize; 3] = [HUGE_FRAME_BIT_SIZE, LARGE_FRAME_BIT_SIZE, FRAME_BIT_SIZE];
+
 #[derive(Debug)]
 pub struct SpaceManager {
     pub vspace_man: vspace_man::VSpaceMan,
@@ -70,13 +79,19 @@ impl SpaceManager {
                 mapped_asid: _,
                 is_device: _,
             } => {
-                if bit_sz == 12 {
+                if let Some(level) = frame_level(bit_sz as usize) {
                     let cap = RamCap::new(slot);
                     self.vspace_man
-                        .map_frame(cap.into(), mapped_vaddr, Permission::writable(), 1, false)
+                        .map_frame(
+                            cap.into(),
+                            mapped_vaddr,
+                            Permission::writable(),
+                            level + 1,
+                            false,
+                        )
                         .unwrap();
                 } else {
-                    info!("TODO: frame bitsizes other than 12 are not supported yet");
+                    info!("frame of {} bits cannot be mapped, ignoring it", bit_sz);
                     core::mem::forget(slot);
                 }
             }
@@ -114,9 +129,31 @@ impl SpaceManager {
     }
 
     pub fn vspace_alloc(&self, layout: Layout) -> Option<usize> {
-        Some(self.vmspace_man.allocate_mem(layout))
+        self.vmspace_man.allocate_mem(layout)
+    }
+
+    /// Take `[vaddr, vaddr + size)` out of the free address space, for a mapping at a fixed
+    /// address. `None` if some of it is in use already.
+    pub fn vspace_reserve(&self, vaddr: usize, size: usize) -> Option<usize> {
+        self.vmspace_man.reserve(vaddr, size)
     }
 
+    /// Give an address range back without touching what is mapped in it.
+    pub fn vspace_free(&self, vaddr: usize, size: usize) {
+        self.vmspace_man.free(vaddr, size)
+    }
+
+    /// The range in use that `vaddr` falls in, as allocated or reserved.
+    pub fn vspace_area(&self, vaddr: usize) -> Option<VmArea> {
+        self.vmspace_man.find(vaddr)
+    }
+
+    /// Back `size` bytes at `vaddr` with new frames, or anywhere if `vaddr` is 0. Aligned
+    /// parts of big ranges use 2M and 1G frames, falling back to smaller ones when no untyped
+    /// is large enough.
+    ///
+    /// Fails without leaving anything mapped when the address space, the frames or the page
+    /// tables run out. A `vaddr` given by the caller stays theirs to free.
     pub fn map_frame_at(
         &self,
         paddr: usize,
@@ -128,35 +165,86 @@ impl SpaceManager {
             return Err(());
         }
 
-        let mut rem_size = crate::utils::align_up(size, 4096);
-        let base_vaddr = if vaddr == 0 {
-            let layout = Layout::from_size_align(rem_size, 4096).unwrap();
-            self.vspace_alloc(layout).unwrap()
+        let mut rem_size = crate::utils::align_up(size, FRAME_SIZE);
+        let own_vaddr = vaddr == 0;
+        let base_vaddr = if own_vaddr {
+            let align = FRAME_BIT_SIZES
+                .iter()
+                .map(|bit_sz| 1 << bit_sz)
+                .find(|frame_size| *frame_size <= rem_size)
+                .unwrap_or(FRAME_SIZE);
+            let layout = Layout::from_size_align(rem_size, align).unwrap();
+            self.vspace_alloc(layout).ok_or(())?
         } else {
             vaddr
         };
         let mut vaddr = base_vaddr;
 
         while rem_size > 0 {
-            let frame = self.alloc_object::<RamObj>(12).ok_or(())?;
-            self.insert_ram_at(frame, vaddr, perm);
-            vaddr += 4096;
-            rem_size -= 4096;
+            let mapped = FRAME_BIT_SIZES
+                .iter()
+                .filter(|bit_sz| vaddr % (1 << **bit_sz) == 0 && rem_size >= 1 << **bit_sz)
+                .find_map(|bit_sz| Some((self.alloc_object::<RamObj>(*bit_sz)?, *bit_sz)))
+                .ok_or(())
+                .and_then(|(frame, bit_sz)| {
+                    self.insert_frame_at(frame, bit_sz, vaddr, perm)
+                        .map(|_| bit_sz)
+                        .map_err(|frame| {
+                            self.free_object(frame).ok();
+                        })
+                });
+            let bit_sz = match mapped {
+                Ok(bit_sz) => bit_sz,
+                Err(()) => {
+                    self.free_frames(base_vaddr as *mut u8, vaddr - base_vaddr);
+                    if own_vaddr {
+                        self.vspace_free(base_vaddr, vaddr - base_vaddr + rem_size);
+                    }
+                    return Err(());
+                }
+            };
+            vaddr += 1 << bit_sz;
+            rem_size -= 1 << bit_sz;
         }
 
         Ok(base_vaddr as *mut u8)
     }
 
-    /// Insert an RamCap to vspace to manage and handle backed page table
-    pub fn insert_ram_at(&self, ram: RamCap, vaddr: usize, perm: Permission) -> *mut u8 {
-        // TODO: support large page
-        let layout = Layout::from_size_align(4096, 4096).unwrap();
-        let vaddr = if vaddr == 0 {
-            self.vspace_alloc(layout).unwrap()
+    /// Insert an RamCap to vspace to manage and handle backed page table. The frame is handed
+    /// back if it cannot be mapped.
+    pub fn insert_ram_at(
+        &self,
+        ram: RamCap,
+        vaddr: usize,
+        perm: Permission,
+    ) -> Result<*mut u8, RamCap> {
+        self.insert_frame_at(ram, FRAME_BIT_SIZE, vaddr, perm)
+    }
+
+    /// Like `insert_ram_at`, for a frame of `bit_sz` bits. 2M and 1G frames are mapped as
+    /// block entries, so `vaddr` must be aligned to their size.
+    ///
+    /// Without address space left, or memory for the page tables, nothing stays mapped and
+    /// the frame is handed back.
+    pub fn insert_frame_at(
+        &self,
+        ram: RamCap,
+        bit_sz: usize,
+        vaddr: usize,
+        perm: Permission,
+    ) -> Result<*mut u8, RamCap> {
+        let level = frame_level(bit_sz).expect("unsupported frame size");
+        let size = 1 << bit_sz;
+        let (vaddr, own_vaddr) = if vaddr == 0 {
+            let layout = Layout::from_size_align(size, size).unwrap();
+            match self.vspace_alloc(layout) {
+                Some(vaddr) => (vaddr, true),
+                None => return Err(ram),
+            }
         } else {
-            vaddr
+            (vaddr, false)
         };
-        let mut frame_entry = VSpaceEntry::new_frame(ram.into(), vaddr, perm, 0);
+        let mut frame_entry = VSpaceEntry::new_frame(ram.into(), vaddr, perm, level);
         loop {
             let res = self.vspace_man.install_entry(frame_entry, true);
             if let Err((e, ent)) = res {
@@ -169,7 +257,18 @@ impl SpaceManager {
                     //     panic!("wrong slot type at level {} vaddr {:x}", level, vaddr);
                     // }
                     VSpaceManError::PageTableMiss { level } => {
-                        let vtable_cap = self.alloc_object::<VTableObj>(12).unwrap();
+                        let vtable_cap = match self.alloc_object::<VTableObj>(12) {
+                            Some(cap) => cap,
+                            None => {
+                                /* Drop the tables mapped for it so far */
+                                self.free_frames(vaddr as *mut u8, size);
+                                if own_vaddr {
+                                    self.vspace_free(vaddr, size);
+                                }
+                                let ram = frame_entry.into_ramcap().ok().unwrap();
+                                return Err(ram.try_unwrap().unwrap());
+                            }
+                        };
                         self.vspace_man
                             .map_table(vtable_cap.into(), vaddr, level, true)
                             .unwrap();
@@ -182,11 +281,72 @@ impl SpaceManager {
                 break;
             }
         }
-        vaddr as *mut u8
+        Ok(vaddr as *mut u8)
+    }
+
+    /// Map `layout.size()` bytes of new memory anywhere, aligned to `layout.align()` at least.
+    /// Undone with `memory_free`.
+    pub fn memory_map(&self, layout: Layout, perm: Permission) -> Option<*mut u8> {
+        let size = crate::utils::align_up(layout.size(), FRAME_SIZE);
+        let frame_align = FRAME_BIT_SIZES
+            .iter()
+            .map(|bit_sz| 1 << bit_sz)
+            .find(|frame_size| *frame_size <= size)
+            .unwrap_or(FRAME_SIZE);
+        let layout = Layout::from_size_align(size, layout.align().max(frame_align)).ok()?;
+        let vaddr = self.vspace_alloc(layout)?;
+        match self.map_frame_at(0, vaddr, size, perm) {
+            Ok(ptr) => Some(ptr),
+            Err(()) => {
+                self.vspace_free(vaddr, size);
+                None
+            }
+        }
     }
 
+    /// Unmap `[base_ptr, base_ptr + len)` and give the address range back.
     pub fn memory_unmap(&self, base_ptr: *mut u8, len: usize) {
-        self.vspace_man.memory_unmap(base_ptr, len)
+        self.vspace_man
+            .memory_unmap(base_ptr, len, drop, |table| self.free_table(table));
+        self.vspace_free(base_ptr as usize, len);
+    }
+
+    /// Like `memory_unmap`, and the frames go back to their untyped. Only for memory from
+    /// `memory_map` or `map_frame_at`, not for copies of caps owned elsewhere.
+    pub fn memory_free(&self, base_ptr: *mut u8, len: usize) {
+        self.free_frames(base_ptr, len);
+        self.vspace_free(base_ptr as usize, len);
+    }
+
+    /* Unmap and free the frames of `memory_free`, keeping the address range */
+    fn free_frames(&self, base_ptr: *mut u8, len: usize) {
+        self.vspace_man.memory_unmap(
+            base_ptr,
+            len,
+            |frame| {
+                if let Ok(frame) = frame.try_unwrap() {
+                    if let Err(e) = self.free_object(frame) {
+                        log::warn!("cannot free a frame at {:p}: {:?}", base_ptr, e);
+                    }
+                }
+            },
+            |table| self.free_table(table),
+        );
+    }
+
+    /* Page tables emptied by an unmap, mostly allocated by `map_frame_at` */
+    fn free_table(&self, table: VTableRef) {
+        if let Ok(table) = table.try_unwrap() {
+            if let Err(e) = self.free_object(table) {
+                log::warn!("cannot free a page table: {:?}", e);
+            }
+        }
+    }
+
+    /// Change the permission of the mapped memory at `base_ptr`. The kernel refuses mappings
+    /// that are writable and executable at once.
+    pub fn mprotect(&self, base_ptr: *mut u8, len: usize, perm: Permission) -> SysResult<()> {
+        self.vspace_man.protect(base_ptr, len, perm)
     }
 
     pub fn insert_vtable(&self, table: VTableCap, vaddr: usize, level: usize, do_map: bool) {
@@ -208,7 +368,47 @@ impl SpaceManager {
 
     pub fn alloc_object<T: KernelObject>(&self, size: usize) -> Option<Capability<T>> {
         let slot = self.cspace_alloc()?;
-        self.utspace_man.alloc_object::<T>(slot, size)
+        self.utspace_man
+            .alloc_object::<T>(&self.root_cnode(), &self.cspace_man, slot, size)
+    }
+
+    /// Allocate `count` objects of the same type with a single retype
+    pub fn alloc_objects<T: KernelObject>(
+        &self,
+        size: usize,
+        count: usize,
+    ) -> Option<Vec<Capability<T>>> {
+        let slots = self.cspace_man.allocate_slots(count)?;
+        self.utspace_man.alloc_objects::<T>(
+            &self.root_cnode(),
+            &self.cspace_man,
+            slots.first()?.slot(),
+            count,
+            size,
+        )?;
+        Some(slots.into_iter().map(Capability::new).collect())
+    }
+
+    /// Delete an object allocated by `alloc_object` or `alloc_objects` and give its memory back.
+    pub fn free_object<T: KernelObject>(&self, cap: Capability<T>) -> SysResult<()> {
+        self.utspace_man.free_object(cap)
+    }
+
+    /// How much untyped memory the objects of this process take.
+    pub fn untyped_stats(&self) -> utspace_man::UntypedStats {
+        self.utspace_man.stats()
+    }
+
+    /// Allocate an object directly into `slot` of another CNode, e.g. the CSpace of a child
+    /// process, without keeping a copy of it around.
+    pub fn alloc_object_into<T: KernelObject>(
+        &self,
+        cnode: &CNodeCap,
+        slot: usize,
+        size: usize,
+    ) -> Option<()> {
+        self.utspace_man
+            .alloc_objects_into::<T>(cnode, slot, 1, size)
     }
 
     //    pub fn alloc_object_at<T: KernelObject>(&self, paddr: usize, bit_sz: usize, maybe_device: bool) -> Option<Capability<RamObj>> {
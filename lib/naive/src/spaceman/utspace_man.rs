@@ -1,12 +1,24 @@
-use crate::objects::{CapSlot, Capability, KernelObject, UntypedCap, UntypedObj};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::objects::{CNodeCap, CapSlot, Capability, KernelObject, UntypedCap, UntypedObj};
+use alloc::collections::{BTreeMap, LinkedList};
 use alloc::vec::Vec;
+use rustyl4api::error::SysResult;
+use rustyl4api::objects::{ObjType, RETYPE_CNODE_GUARD};
+use rustyl4api::vspace::FRAME_BIT_SIZE;
 use spin::Mutex;
 
+use super::cspace_man::CSpaceMan;
+
+/* Object sizes are below 64 bits, one free list per size */
+const UNTYPED_SIZE_CLASSES: usize = 64;
+
 #[derive(Debug)]
 struct UntypedNode {
     paddr: usize,
     cap: Capability<UntypedObj>,
-    free_offset: usize,
+    /// Number of objects retyped out of this untyped that have not been freed yet
+    live_objects: usize,
 }
 
 impl UntypedNode {
@@ -14,26 +26,56 @@ impl UntypedNode {
         Self {
             paddr: paddr,
             cap: cap,
-            free_offset: 0,
+            live_objects: 0,
         }
     }
 }
 
+/* Where an object handed out by `alloc_object` came from */
+#[derive(Debug)]
+enum Origin {
+    /* Packed with other objects into the untyped at this index of `ut_list` */
+    Shared(usize),
+    /* Alone in an untyped of this bit size carved out for it */
+    Own(UntypedCap, usize),
+}
+
+/// How much untyped memory the manager has handed out, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UntypedStats {
+    /// Carved out for frames, page tables and CNodes, which get an untyped each. Freed ones are
+    /// reset and kept for the next object of the same size, so this only grows when no freed
+    /// untyped fits.
+    pub reserved: usize,
+    /// The part of `reserved` held by objects that have not been freed.
+    pub in_use: usize,
+    /// Number of smaller objects packed together into shared untypeds that have not been freed.
+    pub packed_objects: usize,
+}
+
 #[derive(Debug)]
 pub struct UntypedSpaceMan {
     ut_list: Mutex<Vec<UntypedNode>>,
-    // empty_ut: Vec<Vec<UntypedNode>>,
-    // partial_ut: Vec<Vec<UntypedNode>>,
-    // full_ut: Vec<Vec<UntypedNode>>,
+    /// Root CNode slot of every object handed out by `alloc_object`, mapped to where its
+    /// memory came from.
+    allocations: Mutex<BTreeMap<usize, Origin>>,
+    /// Reset untypeds ready for reuse, indexed by bit size.
+    free_ut: Mutex<[LinkedList<UntypedCap>; UNTYPED_SIZE_CLASSES]>,
+    reserved: AtomicUsize,
+    in_use: AtomicUsize,
+    packed_objects: AtomicUsize,
 }
 
 impl UntypedSpaceMan {
     pub fn new() -> Self {
+        const EMPTY: LinkedList<UntypedCap> = LinkedList::new();
         Self {
             ut_list: Mutex::new(Vec::new()),
-            // empty_ut: Vec::new(),
-            // partial_ut: Vec::new(),
-            // full_ut: Vec::new(),
+            allocations: Mutex::new(BTreeMap::new()),
+            free_ut: Mutex::new([EMPTY; UNTYPED_SIZE_CLASSES]),
+            reserved: AtomicUsize::new(0),
+            in_use: AtomicUsize::new(0),
+            packed_objects: AtomicUsize::new(0),
         }
     }
 
@@ -62,32 +104,201 @@ impl UntypedSpaceMan {
             return;
         }
 
-        // let sz_offset = bit_sz - 4;
-
-        // if self.empty_ut.len() <= sz_offset as usize {
-        //     self.empty_ut.resize_with(sz_offset as usize + 1, || Vec::new());
-        // }
-
         self.ut_list.lock().push(UntypedNode::new_empty(cap, paddr));
-        // self.empty_ut[sz_offset as usize].push(UntypedNode::new_empty(cap, paddr));
     }
 
+    /// Allocate one object into `dest_slot` of `cnode`. The object is tracked so that it can be
+    /// given back with `free_object`.
     pub fn alloc_object<T: KernelObject>(
         &self,
+        cnode: &CNodeCap,
+        cspace: &CSpaceMan,
         dest_slot: CapSlot,
         size: usize,
     ) -> Option<Capability<T>> {
-        for node in self.ut_list.lock().iter() {
-            if let Ok(_) = node.cap.retype(T::obj_type(), size, dest_slot.slot(), 1) {
-                return Some(Capability::<T>::new(dest_slot));
+        self.alloc_objects::<T>(cnode, cspace, dest_slot.slot(), 1, size)?;
+        Some(Capability::<T>::new(dest_slot))
+    }
+
+    /// Allocate `count` tracked objects into the slots of `cnode` starting at `slot_start`.
+    ///
+    /// Frames, page tables and CNodes each get an untyped of their own so that their memory
+    /// can be reused as soon as they are freed. `cspace` provides the slots for those untypeds.
+    pub fn alloc_objects<T: KernelObject>(
+        &self,
+        cnode: &CNodeCap,
+        cspace: &CSpaceMan,
+        slot_start: usize,
+        count: usize,
+        size: usize,
+    ) -> Option<()> {
+        let bit_sz = match own_bit_size(T::obj_type(), size) {
+            Some(bit_sz) => bit_sz,
+            None => {
+                let index = self.retype_into::<T>(cnode, slot_start, count, size)?;
+                self.packed_objects.fetch_add(count, Ordering::Relaxed);
+                let mut allocations = self.allocations.lock();
+                for slot in slot_start..slot_start + count {
+                    allocations.insert(slot, Origin::Shared(index));
+                }
+                return Some(());
+            }
+        };
+
+        /* Get every untyped first so that a failure leaves nothing half allocated. They are all
+         * still empty, so putting them back cannot fail */
+        let mut uts = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self.take_untyped(cspace, bit_sz) {
+                Some(ut) => uts.push(ut),
+                None => {
+                    for ut in uts {
+                        self.put_untyped(ut, bit_sz).ok();
+                    }
+                    return None;
+                }
             }
         }
 
-        None
-        // use rustyl4api::init::InitCSpaceSlot::UntypedStart;
+        let mut uts = uts.into_iter();
+        for slot in slot_start..slot_start + count {
+            let ut = uts.next().unwrap();
+            /* An empty untyped of exactly the object size only refuses occupied slots and a bad
+             * CNode guard. The guard is the same for all, so the first retype catches it */
+            if ut.retype(T::obj_type(), size, cnode, slot, 1).is_err() {
+                assert_eq!(slot, slot_start, "destination slot is occupied");
+                self.put_untyped(ut, bit_sz).ok();
+                for ut in uts {
+                    self.put_untyped(ut, bit_sz).ok();
+                }
+                return None;
+            }
+            self.in_use.fetch_add(1 << bit_sz, Ordering::Relaxed);
+            self.allocations
+                .lock()
+                .insert(slot, Origin::Own(ut, bit_sz));
+        }
+        Some(())
+    }
+
+    /// Usage of the untyped memory handed out so far.
+    pub fn stats(&self) -> UntypedStats {
+        UntypedStats {
+            reserved: self.reserved.load(Ordering::Relaxed),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            packed_objects: self.packed_objects.load(Ordering::Relaxed),
+        }
+    }
 
-        // let untyped_cap = Capability::<UntypedObj>::new(UntypedStart as usize);
-        // untyped_cap.retype(T::obj_type(), size, dest_slot, 1).ok()?;
-        // Some(Capability::<T>::new(dest_slot))
+    /* A freed untyped of `bit_sz` bits, or a new one carved out of the first untyped with room */
+    fn take_untyped(&self, cspace: &CSpaceMan, bit_sz: usize) -> Option<UntypedCap> {
+        if let Some(ut) = self.free_ut.lock()[bit_sz].pop_front() {
+            return Some(ut);
+        }
+
+        /* Carved untypeds are never given back, so their parent is never reset */
+        let slot = cspace.allocate_slot()?;
+        self.retype_into::<UntypedObj>(&cspace.root_cnode(), slot.slot(), 1, bit_sz)?;
+        self.reserved.fetch_add(1 << bit_sz, Ordering::Relaxed);
+        Some(UntypedCap::new(slot))
+    }
+
+    /* Only an untyped that could be reset is handed out again, one that still has children is
+     * dropped and its memory is lost */
+    fn put_untyped(&self, ut: UntypedCap, bit_sz: usize) -> SysResult<()> {
+        ut.reset()?;
+        /* Allocate the list node before taking the lock, it may need more heap. The untyped
+         * freed last is handed out first */
+        let mut node = LinkedList::new();
+        node.push_back(ut);
+        let mut free_ut = self.free_ut.lock();
+        node.append(&mut free_ut[bit_sz]);
+        free_ut[bit_sz] = node;
+        Ok(())
+    }
+
+    /// Retype `count` objects into the slots of `cnode` starting at `slot_start`,
+    /// all out of the same untyped.
+    ///
+    /// These objects are not tracked, e.g. because they live in another CSpace. Their untyped
+    /// is never reset.
+    pub fn alloc_objects_into<T: KernelObject>(
+        &self,
+        cnode: &CNodeCap,
+        slot_start: usize,
+        count: usize,
+        size: usize,
+    ) -> Option<()> {
+        self.retype_into::<T>(cnode, slot_start, count, size)
+            .map(|_| ())
+    }
+
+    fn retype_into<T: KernelObject>(
+        &self,
+        cnode: &CNodeCap,
+        slot_start: usize,
+        count: usize,
+        size: usize,
+    ) -> Option<usize> {
+        let mut ut_list = self.ut_list.lock();
+        let (index, node) = ut_list.iter_mut().enumerate().find(|(_, node)| {
+            node.cap
+                .retype(T::obj_type(), size, cnode, slot_start, count)
+                .is_ok()
+        })?;
+        node.live_objects += count;
+        Some(index)
+    }
+
+    /// Give an object allocated by `alloc_object` back. Every capability derived from it is
+    /// revoked. An untyped of its own is reset and kept for the next object of its size, a
+    /// shared one is reset once all of its objects are gone.
+    ///
+    /// If the revoke or the reset fails, e.g. because something was retyped out of the object,
+    /// the cap is still deleted but its untyped is never handed out again. The error is
+    /// returned. Objects that were not allocated by this manager are simply deleted.
+    pub fn free_object<T: KernelObject>(&self, cap: Capability<T>) -> SysResult<()> {
+        let origin = self.allocations.lock().remove(&cap.slot.slot());
+
+        let revoked = cap.revoke();
+        drop(cap);
+
+        match origin {
+            Some(Origin::Shared(index)) => {
+                self.packed_objects.fetch_sub(1, Ordering::Relaxed);
+                revoked?;
+                let mut ut_list = self.ut_list.lock();
+                let node = &mut ut_list[index];
+                node.live_objects -= 1;
+                if node.live_objects == 0 {
+                    node.cap.reset()?;
+                }
+            }
+            Some(Origin::Own(ut, bit_sz)) => {
+                /* Memory that cannot be reused stays counted as in use */
+                revoked?;
+                self.put_untyped(ut, bit_sz)?;
+                self.in_use.fetch_sub(1 << bit_sz, Ordering::Relaxed);
+            }
+            None => revoked?,
+        }
+        Ok(())
+    }
+}
+
+/* The bit size of the untyped an object of `obj_type` gets to itself, or `None` if it is packed
+ * with others. Small fixed size objects are packed. */
+fn own_bit_size(obj_type: ObjType, size: usize) -> Option<usize> {
+    let bit_sz = match obj_type {
+        ObjType::Ram => size,
+        /* Without the guard a nested CNode may carry */
+        ObjType::CNode => size & (RETYPE_CNODE_GUARD - 1),
+        ObjType::VTable => FRAME_BIT_SIZE,
+        _ => return None,
+    };
+    if bit_sz < UNTYPED_SIZE_CLASSES {
+        Some(bit_sz)
+    } else {
+        None
     }
 }
@@ -1,71 +1,338 @@
 use alloc::alloc::Layout;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::collections::LinkedList;
 
-#[derive(Copy, Clone, Debug, Default)]
+use spin::Mutex;
+
+use rustyl4api::vspace::FRAME_SIZE;
+
+use crate::utils::align_up;
+
+/// Automatic allocations come from `[VM_START, VM_END)`, above the program and its stack.
+pub const VM_START: usize = 0x8000000000;
+pub const VM_END: usize = 0x1000000000000;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub struct VmArea {
     start: usize,
     end: usize,
 }
 
-#[derive(Debug, Default)]
+impl VmArea {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, vaddr: usize) -> bool {
+        self.start <= vaddr && vaddr < self.end
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// Which free range an allocation is carved from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// The lowest one it fits in
+    FirstFit,
+    /// The smallest one it fits in, leaving big ranges for big allocations
+    BestFit,
+}
+
+/// Keeps track of the virtual address ranges in use, page by page. Ranges that are not in use
+/// are free, so freed ranges are handed out again.
+///
+/// The list nodes are allocated before taking the lock, as the heap allocator comes back here
+/// for address space when it runs dry.
+#[derive(Debug)]
 pub struct VMSpaceMan {
-    // vma_list: LinkedList<VmArea>,
-    // code_start: usize,
-    // code_end: usize,
-    // start_data: usize,
-    // end_data: usize,
-    // start_brk: usize,
-    end_brk: AtomicUsize,
-    // start_stack: usize,
-    // start_arg: usize,
-    // end_arg: usize,
-    // start_env: usize,
-    // end_env: usize,
+    /* In use, sorted by address and never overlapping */
+    vma_list: Mutex<LinkedList<VmArea>>,
+    policy: FitPolicy,
 }
 
 impl VMSpaceMan {
     pub fn new() -> Self {
+        Self::with_policy(FitPolicy::FirstFit)
+    }
+
+    pub fn with_policy(policy: FitPolicy) -> Self {
         Self {
-            // vma_list : LinkedList::default(),
-            end_brk: AtomicUsize::new(0x8000000000),
+            vma_list: Mutex::new(LinkedList::new()),
+            policy,
         }
     }
 
-    // pub fn insert_vma(&mut self, start: usize, end: usize) {
-    //     let vma = VmArea {start, end};
-    //     let mut cur = self.vma_list.cursor_front_mut();
+    pub fn allocate_mem(&self, layout: Layout) -> Option<usize> {
+        self.allocate_mem_with(layout, self.policy)
+    }
 
-    //     loop {
-    //         if cur.current().is_none() {
-    //             break;
-    //         }
-    //         if cur.current().unwrap().start > start {
-    //             break;
-    //         }
-    //         cur.move_next();
-    //     }
+    /// Find room for `layout` in `[VM_START, VM_END)` and mark it in use. The size is rounded
+    /// up to whole pages and the alignment is at least a page.
+    pub fn allocate_mem_with(&self, layout: Layout, policy: FitPolicy) -> Option<usize> {
+        let size = align_up(layout.size().max(1), FRAME_SIZE);
+        let align = layout.align().max(FRAME_SIZE);
+        let mut node = single_node();
 
-    //     cur.insert_before(vma)
-    // }
+        let mut vma_list = self.vma_list.lock();
+        /* Index of the area the allocation goes in front of, its start and the gap size */
+        let mut best: Option<(usize, usize, usize)> = None;
+        let mut prev_end = VM_START;
+        let mut iter = vma_list.iter().enumerate();
+        loop {
+            let next = iter.next();
+            let (idx, gap_end) = match next {
+                Some((idx, vma)) => (idx, vma.start.min(VM_END)),
+                None => (vma_list.len(), VM_END),
+            };
+            let start = align_up(prev_end, align);
+            if start < gap_end && gap_end - start >= size {
+                let gap_size = gap_end - prev_end;
+                if best.map_or(true, |(_, _, best_size)| gap_size < best_size) {
+                    best = Some((idx, start, gap_size));
+                }
+                if policy == FitPolicy::FirstFit {
+                    break;
+                }
+            }
+            match next {
+                Some((_, vma)) => prev_end = prev_end.max(vma.end),
+                None => break,
+            }
+        }
 
-    // pub fn find_vma(&self, addr: usize) -> Option<&VmArea> {
-    //     self.vma_list.iter().find(|vma| vma.end > addr)
-    // }
+        let (idx, start, _) = best?;
+        *node.front_mut().unwrap() = VmArea {
+            start,
+            end: start + size,
+        };
+        let mut cur = vma_list.cursor_front_mut();
+        for _ in 0..idx {
+            cur.move_next();
+        }
+        cur.splice_before(node);
+        Some(start)
+    }
 
-    pub fn allocate_mem(&self, layout: Layout) -> usize {
-        use crate::utils::align_up;
+    /// Mark `[start, start + size)` in use, e.g. for a mapping at a fixed address. Fails if any
+    /// of it already is.
+    pub fn reserve(&self, start: usize, size: usize) -> Option<usize> {
+        if start % FRAME_SIZE != 0 || size == 0 {
+            return None;
+        }
+        let end = start.checked_add(align_up(size, FRAME_SIZE))?;
+        let mut node = single_node();
+        *node.front_mut().unwrap() = VmArea { start, end };
 
-        loop {
-            let start = align_up(self.end_brk.load(Ordering::Relaxed), layout.align());
-            let size = layout.size();
-            let end = start + size;
-            if let Ok(start) =
-                self.end_brk
-                    .compare_exchange(start, end, Ordering::Relaxed, Ordering::Relaxed)
-            {
-                return start;
+        let mut vma_list = self.vma_list.lock();
+        let mut cur = vma_list.cursor_front_mut();
+        while let Some(vma) = cur.current() {
+            if vma.overlaps(start, end) {
+                return None;
+            }
+            if vma.start >= end {
+                break;
+            }
+            cur.move_next();
+        }
+        cur.splice_before(node);
+        Some(start)
+    }
+
+    /// Give `[start, start + size)` back. Areas only partly in it shrink, one covering it
+    /// splits in two.
+    pub fn free(&self, start: usize, size: usize) {
+        let end = start.saturating_add(align_up(size, FRAME_SIZE));
+        let mut spare = single_node();
+        let mut removed = LinkedList::new();
+
+        let mut vma_list = self.vma_list.lock();
+        let mut cur = vma_list.cursor_front_mut();
+        while let Some(vma) = cur.current() {
+            if vma.start >= end {
+                break;
+            }
+            if !vma.overlaps(start, end) {
+                cur.move_next();
+            } else if start <= vma.start && vma.end <= end {
+                removed.append(&mut cur.remove_current_as_list().unwrap());
+            } else if vma.start < start && end < vma.end {
+                *spare.front_mut().unwrap() = VmArea {
+                    start: end,
+                    end: vma.end,
+                };
+                vma.end = start;
+                cur.splice_after(core::mem::take(&mut spare));
+                break;
+            } else if vma.start < start {
+                vma.end = start;
+                cur.move_next();
+            } else {
+                vma.start = end;
+                break;
             }
         }
-        //        self.vma_list.push_back(VmArea{start, end});
+        drop(vma_list);
+
+        /* Outside the lock, like the allocations */
+        drop(removed);
+        drop(spare);
+    }
+
+    /// The area in use that `vaddr` falls in.
+    pub fn find(&self, vaddr: usize) -> Option<VmArea> {
+        self.vma_list
+            .lock()
+            .iter()
+            .take_while(|vma| vma.start <= vaddr)
+            .find(|vma| vma.contains(vaddr))
+            .copied()
+    }
+}
+
+fn single_node() -> LinkedList<VmArea> {
+    let mut node = LinkedList::new();
+    node.push_back(VmArea::default());
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn pages(n: usize) -> Layout {
+        Layout::from_size_align(n * FRAME_SIZE, FRAME_SIZE).unwrap()
+    }
+
+    fn areas(man: &VMSpaceMan) -> Vec<(usize, usize)> {
+        man.vma_list
+            .lock()
+            .iter()
+            .map(|vma| (vma.start, vma.end))
+            .collect()
+    }
+
+    #[test]
+    fn free_splits_an_area() {
+        let man = VMSpaceMan::new();
+        let start = man.allocate_mem(pages(4)).unwrap();
+        man.free(start + FRAME_SIZE, 2 * FRAME_SIZE);
+
+        assert_eq!(
+            areas(&man),
+            [
+                (start, start + FRAME_SIZE),
+                (start + 3 * FRAME_SIZE, start + 4 * FRAME_SIZE)
+            ]
+        );
+        assert_eq!(man.find(start + FRAME_SIZE), None);
+        assert_eq!(
+            man.find(start + 3 * FRAME_SIZE).unwrap().start(),
+            start + 3 * FRAME_SIZE
+        );
+
+        /* The hole is handed out again */
+        assert_eq!(man.allocate_mem(pages(2)), Some(start + FRAME_SIZE));
+    }
+
+    #[test]
+    fn free_shrinks_the_ends() {
+        let man = VMSpaceMan::new();
+        let start = man.allocate_mem(pages(4)).unwrap();
+        man.free(start, FRAME_SIZE);
+        man.free(start + 3 * FRAME_SIZE, FRAME_SIZE);
+
+        assert_eq!(areas(&man), [(start + FRAME_SIZE, start + 3 * FRAME_SIZE)]);
+    }
+
+    #[test]
+    fn freed_neighbours_merge_into_one_gap() {
+        let man = VMSpaceMan::new();
+        let a = man.allocate_mem(pages(1)).unwrap();
+        let b = man.allocate_mem(pages(2)).unwrap();
+        let c = man.allocate_mem(pages(1)).unwrap();
+        assert_eq!((b, c), (a + FRAME_SIZE, a + 3 * FRAME_SIZE));
+
+        man.free(a, FRAME_SIZE);
+        man.free(c, FRAME_SIZE);
+        assert_eq!(areas(&man), [(b, c)]);
+        man.free(b, 2 * FRAME_SIZE);
+        assert!(areas(&man).is_empty());
+
+        /* Four pages only fit where all three of them were */
+        assert_eq!(man.allocate_mem(pages(4)), Some(a));
+    }
+
+    #[test]
+    fn free_across_several_areas() {
+        let man = VMSpaceMan::new();
+        let a = man.allocate_mem(pages(2)).unwrap();
+        let b = man.allocate_mem(pages(2)).unwrap();
+        let c = man.allocate_mem(pages(2)).unwrap();
+
+        /* The tail of `a`, all of `b` and the head of `c` */
+        man.free(a + FRAME_SIZE, 4 * FRAME_SIZE);
+        assert_eq!(
+            areas(&man),
+            [(a, a + FRAME_SIZE), (c + FRAME_SIZE, c + 2 * FRAME_SIZE)]
+        );
+        assert_eq!(man.find(b), None);
+    }
+
+    #[test]
+    fn reserve_refuses_overlaps() {
+        let man = VMSpaceMan::new();
+        let base = VM_START + 16 * FRAME_SIZE;
+        assert_eq!(man.reserve(base, 4 * FRAME_SIZE), Some(base));
+
+        assert_eq!(man.reserve(base, FRAME_SIZE), None);
+        assert_eq!(man.reserve(base - FRAME_SIZE, 2 * FRAME_SIZE), None);
+        assert_eq!(man.reserve(base + 3 * FRAME_SIZE, 2 * FRAME_SIZE), None);
+        assert_eq!(man.reserve(base - FRAME_SIZE, 6 * FRAME_SIZE), None);
+        assert_eq!(areas(&man), [(base, base + 4 * FRAME_SIZE)]);
+
+        /* Touching is not overlapping */
+        assert_eq!(
+            man.reserve(base - FRAME_SIZE, FRAME_SIZE),
+            Some(base - FRAME_SIZE)
+        );
+        assert_eq!(
+            man.reserve(base + 4 * FRAME_SIZE, FRAME_SIZE),
+            Some(base + 4 * FRAME_SIZE)
+        );
+        assert_eq!(areas(&man).len(), 3);
+    }
+
+    #[test]
+    fn allocations_skip_reserved_ranges() {
+        let man = VMSpaceMan::new();
+        man.reserve(VM_START + FRAME_SIZE, FRAME_SIZE).unwrap();
+
+        assert_eq!(man.allocate_mem(pages(2)), Some(VM_START + 2 * FRAME_SIZE));
+        assert_eq!(man.allocate_mem(pages(1)), Some(VM_START));
+    }
+
+    #[test]
+    fn best_fit_takes_the_smallest_gap() {
+        let man = VMSpaceMan::with_policy(FitPolicy::BestFit);
+        man.reserve(VM_START + 4 * FRAME_SIZE, FRAME_SIZE).unwrap();
+        man.reserve(VM_START + 7 * FRAME_SIZE, FRAME_SIZE).unwrap();
+
+        /* Four free pages first, then two */
+        assert_eq!(man.allocate_mem(pages(2)), Some(VM_START + 5 * FRAME_SIZE));
+        assert_eq!(
+            man.allocate_mem_with(pages(2), FitPolicy::FirstFit),
+            Some(VM_START)
+        );
     }
 }
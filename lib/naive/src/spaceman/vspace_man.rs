@@ -1,10 +1,13 @@
 use alloc::collections::linked_list::LinkedList;
+use alloc::vec::Vec;
 
 use spin::Mutex;
 
+use crate::objects::identify::{cap_identify, IdentifyResult};
 use crate::objects::{RamRef, VTableRef};
-use rustyl4api::error::SysResult;
-use rustyl4api::vspace::Permission;
+use crate::utils::align_down;
+use rustyl4api::error::{SysError, SysResult};
+use rustyl4api::vspace::{Permission, FRAME_BIT_SIZE, FRAME_SIZE};
 
 #[derive(Debug, Clone)]
 pub enum VSpaceEntry {
@@ -104,6 +107,19 @@ impl VSpaceEntry {
             VSpaceEntry::Table(t) => t.level,
         }
     }
+
+    /* Collect the caps of this entry and of everything below it, tables after their entries */
+    fn drain_into(self, frames: &mut Vec<RamRef>, tables: &mut Vec<VTableRef>) {
+        match self {
+            VSpaceEntry::Frame(f) => frames.push(f.cap),
+            VSpaceEntry::Table(t) => {
+                for entry in t.entry {
+                    entry.drain_into(frames, tables);
+                }
+                tables.push(t.cap);
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -113,6 +129,15 @@ pub enum VSpaceManError {
     PageTableMiss { level: usize },
 }
 
+/// The level of the entry mapping a frame of `bit_sz` bits: 0 for a 4K page, 1 and 2 for
+/// 2M and 1G blocks. Other sizes cannot be mapped.
+pub fn frame_level(bit_sz: usize) -> Option<usize> {
+    match bit_sz {
+        12 | 21 | 30 => Some((bit_sz - FRAME_BIT_SIZE) / 9),
+        _ => None,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FrameNode {
     vaddr: usize,
@@ -134,6 +159,18 @@ impl FrameNode {
     pub fn map_to_vspace(&self, root: &VTableRef) -> SysResult<()> {
         self.cap.map(root, self.vaddr, self.perm)
     }
+
+    pub fn vaddr(&self) -> usize {
+        self.vaddr
+    }
+
+    pub fn bit_size(&self) -> usize {
+        FRAME_BIT_SIZE + self.level * 9
+    }
+
+    pub fn size(&self) -> usize {
+        1 << self.bit_size()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -183,6 +220,44 @@ impl VTableNode {
     pub fn map_to_vspace(&self, root: &VTableRef) -> SysResult<()> {
         self.cap.map(root, self.vaddr, self.level + 1)
     }
+
+    /* Take the frame covering `vaddr` out of this table or the tables below it */
+    fn remove_frame(&mut self, vaddr: usize) -> Option<FrameNode> {
+        let level = self.level;
+        let idx = vaddr_to_idx(vaddr, level);
+        let mut cur = self.entry.cursor_front_mut();
+        while let Some(entry) = cur.current() {
+            if vaddr_to_idx(entry.vaddr(), level) == idx {
+                if let Some(table) = entry.as_vtable_node_mut() {
+                    return table.remove_frame(vaddr);
+                }
+                return match cur.remove_current() {
+                    Some(VSpaceEntry::Frame(frame)) => Some(frame),
+                    _ => None,
+                };
+            }
+            cur.move_next();
+        }
+        None
+    }
+
+    /* Take out the lowest table on the way to `vaddr` that has nothing left in it */
+    fn remove_empty_table(&mut self, vaddr: usize) -> Option<VTableRef> {
+        let level = self.level;
+        let idx = vaddr_to_idx(vaddr, level);
+        let mut cur = self.entry.cursor_front_mut();
+        while let Some(entry) = cur.current() {
+            if vaddr_to_idx(entry.vaddr(), level) == idx {
+                let table = entry.as_vtable_node_mut()?;
+                if !table.entry.is_empty() {
+                    return table.remove_empty_table(vaddr);
+                }
+                return cur.remove_current()?.into_vtablecap().ok();
+            }
+            cur.move_next();
+        }
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -205,6 +280,10 @@ impl VSpace {
         let mut cur_level = self.0.level();
         let mut cur_node = &mut self.0;
         while cur_level > level {
+            /* A block frame covers everything below its level */
+            if !cur_node.is_table() {
+                break;
+            }
             cur_node = cur_node
                 .as_vtable_node_mut()
                 .ok_or(VSpaceManError::SlotTypeError { level: cur_level })?
@@ -246,26 +325,47 @@ impl VSpace {
         Ok(())
     }
 
-    pub fn memory_unmap(&mut self, base_ptr: *mut u8, len: usize) {
-        let range = base_ptr as usize..base_ptr as usize + len;
-        'outer: for vaddr in range.step_by(4096) {
-            let table_entry = self
-                .lookup_entry(vaddr, 3)
-                .unwrap()
-                .as_vtable_node_mut()
-                .unwrap();
-            let mut cur = table_entry.entry.cursor_front_mut();
-            cur.move_next();
-            while cur.current().is_some() {
-                if cur.current().unwrap().as_frame_node().unwrap().vaddr == vaddr as usize {
-                    let entry = cur.remove_current().unwrap();
-                    let frame_cap = entry.into_ramcap().unwrap();
-                    frame_cap.unmap().unwrap();
-                    continue 'outer;
-                }
-                cur.move_next();
+    fn remove_frame(&mut self, vaddr: usize) -> Option<FrameNode> {
+        self.0.as_vtable_node_mut().unwrap().remove_frame(vaddr)
+    }
+
+    fn remove_empty_table(&mut self, vaddr: usize) -> Option<VTableRef> {
+        self.0
+            .as_vtable_node_mut()
+            .unwrap()
+            .remove_empty_table(vaddr)
+    }
+
+    /* Check the whole range first so that a failure leaves every mapping as it was */
+    pub fn protect(&mut self, base_ptr: *mut u8, len: usize, perm: Permission) -> SysResult<()> {
+        let start = base_ptr as usize;
+        let end = start + len;
+
+        let mut vaddr = start;
+        while vaddr < end {
+            let frame = self
+                .lookup_entry(vaddr, 0)
+                .ok()
+                .and_then(|e| e.as_frame_node())
+                .ok_or(SysError::VSpaceCapNotMapped)?;
+            if frame.vaddr() < start || frame.vaddr() + frame.size() > end {
+                return Err(SysError::InvalidValue);
             }
+            vaddr = frame.vaddr() + frame.size();
+        }
+
+        let mut vaddr = start;
+        while vaddr < end {
+            let frame = self
+                .lookup_entry(vaddr, 0)
+                .ok()
+                .and_then(|e| e.as_frame_node_mut())
+                .unwrap();
+            frame.cap.remap(perm)?;
+            frame.perm = perm;
+            vaddr = frame.vaddr() + frame.size();
         }
+        Ok(())
     }
 }
 
@@ -314,8 +414,71 @@ impl VSpaceMan {
             .map_err(|(e, ent)| (e, ent.into_vtablecap().unwrap()))
     }
 
-    pub fn memory_unmap(&self, base_ptr: *mut u8, len: usize) {
-        self.root.lock().memory_unmap(base_ptr, len)
+    /// Unmap the frames in `[base_ptr, base_ptr + len)` and hand their caps to `f`, e.g. to
+    /// free them. The page tables left empty are unmapped too and handed to `g`.
+    ///
+    /// A frame whose cap was revoked meanwhile, e.g. memory shared by a process that has
+    /// freed it, is already unmapped and is only dropped from the VSpace.
+    pub fn memory_unmap<F, G>(&self, base_ptr: *mut u8, len: usize, mut f: F, mut g: G)
+    where
+        F: FnMut(RamRef),
+        G: FnMut(VTableRef),
+    {
+        let end = base_ptr as usize + len;
+        let mut vaddr = base_ptr as usize;
+        while vaddr < end {
+            /* One frame at a time, freeing a frame may need the lock */
+            let frame = self.root.lock().remove_frame(vaddr);
+            match frame {
+                Some(frame) => {
+                    vaddr = frame.vaddr() + frame.size();
+                    if let Err(e) = frame.cap.unmap() {
+                        match cap_identify(frame.cap.slot()) {
+                            Ok(IdentifyResult::NullObj) => {}
+                            _ => panic!("cannot unmap frame at {:x}: {:?}", frame.vaddr(), e),
+                        }
+                    }
+                    f(frame.cap);
+                }
+                None => vaddr = align_down(vaddr, FRAME_SIZE) + FRAME_SIZE,
+            }
+        }
+
+        /* A last level table covers 2M. Tables are unmapped under the lock so that no new
+         * table is mapped in their place before they are gone */
+        let table_span = 1 << (FRAME_BIT_SIZE + 9);
+        let mut vaddr = align_down(base_ptr as usize, table_span);
+        while vaddr < end {
+            loop {
+                let mut root = self.root.lock();
+                let table = match root.remove_empty_table(vaddr) {
+                    Some(table) => table,
+                    None => break,
+                };
+                table.unmap().unwrap();
+                drop(root);
+                g(table);
+            }
+            vaddr += table_span;
+        }
+    }
+
+    /// Change the permission of every frame in `[base_ptr, base_ptr + len)`. The range must
+    /// be fully mapped and must not cut through a block frame.
+    pub fn protect(&self, base_ptr: *mut u8, len: usize, perm: Permission) -> SysResult<()> {
+        self.root.lock().protect(base_ptr, len, perm)
+    }
+
+    /// Invalidate every mapping of the VSpace and hand back the caps of its frames and tables,
+    /// the root table last, so that they can be freed.
+    pub fn teardown(self) -> SysResult<(Vec<RamRef>, Vec<VTableRef>)> {
+        let root = self.root.into_inner();
+        root.root_cap().teardown()?;
+
+        let mut frames = Vec::new();
+        let mut tables = Vec::new();
+        root.0.drain_into(&mut frames, &mut tables);
+        Ok((frames, tables))
     }
 
     pub fn lookup_entry(&self, vaddr: usize, level: u8) -> Result<VSpaceEntry, VSpaceManError> {
@@ -1,33 +1,159 @@
-use crate::objects::{TcbCap, TcbObj};
-use crate::space_manager::{gsm, ROOT_CNODE_CAP, ROOT_VNODE_CAP};
+use crate::objects::{EpCap, RamCap, RamObj, TcbCap, TcbObj};
+use crate::space_manager::{copy_cap, gsm, ROOT_CNODE_CAP, ROOT_VNODE_CAP};
+use rustyl4api::error::SysResult;
+use rustyl4api::syscall::{syscall, MsgInfo, SyscallOp};
+use rustyl4api::vspace::FRAME_SIZE;
 
+/// A thread sharing the address space of its creator. Dropping it deletes the TCB, which
+/// destroys the thread, and gives its stack and IPC buffer back.
 pub struct Thread {
-    _tcb: TcbCap,
+    /* Only taken when the thread is dropped */
+    tcb: Option<TcbCap>,
+    ipc_buf: Option<RamCap>,
+    ipc_buf_vaddr: usize,
+    stack: Stack,
     // _fault_receiver: FaultReceiver,
 }
 
+/* Where the stack of a thread lives, with its guard page if it has one */
+enum Stack {
+    Paged { base: usize, size: usize },
+    Fixed { base: usize, size: usize },
+}
+
+impl Thread {
+    pub fn tcb(&self) -> &TcbCap {
+        self.tcb.as_ref().unwrap()
+    }
+
+    pub fn suspend(&self) -> SysResult<()> {
+        self.tcb().suspend()
+    }
+
+    pub fn resume(&self) -> SysResult<()> {
+        self.tcb().resume()
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        /* The thread must be gone before its stack and IPC buffer are */
+        if let Err(e) = gsm!().free_object(self.tcb.take().unwrap()) {
+            log::warn!("cannot free the TCB of a thread: {:?}", e);
+        }
+
+        gsm!().memory_unmap(self.ipc_buf_vaddr as *mut u8, FRAME_SIZE);
+        if let Err(e) = gsm!().free_object(self.ipc_buf.take().unwrap()) {
+            log::warn!("cannot free the IPC buffer of a thread: {:?}", e);
+        }
+
+        match self.stack {
+            Stack::Paged { base, size } => crate::pager::release(base, size),
+            Stack::Fixed { base, size } => gsm!().memory_free(base as *mut u8, size),
+        }
+    }
+}
+
+/* Pages of a thread stack mapped up front, and how far it may grow on demand */
+const STACK_PAGES: usize = 4;
+const STACK_MAX_PAGES: usize = 64;
+
+/// Spawn a thread whose stack grows on demand up to `STACK_MAX_PAGES` pages, with a guard page
+/// below. Its faults go to the pager.
 pub fn spawn(entry: fn() -> !) -> Thread {
-    use rustyl4api::vspace::{Permission, FRAME_SIZE};
+    use core::alloc::Layout;
+    use rustyl4api::vspace::Permission;
+
+    let layout = Layout::from_size_align((STACK_MAX_PAGES + 1) * FRAME_SIZE, FRAME_SIZE).unwrap();
+    let guard = gsm!().vspace_alloc(layout).unwrap();
+    let stack_top = guard + layout.size();
+    let stack_base = stack_top - STACK_PAGES * FRAME_SIZE;
+
+    gsm!()
+        .map_frame_at(
+            0,
+            stack_base,
+            FRAME_SIZE * STACK_PAGES,
+            Permission::writable(),
+        )
+        .unwrap();
+    crate::pager::guard(guard, FRAME_SIZE);
+    crate::pager::reserve_at(
+        guard + FRAME_SIZE,
+        stack_base - guard - FRAME_SIZE,
+        Permission::writable(),
+    );
+
+    let stack = Stack::Paged {
+        base: guard,
+        size: layout.size(),
+    };
+    spawn_on_stack(entry, stack, stack_top, Some(&crate::pager::fault_ep()))
+}
+
+/// Spawn a thread with a stack of `STACK_PAGES` pages mapped up front and no fault handler,
+/// e.g. the pager itself.
+pub(crate) fn spawn_fixed(entry: fn() -> !) -> Thread {
+    spawn_fixed_stack(entry, None)
+}
+
+/// Spawn a thread with a stack of `STACK_PAGES` pages mapped up front whose faults go to
+/// `fault_ep`, e.g. to check them in a test.
+pub fn spawn_with_fault_handler(entry: fn() -> !, fault_ep: &EpCap) -> Thread {
+    spawn_fixed_stack(entry, Some(fault_ep))
+}
+
+fn spawn_fixed_stack(entry: fn() -> !, fault_ep: Option<&EpCap>) -> Thread {
+    use rustyl4api::vspace::Permission;
+
+    let size = FRAME_SIZE * STACK_PAGES;
+    let base = gsm!()
+        .map_frame_at(0, 0, size, Permission::writable())
+        .unwrap() as usize;
+    spawn_on_stack(entry, Stack::Fixed { base, size }, base + size, fault_ep)
+}
+
+fn spawn_on_stack(
+    entry: fn() -> !,
+    stack: Stack,
+    stack_top: usize,
+    fault_ep: Option<&EpCap>,
+) -> Thread {
+    use rustyl4api::vspace::{Permission, FRAME_BIT_SIZE};
 
-    let npages = 4;
     let tcb = gsm!().alloc_object::<TcbObj>(12)
         .expect("Fail to allocate TCB object");
 
-    let stack_base = gsm!()
-        .map_frame_at(0, 0, FRAME_SIZE * npages, Permission::writable())
-        .unwrap() as usize;
-    // let fault_receiver = EP_SERVER.derive_fault_receiver().unwrap();
+    let ipc_buf = gsm!()
+        .alloc_object::<RamObj>(FRAME_BIT_SIZE)
+        .expect("Fail to allocate IPC buffer");
+    let ipc_buf_vaddr = gsm!()
+        .insert_ram_at(copy_cap(&ipc_buf).unwrap(), 0, Permission::writable())
+        .expect("Fail to map IPC buffer") as usize;
     tcb.configure(
         Some(&ROOT_VNODE_CAP),
         Some(&ROOT_CNODE_CAP),
-        // Some(&fault_receiver.badged_ep()),
-        None,
+        fault_ep,
+        Some((&ipc_buf, ipc_buf_vaddr)),
     )
     .expect("Error Configuring TCB");
 
-    tcb.set_registers(0b1100, entry as usize, stack_base + FRAME_SIZE * npages)
+    tcb.set_entry(entry as usize, stack_top, &[])
         .expect("Error Setting Registers");
     tcb.resume().expect("Error Resuming TCB");
-    // Thread { _tcb: tcb, _fault_receiver: fault_receiver }
-    Thread { _tcb: tcb }
+    Thread {
+        tcb: Some(tcb),
+        ipc_buf: Some(ipc_buf),
+        ipc_buf_vaddr,
+        stack,
+    }
+}
+
+/// Stop the calling thread for good. Its TCB stays around until the last capability to it is
+/// deleted.
+pub fn exit() -> ! {
+    let info = MsgInfo::new(SyscallOp::ThreadExit, 0);
+    let mut args = [0; 6];
+    let _ = syscall(info, &mut args);
+    unreachable!("exited thread got scheduled again")
 }
@@ -1,12 +1,39 @@
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
 
 use futures_util::AsyncReadExt;
+use rustyl4api::error::SysError;
 
 use crate::fs::File;
+use crate::objects::{EndpointObj, EpCap};
+use crate::space_manager::gsm;
+use crate::thread::{self, Thread};
 
 static TIME_CLIENT: OnceCell<Mutex<File>> = OnceCell::uninit();
 
+/* Frequency of the generic timer */
+fn cntfrq_el0() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("mrs {x}, cntfrq_el0", x = out(reg) x, options(nomem));
+    }
+    x
+}
+
+fn cntpct_el0() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("isb", "mrs {x}, cntpct_el0", x = out(reg) x, options(nomem));
+    }
+    x
+}
+
 async fn time_client() -> &'static Mutex<File> {
     let time_client = if TIME_CLIENT.get().is_none() {
         let timer_fd = File::open(&"/dev/timer").await.unwrap();
@@ -29,13 +56,133 @@ pub async fn current_time() -> u64 {
     unsafe { core::mem::transmute(time_buf) }
 }
 
-pub async fn sleep_us(us: u64) {
-    // QEMU for rpi3 do not support system timer irq. spin waiting for now.
-    let cur = current_time().await;
+/// Future returned by `sleep_us` and `sleep_ms`.
+#[derive(Debug)]
+pub struct Sleep {
+    /* In generic timer ticks */
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    /*
+     * Blocking the thread would stall every other task of the executor, so a sleeping task
+     * leaves its waker to the sleeper thread, which wakes it at the deadline.
+     */
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if cntpct_el0() >= self.deadline {
+            Poll::Ready(())
+        } else {
+            SLEEPER.add(self.deadline, cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+/*
+ * Longest single wait of the sleeper thread, in ticks. It fits the timeout field of a system
+ * call, and a longer sleep takes several waits.
+ */
+const MAX_WAIT: u64 = u32::MAX as u64;
+
+/*
+ * The deadlines of the sleeping tasks. A thread of its own waits for the earliest one with a
+ * timed receive on an endpoint nobody else uses, and is sent an empty message when an earlier
+ * deadline comes in.
+ */
+struct Sleeper {
+    ep: EpCap,
+    inner: Mutex<SleeperInner>,
+    _thread: Thread,
+}
+
+struct SleeperInner {
+    wakers: Vec<(u64, Waker)>,
+    /* The deadline the sleeper thread waits for, u64::MAX if none */
+    waiting_for: u64,
+}
+
+lazy_static! {
+    static ref SLEEPER: Sleeper = Sleeper {
+        ep: gsm!().alloc_object::<EndpointObj>(12).unwrap(),
+        inner: Mutex::new(SleeperInner {
+            wakers: Vec::new(),
+            waiting_for: u64::MAX,
+        }),
+        _thread: thread::spawn(sleeper_thread),
+    };
+}
+
+impl Sleeper {
+    fn add(&self, deadline: u64, waker: &Waker) {
+        let mut inner = self.inner.lock();
+        let known = inner
+            .wakers
+            .iter()
+            .any(|(d, w)| *d == deadline && w.will_wake(waker));
+        if !known {
+            inner.wakers.push((deadline, waker.clone()));
+        }
+        let kick = deadline < inner.waiting_for;
+        drop(inner);
+
+        /* The sleeper thread always comes back to its receive, so this blocks only briefly */
+        if kick {
+            self.ep.send(&[], Vec::new()).ok();
+        }
+    }
+
+    /* Take the wakers whose deadline has passed, and set the next deadline to wait for */
+    fn expire(&self, now: u64) -> (Vec<Waker>, u64) {
+        let mut inner = self.inner.lock();
+        let mut expired = Vec::new();
+        let mut i = 0;
+        while i < inner.wakers.len() {
+            if inner.wakers[i].0 <= now {
+                expired.push(inner.wakers.swap_remove(i).1);
+            } else {
+                i += 1;
+            }
+        }
+        let next = inner
+            .wakers
+            .iter()
+            .map(|(d, _)| *d)
+            .min()
+            .unwrap_or(u64::MAX);
+        inner.waiting_for = next;
+        (expired, next)
+    }
+}
+
+fn sleeper_thread() -> ! {
+    loop {
+        let now = cntpct_el0();
+        let (expired, next) = SLEEPER.expire(now);
+        expired.into_iter().for_each(Waker::wake);
+
+        /* A timeout of 0 waits for a message only */
+        let ticks = if next == u64::MAX {
+            0
+        } else {
+            (next - now).clamp(1, MAX_WAIT)
+        };
+        match SLEEPER.ep.receive_timeout(Vec::new(), ticks as usize) {
+            Ok(_) | Err(SysError::Timeout) => {}
+            Err(e) => log::error!("sleeper receive error {:?}", e),
+        }
+    }
+}
 
-    while current_time().await < cur + us {}
+/// Complete once `us` microseconds have passed. The other tasks keep running meanwhile.
+pub fn sleep_us(us: u64) -> Sleep {
+    let ticks = us.saturating_mul(cntfrq_el0()) / 1_000_000;
+    Sleep {
+        deadline: cntpct_el0().saturating_add(ticks),
+    }
 }
 
-pub async fn sleep_ms(ms: u64) {
-    sleep_us(ms * 1000).await
+pub fn sleep_ms(ms: u64) -> Sleep {
+    sleep_us(ms.saturating_mul(1000))
 }
@@ -3,7 +3,7 @@ mod slab_allocator;
 
 use core::alloc::{AllocError, GlobalAlloc, Layout};
 use core::ptr::NonNull;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use slab_allocator::SlabAllocator;
 
@@ -12,10 +12,30 @@ use rustyl4api::vspace::{Permission, FRAME_SIZE};
 
 pub const SLAB_ALLOC_BITSZ: usize = rustyl4api::vspace::FRAME_BIT_SIZE;
 
+/// Heap usage, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Requested by allocations that are not freed yet
+    pub in_use: usize,
+    /// Mapped for the heap, slab pools and large allocations
+    pub mapped: usize,
+    /// The most `in_use` has been
+    pub peak: usize,
+}
+
+/// Objects of up to a page come from the slab pools, one per power-of-two size class. Larger
+/// ones get frames of their own, which are unmapped and given back when they are freed.
+///
+/// Heap memory is always mapped before it is handed out, never left to the pager: the space
+/// manager and the pager allocate from the heap themselves, and a fault taken while holding
+/// a space manager lock, or on the pager thread, cannot be served.
 #[derive(Debug)]
 pub struct VmAllocator {
     slab_alloc: SlabAllocator,
     backup_empty: AtomicBool,
+    in_use: AtomicUsize,
+    mapped: AtomicUsize,
+    peak: AtomicUsize,
 }
 
 impl VmAllocator {
@@ -23,14 +43,19 @@ impl VmAllocator {
         VmAllocator {
             slab_alloc: SlabAllocator::new(),
             backup_empty: AtomicBool::new(false),
+            in_use: AtomicUsize::new(0),
+            mapped: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
         }
     }
 
     pub fn add_mempool(&self, base: *mut u8, size: usize) {
+        self.mapped.fetch_add(size, Ordering::Relaxed);
         self.slab_alloc.add_mempool(base, size)
     }
 
     pub fn add_backup_mempool(&self, base: *mut u8, size: usize) {
+        self.mapped.fetch_add(size, Ordering::Relaxed);
         self.slab_alloc.add_backup_mempool(base, size)
     }
 
@@ -42,25 +67,44 @@ impl VmAllocator {
     }
 
     pub fn vm_alloc(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
-        // TODO: support object larger than a page
-        let obj_bitsz = layout.size().trailing_zeros();
-        if obj_bitsz >= SLAB_ALLOC_BITSZ as u32 {
-            let ret = gsm!()
-                .map_frame_at(0, 0, layout.size(), Permission::writable())
-                .map(|vaddr| NonNull::new(vaddr).unwrap())
-                .map_err(|_| AllocError {});
-            return ret;
-        }
-
-        self.slab_alloc.slab_alloc(layout).or_else(|_| {
-            self.slab_alloc.swap_pool();
-            self.slab_refill();
-            self.slab_alloc.slab_alloc(layout)
-        })
+        let ret = if is_large(layout) {
+            gsm!()
+                .memory_map(layout, Permission::writable())
+                .and_then(NonNull::new)
+                .ok_or(AllocError {})
+                .map(|ptr| {
+                    self.mapped.fetch_add(large_size(layout), Ordering::Relaxed);
+                    ptr
+                })
+        } else {
+            self.slab_alloc.slab_alloc(layout).or_else(|_| {
+                self.slab_alloc.swap_pool();
+                self.slab_refill();
+                self.slab_alloc.slab_alloc(layout)
+            })
+        }?;
+
+        let in_use = self.in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+        Ok(ret)
     }
 
     pub fn vm_dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
-        self.slab_alloc.slab_dealloc(ptr, layout)
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        if is_large(layout) {
+            gsm!().memory_free(ptr.as_ptr(), large_size(layout));
+            self.mapped.fetch_sub(large_size(layout), Ordering::Relaxed);
+        } else {
+            self.slab_alloc.slab_dealloc(ptr, layout)
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            in_use: self.in_use.load(Ordering::Relaxed),
+            mapped: self.mapped.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+        }
     }
 
     pub fn backup_empty(&self) -> bool {
@@ -92,6 +136,20 @@ unsafe impl GlobalAlloc for VmAllocator {
 #[global_allocator]
 pub static GLOBAL_VM_ALLOC: VmAllocator = VmAllocator::new();
 
+/* Too big for the largest slab size class */
+fn is_large(layout: Layout) -> bool {
+    layout.size().max(layout.align()) > 1 << SLAB_ALLOC_BITSZ
+}
+
+fn large_size(layout: Layout) -> usize {
+    crate::utils::align_up(layout.size(), FRAME_SIZE)
+}
+
+/// How much memory the heap of this process uses.
+pub fn heap_stats() -> HeapStats {
+    GLOBAL_VM_ALLOC.stats()
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!("allocation error: {:?}", layout)
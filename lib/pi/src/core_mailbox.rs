@@ -0,0 +1,55 @@
+use volatile::Volatile;
+
+/// The mailbox bits of the core interrupt source registers start here (ref: QA7 4.10, page 16)
+const MAILBOX_IRQ_SHIFT: usize = 4;
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    _unused1: [u32; 20],
+    CORE_MAILBOX_IRQCNTL: [u32; 4],
+    CORE_IRQ_SRC: [u32; 4],
+    CORE_FIQ_SRC: [u32; 4],
+    CORE_MAILBOX_SET: [[u32; 4]; 4],
+    CORE_MAILBOX_RDCLR: [[u32; 4]; 4],
+}
+
+/// The per core mailboxes of the BCM2836 local peripherals (ref: QA7 4.7, page 9). Each core owns
+/// four 32-bit mailboxes. Setting bits in another core's mailbox raises an interrupt on that core.
+pub struct CoreMailbox {
+    registers: &'static mut Registers,
+}
+
+impl CoreMailbox {
+    /// Returns a new instance of `CoreMailbox`.
+    pub fn new(base: usize) -> CoreMailbox {
+        CoreMailbox {
+            registers: unsafe { &mut *(base as *mut Registers) },
+        }
+    }
+
+    /// Routes mailbox `mbox` of core `cpu` to the IRQ line of that core.
+    pub fn enable_irq(&mut self, cpu: usize, mbox: usize) {
+        let val = Volatile::new_read_only(&self.registers.CORE_MAILBOX_IRQCNTL[cpu]).read();
+        Volatile::new_write_only(&mut self.registers.CORE_MAILBOX_IRQCNTL[cpu])
+            .write(val | (1 << mbox));
+    }
+
+    /// Sets `bits` in mailbox `mbox` of core `cpu`.
+    pub fn send(&mut self, cpu: usize, mbox: usize, bits: u32) {
+        Volatile::new_write_only(&mut self.registers.CORE_MAILBOX_SET[cpu][mbox]).write(bits);
+    }
+
+    /// Reads mailbox `mbox` of core `cpu` and clears the bits that were read.
+    pub fn take(&mut self, cpu: usize, mbox: usize) -> u32 {
+        let val = Volatile::new_read_only(&self.registers.CORE_MAILBOX_RDCLR[cpu][mbox]).read();
+        Volatile::new_write_only(&mut self.registers.CORE_MAILBOX_RDCLR[cpu][mbox]).write(val);
+        val
+    }
+
+    pub fn is_pending(&self, cpu: usize, mbox: usize) -> bool {
+        Volatile::new_read_only(&self.registers.CORE_IRQ_SRC[cpu]).read()
+            & (1 << (MAILBOX_IRQ_SHIFT + mbox))
+            != 0
+    }
+}
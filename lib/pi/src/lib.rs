@@ -14,5 +14,7 @@ pub mod gpio;
 pub mod timer;
 pub mod uart;
 //pub mod atags;
+pub mod core_mailbox;
 pub mod generic_timer;
 pub mod interrupt;
+pub mod pl011;
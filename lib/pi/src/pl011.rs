@@ -0,0 +1,91 @@
+use volatile::Volatile;
+
+/// The base address for the PL011 `UART0` registers.
+//const PL011_REG_BASE: usize = IO_BASE + 0x201000;
+
+/// Bit fields of the `FR` register.
+#[repr(u32)]
+enum FrStatus {
+    RxEmpty = 1 << 4,
+    TxFull = 1 << 5,
+}
+
+const LCRH_FEN: u32 = 1 << 4;
+const LCRH_WLEN_8: u32 = 0b11 << 5;
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    DR: u32, /* 0x7E20 1000 */
+    RSRECR: u32,
+    __r0: [u32; 4],
+    FR: u32, /* 0x7E20 1018 */
+    __r1: u32,
+    ILPR: u32,
+    IBRD: u32,
+    FBRD: u32,
+    LCRH: u32,
+    CR: u32, /* 0x7E20 1030 */
+    IFLS: u32,
+    IMSC: u32,
+    RIS: u32,
+    MIS: u32,
+    ICR: u32,
+}
+
+/// The Raspberry Pi's PL011 UART, driven by polling.
+pub struct Pl011 {
+    registers: &'static mut Registers,
+}
+
+impl Pl011 {
+    pub fn new(page_base: usize) -> Pl011 {
+        Pl011 {
+            registers: unsafe { &mut *(page_base as *mut Registers) },
+        }
+    }
+
+    /// Sets the data size to 8 bits, enables the FIFOs and sets the baud rate to `baud_rate`
+    /// from the 48 MHz UART clock, then enables the transmitter and receiver. Interrupts are
+    /// left masked.
+    pub fn initialize(&mut self, baud_rate: usize) {
+        const UART_CLOCK: usize = 48_000_000;
+        let divisor = UART_CLOCK * 4 / baud_rate;
+
+        Volatile::new_write_only(&mut self.registers.CR).write(0);
+        Volatile::new_write_only(&mut self.registers.ICR).write(0x7ff);
+        Volatile::new_write_only(&mut self.registers.IMSC).write(0);
+        Volatile::new_write_only(&mut self.registers.IBRD).write((divisor >> 6) as u32);
+        Volatile::new_write_only(&mut self.registers.FBRD).write((divisor & 0x3f) as u32);
+        Volatile::new_write_only(&mut self.registers.LCRH).write(LCRH_WLEN_8 | LCRH_FEN);
+        Volatile::new_write_only(&mut self.registers.CR).write(CR_UARTEN | CR_TXE | CR_RXE);
+    }
+
+    pub fn can_write(&self) -> bool {
+        let val = Volatile::new_read_only(&self.registers.FR).read();
+        val & FrStatus::TxFull as u32 == 0
+    }
+
+    /// Write the byte `byte`. This method blocks until there is space available
+    /// in the output FIFO.
+    pub fn write_byte(&mut self, byte: u8) {
+        while !self.can_write() {}
+        Volatile::new_write_only(&mut self.registers.DR).write(byte as u32);
+    }
+
+    /// Returns `true` if there is at least one byte ready to be read. This
+    /// method does not block.
+    pub fn has_byte(&self) -> bool {
+        let val = Volatile::new_read_only(&self.registers.FR).read();
+        val & FrStatus::RxEmpty as u32 == 0
+    }
+
+    /// Reads a byte. Blocks indefinitely until a byte is ready to be read.
+    pub fn read_byte(&mut self) -> u8 {
+        while !self.has_byte() {}
+        Volatile::new_read_only(&self.registers.DR).read() as u8
+    }
+}
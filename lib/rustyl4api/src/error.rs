@@ -15,6 +15,10 @@ pub enum SysError {
     VSpaceSlotOccupied { level: u8 },
     VSpacePermissionError,
     InvalidValue,
+    InsufficientRights,
+    RevokeFirst,
+    IpcAborted,
+    Timeout,
 
     /* Untyped */
     SizeTooSmall,
@@ -37,6 +41,10 @@ impl SysError {
             SysError::VSpaceSlotOccupied { level: _ } => SysErrno::VSpaceSlotOccupied,
             SysError::VSpacePermissionError => SysErrno::VSpacePermissionError,
             SysError::InvalidValue => SysErrno::InvalidValue,
+            SysError::InsufficientRights => SysErrno::InsufficientRights,
+            SysError::RevokeFirst => SysErrno::RevokeFirst,
+            SysError::IpcAborted => SysErrno::IpcAborted,
+            SysError::Timeout => SysErrno::Timeout,
             SysError::SizeTooSmall => SysErrno::SizeTooSmall,
         }
     }
@@ -59,6 +67,10 @@ pub enum SysErrno {
     VSpaceSlotOccupied,
     VSpacePermissionError,
     InvalidValue,
+    InsufficientRights,
+    RevokeFirst,
+    IpcAborted,
+    Timeout,
 
     /* Untyped */
     SizeTooSmall,
@@ -4,6 +4,8 @@ use num_traits::FromPrimitive;
 pub enum Fault {
     DataFault(VmFaultInfo),
     PrefetchFault(VmFaultInfo),
+    UserException(UserExceptionInfo),
+    UnknownSyscall(UnknownSyscallInfo),
 }
 
 impl Fault {
@@ -16,13 +18,21 @@ impl Fault {
     }
 
     pub fn new_prefetch_fault(addr: u64, level: u8, kind: VmFaultKind) -> Self {
-        Self::DataFault(VmFaultInfo {
+        Self::PrefetchFault(VmFaultInfo {
             address: addr,
             level,
             kind,
         })
     }
 
+    pub fn new_user_exception(pc: u64, kind: UserExceptionKind, syndrome: u32) -> Self {
+        Self::UserException(UserExceptionInfo { pc, kind, syndrome })
+    }
+
+    pub fn new_unknown_syscall(pc: u64, number: u16) -> Self {
+        Self::UnknownSyscall(UnknownSyscallInfo { pc, number })
+    }
+
     pub fn as_ipc_message_buf(&self) -> [usize; 3] {
         let mut buf = [0; 3];
         match self {
@@ -36,25 +46,44 @@ impl Fault {
                 buf[1] = info.address as usize;
                 buf[2] = (info.level as usize) << 32 | info.kind as usize;
             }
+            Self::UserException(info) => {
+                buf[0] = 2;
+                buf[1] = info.pc as usize;
+                buf[2] = (info.kind as usize) << 32 | info.syndrome as usize;
+            }
+            Self::UnknownSyscall(info) => {
+                buf[0] = 3;
+                buf[1] = info.pc as usize;
+                buf[2] = info.number as usize;
+            }
         }
         buf
     }
 
     pub fn from_ipc_message_buf(buf: &[usize]) -> Self {
-        let addr = buf[1] as u64;
-        let level = (buf[2] >> 32) as u8;
-        let kind = buf[2] as u8;
-        let info = VmFaultInfo {
-            address: addr,
-            level: level,
-            kind: VmFaultKind::from_u8(kind).unwrap(),
-        };
-        if buf[0] == 0 {
-            Self::DataFault(info)
-        } else if buf[0] == 1 {
-            Self::PrefetchFault(info)
-        } else {
-            panic!()
+        match buf[0] {
+            0 | 1 => {
+                let info = VmFaultInfo {
+                    address: buf[1] as u64,
+                    level: (buf[2] >> 32) as u8,
+                    kind: VmFaultKind::from_u8(buf[2] as u8).unwrap(),
+                };
+                if buf[0] == 0 {
+                    Self::DataFault(info)
+                } else {
+                    Self::PrefetchFault(info)
+                }
+            }
+            2 => Self::UserException(UserExceptionInfo {
+                pc: buf[1] as u64,
+                kind: UserExceptionKind::from_u8((buf[2] >> 32) as u8).unwrap(),
+                syndrome: buf[2] as u32,
+            }),
+            3 => Self::UnknownSyscall(UnknownSyscallInfo {
+                pc: buf[1] as u64,
+                number: buf[2] as u16,
+            }),
+            _ => panic!(),
         }
     }
 }
@@ -77,3 +106,34 @@ pub enum VmFaultKind {
     TlbConflict = 5,
     Other = 6,
 }
+
+/// A synchronous exception that is not a memory fault. `pc` is where it was taken and `syndrome`
+/// the raw ESR_EL1 value, which holds the immediate of a `brk` for example.
+#[derive(Copy, Clone, Debug)]
+pub struct UserExceptionInfo {
+    pub pc: u64,
+    pub kind: UserExceptionKind,
+    pub syndrome: u32,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+pub enum UserExceptionKind {
+    UndefinedInstruction = 0,
+    IllegalExecutionState = 1,
+    PcAlignment = 2,
+    SpAlignment = 3,
+    TrappedFpu = 4,
+    Breakpoint = 5,
+    Step = 6,
+    Watchpoint = 7,
+    Brk = 8,
+    Other = 9,
+}
+
+/// An `svc` with an immediate the kernel does not serve. `pc` is the instruction after it.
+#[derive(Copy, Clone, Debug)]
+pub struct UnknownSyscallInfo {
+    pub pc: u64,
+    pub number: u16,
+}
@@ -1,4 +1,12 @@
+use core::arch::asm;
+
+use crate::thread::UserContext;
+
+/// Message words passed in registers. The rest of a message goes through the IPC buffer.
 pub const IPC_MAX_ARGS: usize = 4;
+/// Longest message in words, counting the ones passed in registers.
+pub const IPC_MAX_MSG_LEN: usize = 120;
+pub const IPC_MAX_CAPS: usize = 4;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
@@ -8,3 +16,32 @@ pub enum IpcMessageType {
     Notification,
     Fault,
 }
+
+/// A frame registered with `TcbConfigure`, mapped in the thread's own address space. Word `i` of a
+/// message lives in `msg[i]` once `i` is past the register words, whose slots stay unused.
+#[repr(C)]
+pub struct IpcBuffer {
+    pub msg: [usize; IPC_MAX_MSG_LEN],
+    /// Slots of the capabilities to send, or to receive into. Like the message words, the
+    /// first one is passed in a register, so `caps[0]` stays unused and `caps[i]` holds the
+    /// `i`th slot.
+    pub caps: [usize; IPC_MAX_CAPS],
+}
+
+impl IpcBuffer {
+    /// Registers read or written by `TcbReadRegisters` and `TcbWriteRegisters`, laid over `msg`.
+    pub fn user_context(&mut self) -> &mut UserContext {
+        unsafe { &mut *(self.msg.as_mut_ptr() as *mut UserContext) }
+    }
+}
+
+/// The IPC buffer of the calling thread, if it has one. The kernel keeps its address in
+/// tpidr_el0.
+pub fn ipc_buffer() -> Option<&'static mut IpcBuffer> {
+    let buf: usize;
+
+    unsafe {
+        asm!("mrs {buf}, tpidr_el0", buf = out(reg) buf, options(nomem));
+        (buf as *mut IpcBuffer).as_mut()
+    }
+}
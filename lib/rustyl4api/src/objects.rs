@@ -10,6 +10,7 @@ pub enum ObjType {
     Reply = 7,
     Monitor = 8,
     Interrupt = 9,
+    Notification = 10,
 }
 
 impl Default for ObjType {
@@ -24,3 +25,63 @@ pub const TCB_OBJ_BIT_SZ: usize = 10;
 pub const CNODE_DEPTH: usize = core::mem::size_of::<usize>() * 8;
 pub const CNODE_ENTRY_BIT_SZ: usize = 6;
 pub const CNODE_ENTRY_SZ: usize = 1 << CNODE_ENTRY_BIT_SZ;
+
+/// Set in the size argument of a CNode retype to give the new CNodes the guard encoded from
+/// `RETYPE_CNODE_GUARD_SHIFT` up, in the format `CapMutate` takes. Without it they get the
+/// guard of a root CNode, which consumes all the cptr bits their radix does not, so only a
+/// root can be looked up through them.
+pub const RETYPE_CNODE_GUARD: usize = 1 << 8;
+pub const RETYPE_CNODE_GUARD_SHIFT: usize = 9;
+
+/// Access rights carried by a capability. A derived capability can only have
+/// fewer rights than its source.
+///
+/// For endpoints READ allows receiving, WRITE allows sending and GRANT allows
+/// transferring capabilities along with a message. For frames READ and WRITE
+/// limit how the frame can be mapped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CapRights(usize);
+
+impl CapRights {
+    pub const NONE: Self = Self(0b000);
+    pub const READ: Self = Self(0b001);
+    pub const WRITE: Self = Self(0b010);
+    pub const GRANT: Self = Self(0b100);
+    pub const ALL: Self = Self(0b111);
+
+    pub const fn from_bits(bits: usize) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+
+    pub const fn bits(&self) -> usize {
+        self.0
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(&self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl core::ops::BitOr for CapRights {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+impl core::ops::BitAnd for CapRights {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        self.intersection(other)
+    }
+}
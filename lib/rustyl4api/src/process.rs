@@ -1,6 +1,13 @@
 pub const PROCESS_ROOT_CNODE_SIZE: usize = 2048;
 pub const PROCESS_MAIN_THREAD_STACK_TOP: usize = 0x8000000;
 pub const PROCESS_MAIN_THREAD_STACK_PAGES: usize = 4;
+/// How far the main thread stack may grow when the process pages it on demand.
+pub const PROCESS_MAIN_THREAD_STACK_MAX_PAGES: usize = 256;
+/// The IPC buffer of the main thread sits right above its stack.
+pub const PROCESS_MAIN_THREAD_IPC_BUFFER: usize = PROCESS_MAIN_THREAD_STACK_TOP;
+/// Shared regions a parent maps in a child without an address of their own start here, well
+/// below the heap.
+pub const PROCESS_SHM_BASE: usize = 0x4000000000;
 
 #[repr(usize)]
 pub enum ProcessCSpace {
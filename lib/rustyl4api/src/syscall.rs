@@ -3,6 +3,7 @@ use core::convert::{From, TryFrom};
 
 use crate::error::{SysErrno, SysError, SysResult};
 use crate::ipc::IpcMessageType;
+use crate::utils::MASK;
 use num_traits::FromPrimitive;
 
 #[repr(C)]
@@ -12,19 +13,42 @@ pub enum SyscallOp {
     DebugPrint,
     CapIdentify,
     CapCopy,
+    CapMint,
+    CapMove,
+    CapMutate,
+    CapRotate,
     CNodeDelete,
+    CapRevoke,
     Retype,
+    UntypedReset,
     TcbConfigure,
     TcbResume,
-    TcbSetRegisters,
+    TcbSuspend,
+    ThreadExit,
+    TcbReadRegisters,
+    TcbWriteRegisters,
+    TcbBindNotification,
+    TcbSetPriority,
+    TcbSetMaxControlledPriority,
+    TcbSetTimeSlice,
+    TcbSetAffinity,
     EndpointSend,
     EndpointRecv,
     EndpointCall,
     EndpointReply,
     EndpointReplyRecv,
+    EndpointNBSend,
+    EndpointNBRecv,
+    SaveCaller,
+    NotificationSignal,
+    NotificationWait,
+    NotificationPoll,
     RamMap,
     RamUnmap,
+    RamRemap,
     VTableMap,
+    VTableUnmap,
+    VTableTeardown,
     MonitorMintUntyped,
     MonitorInsertTcbToCpu,
     InterruptAttachIrq,
@@ -34,26 +58,44 @@ pub enum SyscallOp {
 pub struct MsgInfo {
     pub label: SyscallOp,
     pub msglen: usize,
-    pub cap_transfer: bool,
+    /// Capabilities sent, or slots offered to receive them
+    pub caps: usize,
+    pub timeout: usize,
 }
 
+const TIMEOUT_BITS: usize = 46;
+
 impl MsgInfo {
     pub const fn new(label: SyscallOp, msglen: usize) -> Self {
         Self {
             label,
             msglen,
-            cap_transfer: false,
+            caps: 0,
+            timeout: 0,
         }
     }
 
-    pub const fn new_ipc(label: SyscallOp, msglen: usize, cap_transfer: bool) -> Self {
+    pub const fn new_ipc(label: SyscallOp, msglen: usize, caps: usize) -> Self {
         Self {
             label,
             msglen,
-            cap_transfer,
+            caps,
+            timeout: 0,
         }
     }
 
+    /// Give up a blocking Recv or Call after `ticks` generic timer ticks. 0 blocks forever.
+    pub const fn with_timeout(self, ticks: usize) -> Self {
+        Self {
+            timeout: ticks & MASK!(TIMEOUT_BITS),
+            ..self
+        }
+    }
+
+    pub const fn get_timeout(&self) -> usize {
+        self.timeout
+    }
+
     pub fn get_label(&self) -> SyscallOp {
         self.label
     }
@@ -65,16 +107,16 @@ impl MsgInfo {
 
 /// MsgInfo layout
 /// -----------------------------------------------
-/// |  label  |msglen|C|                          |
-/// |    8    |  4   |1|                          |
+/// |  label  |msglen|caps|        timeout        |
+/// |    8    |  7   | 3  |          46           |
 /// -----------------------------------------------
-/// C: Cap transfer
 ///
 impl From<MsgInfo> for usize {
     fn from(info: MsgInfo) -> Self {
         (info.label as usize) << 56
-            | (info.msglen as usize) << 52
-            | (info.cap_transfer as usize) << 51
+            | (info.msglen as usize) << 49
+            | (info.caps as usize) << 46
+            | info.timeout
     }
 }
 
@@ -83,13 +125,15 @@ impl TryFrom<usize> for MsgInfo {
 
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         let label = SyscallOp::from_usize(value >> 56).ok_or(SysError::InvalidValue)?;
-        let msglen = (value >> 52) & 0b1111;
-        let cap_transfer = ((value >> 51) & 0b1) == 1;
+        let msglen = (value >> 49) & 0b1111111;
+        let caps = (value >> 46) & 0b111;
+        let timeout = value & MASK!(TIMEOUT_BITS);
 
         Ok(Self {
             label,
             msglen,
-            cap_transfer,
+            caps,
+            timeout,
         })
     }
 }
@@ -98,7 +142,8 @@ impl TryFrom<usize> for MsgInfo {
 pub struct RespInfo {
     pub msgtype: IpcMessageType,
     pub msglen: usize,
-    pub cap_transfer: bool,
+    /// Capabilities that arrived with the message
+    pub caps: usize,
     pub need_reply: bool,
     pub badged: bool,
     pub errno: SysErrno,
@@ -108,14 +153,14 @@ impl RespInfo {
     pub const fn ipc_resp(
         err: SysError,
         msglen: usize,
-        cap_transfer: bool,
+        caps: usize,
         need_reply: bool,
         badged: bool,
     ) -> Self {
         Self {
             msgtype: IpcMessageType::Message,
             msglen,
-            cap_transfer,
+            caps,
             need_reply,
             badged,
             errno: err.errno(),
@@ -126,7 +171,7 @@ impl RespInfo {
         Self {
             msgtype: IpcMessageType::Message,
             msglen: length,
-            cap_transfer: false,
+            caps: 0,
             need_reply: false,
             badged: false,
             errno: err.errno(),
@@ -137,7 +182,7 @@ impl RespInfo {
         Self {
             msgtype: IpcMessageType::Notification,
             msglen: 1,
-            cap_transfer: false,
+            caps: 0,
             need_reply: false,
             badged: false,
             errno: SysErrno::OK,
@@ -148,7 +193,7 @@ impl RespInfo {
         Self {
             msgtype: IpcMessageType::Fault,
             msglen: length,
-            cap_transfer: false,
+            caps: 0,
             need_reply: true,
             badged: badge,
             errno: SysErrno::OK,
@@ -162,21 +207,20 @@ impl RespInfo {
 
 /// MsgInfo layout
 /// -----------------------------------------------
-/// |type|msglen|C|R|B| errno |                   |
-/// |  2 |  4   |1|1|1|   6   |                   |
+/// |type|msglen|caps|R|B| errno |                |
+/// |  2 |  7   | 3  |1|1|   6   |                |
 /// -----------------------------------------------
-/// C: Cap transfer
 /// R: Need Reply
 /// B: Badged
 ///
 impl From<RespInfo> for usize {
     fn from(info: RespInfo) -> Self {
         (info.msgtype as usize) << 62
-            | (info.msglen as usize) << 58
-            | (info.cap_transfer as usize) << 57
-            | (info.need_reply as usize) << 56
-            | (info.badged as usize) << 55
-            | (info.errno as usize) << 49
+            | (info.msglen as usize) << 55
+            | (info.caps as usize) << 52
+            | (info.need_reply as usize) << 51
+            | (info.badged as usize) << 50
+            | (info.errno as usize) << 44
     }
 }
 
@@ -185,16 +229,16 @@ impl TryFrom<usize> for RespInfo {
 
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         let msgtype = IpcMessageType::from_usize(value >> 62).ok_or(SysError::InvalidValue)?;
-        let msglen = (value >> 58) & 0b1111;
-        let cap_transfer = (value >> 57) & 0b1 == 1;
-        let need_reply = (value >> 56) & 0b1 == 1;
-        let badged = (value >> 55) & 0b1 == 1;
-        let errno = SysErrno::from_usize((value >> 49) & 0b111111).ok_or(SysError::InvalidValue)?;
+        let msglen = (value >> 55) & 0b1111111;
+        let caps = (value >> 52) & 0b111;
+        let need_reply = (value >> 51) & 0b1 == 1;
+        let badged = (value >> 50) & 0b1 == 1;
+        let errno = SysErrno::from_usize((value >> 44) & 0b111111).ok_or(SysError::InvalidValue)?;
 
         Ok(Self {
             msgtype,
             msglen,
-            cap_transfer,
+            caps,
             need_reply,
             badged,
             errno,
@@ -225,7 +269,8 @@ pub fn syscall(
     let retinfo = RespInfo::try_from(ret).unwrap();
     match retinfo.errno {
         SysErrno::OK => {
-            let retlen = retinfo.get_length();
+            /* Words of a long message past the registers are left in the IPC buffer */
+            let retlen = retinfo.get_length().min(args.len() - 1);
             Ok((retinfo, &mut args[..retlen], badge))
         }
         SysErrno::CSpaceNotFound => Err(SysError::CSpaceNotFound),
@@ -245,6 +290,10 @@ pub fn syscall(
         }),
         SysErrno::VSpacePermissionError => Err(SysError::VSpacePermissionError),
         SysErrno::InvalidValue => Err(SysError::InvalidValue),
+        SysErrno::InsufficientRights => Err(SysError::InsufficientRights),
+        SysErrno::RevokeFirst => Err(SysError::RevokeFirst),
+        SysErrno::IpcAborted => Err(SysError::IpcAborted),
+        SysErrno::Timeout => Err(SysError::Timeout),
         SysErrno::SizeTooSmall => Err(SysError::SizeTooSmall),
     }
 }
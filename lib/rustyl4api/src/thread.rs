@@ -1,6 +1,22 @@
 use crate::utils::MASK;
 use core::arch::asm;
 
+/// Register state of a thread as `TcbReadRegisters` and `TcbWriteRegisters` copy it, through the
+/// start of the caller's IPC buffer. Only the NZCV flags of `spsr` can be written.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct UserContext {
+    pub x: [usize; 31],
+    pub sp: usize,
+    pub elr: usize,
+    pub spsr: usize,
+    pub tpidr: usize,
+}
+
+/// Set in `UserContext::spsr` to have the thread take a `Step` fault after its next instruction.
+/// The kernel clears it again when the fault is taken.
+pub const SPSR_SS: usize = 1 << 21;
+
 fn tpidrro_el0() -> usize {
     let tpidrro: usize;
 
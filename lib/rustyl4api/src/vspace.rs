@@ -1,3 +1,9 @@
 pub use vspace::permission::Permission;
 pub const FRAME_BIT_SIZE: usize = 12;
-pub const FRAME_SIZE: usize = 1 << FRAME_BIT_SIZE;
\ No newline at end of file
+pub const FRAME_SIZE: usize = 1 << FRAME_BIT_SIZE;
+/* A Ram cap of this size maps as a single PD (level 2) block entry */
+pub const LARGE_FRAME_BIT_SIZE: usize = 21;
+pub const LARGE_FRAME_SIZE: usize = 1 << LARGE_FRAME_BIT_SIZE;
+/* A Ram cap of this size maps as a single PUD (level 3) block entry */
+pub const HUGE_FRAME_BIT_SIZE: usize = 30;
+pub const HUGE_FRAME_SIZE: usize = 1 << HUGE_FRAME_BIT_SIZE;
@@ -33,7 +33,7 @@ pub enum AccessPermission {
 
 impl Into<AccessPermission> for Permission {
     fn into(self) -> AccessPermission {
-        if self.contains(Permission::READABLE & Permission::WRITABLE) {
+        if self.contains(Permission::READABLE | Permission::WRITABLE) {
             AccessPermission::ReadWrite
         } else if self.contains(Permission::READABLE) {
             AccessPermission::ReadOnly
@@ -88,3 +88,45 @@ pub fn invalidate_local_tlb_asid(asid: usize) {
     dsb();
     isb();
 }
+
+/// Invalidate every EL1&0 translation on this core.
+pub fn invalidate_local_tlb_all() {
+    dsb();
+    unsafe { asm!("tlbi vmalle1", options(nomem)) }
+    dsb();
+    isb();
+}
+
+/// Invalidate every translation under `asid` on every core in the inner shareable domain.
+pub fn invalidate_tlb_asid_is(asid: usize) {
+    let operand = (asid & MASK!(16)) << 48;
+    unsafe {
+        asm!(
+            "
+            dsb ishst
+            tlbi aside1is, {operand}
+            dsb ish
+        ",
+            operand = in(reg) operand,
+            options(nomem)
+        )
+    }
+    isb();
+}
+
+/// Invalidate the translation of `vaddr` under `asid` on every core in the inner shareable domain.
+pub fn invalidate_tlb_va_asid_is(asid: usize, vaddr: usize) {
+    let operand = (asid & MASK!(16)) << 48 | (vaddr >> 12) & MASK!(44);
+    unsafe {
+        asm!(
+            "
+            dsb ishst
+            tlbi vae1is, {operand}
+            dsb ish
+        ",
+            operand = in(reg) operand,
+            options(nomem)
+        )
+    }
+    isb();
+}
@@ -40,6 +40,11 @@ impl Permission {
     pub fn is_executable(&self) -> bool {
         *self & Self::EXECUTABLE == Self::EXECUTABLE
     }
+
+    /// Writable and executable at once, which mappings are never allowed to be.
+    pub fn is_writable_executable(&self) -> bool {
+        self.is_writable() && self.is_executable()
+    }
 }
 
 impl Into<usize> for Permission {
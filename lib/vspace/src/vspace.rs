@@ -62,6 +62,47 @@ impl<'a, T: TopLevel, const O: usize> VSpace<'a, T, O> {
         Ok(())
     }
 
+    /// Replace the valid page or block entry at `vaddr`, e.g. to change its permission. The
+    /// caller invalidates the TLB.
+    pub fn remap_entry<L>(&mut self, vaddr: VirtAddr<O>, entry: L::EntryType) -> Result<()>
+    where
+        L: TableLevel,
+    {
+        let slot = self.lookup_slot_mut::<L>(vaddr)?;
+        if !slot.is_valid() {
+            return Err(Error::SlotEmpty);
+        }
+        *slot = Entry::new(entry);
+        clean_dcache_by_va(slot as *const _ as usize);
+        Ok(())
+    }
+
+    /// Invalidate every entry of every level, starting from the leaves. The tables themselves
+    /// are left to whoever owns them.
+    pub fn teardown(&mut self) {
+        for i in 0..Level4::TABLE_ENTRIES {
+            let pgde: &mut Entry<Level4> = self.root[i].transmute_mut();
+            let mut pud = match pgde.as_table_mut::<O>() {
+                Some(pud) => pud,
+                None => continue,
+            };
+            for j in 0..Level3::TABLE_ENTRIES {
+                let mut pd = match pud[j].as_table_mut::<O>() {
+                    Some(pd) => pd,
+                    None => continue,
+                };
+                for k in 0..Level2::TABLE_ENTRIES {
+                    if let Some(mut pt) = pd[k].as_table_mut::<O>() {
+                        clear_table(&mut pt);
+                    }
+                }
+                clear_table(&mut pd);
+            }
+            clear_table(&mut pud);
+        }
+        clear_table(&mut self.root);
+    }
+
     pub fn paddr_of_vaddr(&self, vaddr: VirtAddr<O>) -> Option<PhysAddr> {
         let pgde = self.lookup_slot::<Level4>(vaddr).ok()?;
         if !pgde.is_table_entry() {
@@ -94,3 +135,17 @@ impl<'a, T: TopLevel, const O: usize> VSpace<'a, T, O> {
         return Some(pte.paddr());
     }
 }
+
+fn clear_table<L: TableLevel>(table: &mut Table<L>) {
+    const CACHE_LINE_SIZE: usize = 64;
+
+    for i in 0..L::TABLE_ENTRIES {
+        table[i] = Entry::invalid_entry();
+    }
+
+    let start = &table[0] as *const _ as usize;
+    let end = start + L::TABLE_ENTRIES * core::mem::size_of::<Entry<L>>();
+    for line in (start..end).step_by(CACHE_LINE_SIZE) {
+        clean_dcache_by_va(line);
+    }
+}
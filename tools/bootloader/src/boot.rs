@@ -28,15 +28,48 @@ unsafe extern "C" fn _start() {
     const CONTROL_C: usize = 2; // Cacheability control, for data accesses
     const CONTROL_M: usize = 0; // MMU enable
                                 // const  CONTROL_A : usize = 1;  // Alignment check
-    const SCTLR_VALUE: usize = BIT!(CONTROL_I) | BIT!(CONTROL_C) | BIT!(CONTROL_M); // TODO: enable alignment check
+    const CONTROL_UCI: usize = 26; // EL0 cache maintenance by VA, to patch code in user space
+    const SCTLR_VALUE: usize =
+        BIT!(CONTROL_I) | BIT!(CONTROL_C) | BIT!(CONTROL_M) | BIT!(CONTROL_UCI); // TODO: enable alignment check
     asm!(r#"
     msr     daifset, 0xf
     mrs     x0, mpidr_el1        // check core id, only one core is used.
     mov     x1, #0xc1000000
     bic     x0, x0, x1
     cbz     x0, zero_bss
-hang:
-    b       hang
+
+    /* Secondary cores get here once the kernel releases them, and enter it like core 0 did */
+secondary_wait:
+    ldr     x1, =KERNEL_ENTRY
+    ldr     x2, [x1]
+    cbnz    x2, secondary_start
+    wfe
+    b       secondary_wait
+
+secondary_start:
+    ldr     x1, =KERNEL_VSPACE
+    ldr     x3, [x1]
+
+    mov     x0, #(1 << 31)
+    msr     hcr_el2, x0          // set el1 to 64 bit
+    mov     x0, #(15 << 6 | 0b01 << 2 | 1) // DAIF masked, EL1, SpSelx
+    msr     spsr_el2, x0
+
+    ldr     x4, ={TCR_VALUE}
+    msr     tcr_el1, x4
+    msr     ttbr1_el1, x3
+    isb
+
+    ldr     x0, ={SCTLR_VALUE}
+    msr     sctlr_el1, x0
+    isb
+    tlbi    vmalle1
+    dsb     sy
+    isb
+
+    msr     elr_el2, x2
+    mov     x0, xzr              // BootInfo is only handed to core 0
+    eret
 
 zero_bss:
     // load the start address and number of bytes in BSS section
@@ -121,6 +154,15 @@ use vspace::{
 type VSpace<'a> = vspace::arch::VSpace<'a, 0>;
 
 const KERNEL_OFFSET: usize = 0xffff0000_00000000;
+
+/*
+ * Where secondary cores find the kernel. They read it with the MMU and caches off, so the lines
+ * are cleaned to the point of coherency once written.
+ */
+#[no_mangle]
+static mut KERNEL_ENTRY: usize = 0;
+#[no_mangle]
+static mut KERNEL_VSPACE: usize = 0;
 static INIT_FS: &[u8] = include_bytes!("../build/initfs.cpio");
 
 struct KernelLoader<'a> {
@@ -324,6 +366,15 @@ fn map_kernel_virtual_address_space<const O: usize>(
     }
 }
 
+/* Write back the cache line holding `vaddr` to memory */
+unsafe fn clean_dcache_line(vaddr: usize) {
+    asm!("
+    dc      cvac, {0}
+    dsb     sy
+    ",
+    in(reg) vaddr)
+}
+
 unsafe fn jump_to_kernel(kernel_start: usize, bi_frame: *mut u8) -> ! {
     // ABI
     // x0: BootInfo frame base address
@@ -422,5 +473,12 @@ pub extern "C" fn bootloader_main() -> ! {
         vspace::arch::mmu::install_kernel_vspace(PhysAddr(vspace_root_paddr as usize));
     }
 
+    unsafe {
+        KERNEL_VSPACE = vspace_root_paddr as usize;
+        KERNEL_ENTRY = kernel_elf.entry_point() as usize;
+        clean_dcache_line(&KERNEL_VSPACE as *const usize as usize);
+        clean_dcache_line(&KERNEL_ENTRY as *const usize as usize);
+    }
+
     unsafe { jump_to_kernel(kernel_elf.entry_point() as usize, bi_frame) }
 }
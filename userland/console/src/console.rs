@@ -127,7 +127,9 @@ pub async fn console_server_init() {
         .into_alt(Function::Alt5);
 
     let uart_ram_cap = crate::request_memory(0x3f215000, 4096, true).await.unwrap();
-    let uart_base = gsm!().insert_ram_at(uart_ram_cap, 0, Permission::writable());
+    let uart_base = gsm!()
+        .insert_ram_at(uart_ram_cap, 0, Permission::writable())
+        .unwrap();
 
     let mut uart = MiniUart::new(uart_base as usize);
     uart.initialize(115200);
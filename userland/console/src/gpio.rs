@@ -26,7 +26,9 @@ pub static GPIO_SERVER: Mutex<Option<GpioServer>> = Mutex::new(None);
 
 pub async fn init_gpio_server() {
     let gpio_ram_cap = crate::request_memory(0x3f200000, 4096, true).await.unwrap();
-    let gpio_base = gsm!().insert_ram_at(gpio_ram_cap, 0, Permission::writable());
+    let gpio_base = gsm!()
+        .insert_ram_at(gpio_ram_cap, 0, Permission::writable())
+        .unwrap();
 
     *GPIO_SERVER.lock() = Some(GpioServer::new(gpio_base as usize));
 }
@@ -80,7 +80,7 @@ async fn main() {
 
     let ep_server = &*EP_SERVER;
     let con = console::console();
-    let badged_ep = ep_server.handle_notification(Interrupt::Aux as usize, con.clone()).unwrap();
+    let irq_ntfn = ep_server.handle_notification(Interrupt::Aux as usize, con.clone()).unwrap();
     let irq_cap = ns_client()
         .await
         .lock()
@@ -88,7 +88,7 @@ async fn main() {
         .await
         .unwrap();
     irq_cap
-        .attach_ep_to_irq(badged_ep.ep().slot.slot(), Interrupt::Aux as usize)
+        .attach_irq(irq_ntfn.slot.slot(), Interrupt::Aux as usize)
         .unwrap();
 
     let receiver = MsgReceiver::new(&EP_SERVER);
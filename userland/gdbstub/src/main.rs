@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod packet;
+mod stub;
+
+use naive::ns::ns_client;
+use naive::objects::RamCap;
+use naive::space_manager::gsm;
+use pi::pl011::Pl011;
+use rustyl4api::vspace::Permission;
+
+use log::trace;
+
+/* GDB talks to the stub over UART0, QEMU's first serial port */
+const PL011_PADDR: usize = 0x3F201000;
+const BAUD_RATE: usize = 115200;
+
+pub async fn request_memory(paddr: usize, size: usize, maybe_device: bool) -> Result<RamCap, ()> {
+    let client = ns_client();
+    let cap = client
+        .await
+        .lock()
+        .request_memory(paddr, size, maybe_device)
+        .await;
+    Ok(cap.unwrap())
+}
+
+#[naive::main]
+async fn main() {
+    trace!("gdbstub started");
+
+    let uart_ram_cap = request_memory(PL011_PADDR, 4096, true).await.unwrap();
+    let uart_base = gsm!()
+        .insert_ram_at(uart_ram_cap, 0, Permission::writable())
+        .unwrap();
+    let mut uart = Pl011::new(uart_base as usize);
+    uart.initialize(BAUD_RATE);
+
+    let mut stub = stub::GdbStub::new(packet::Connection::new(uart));
+    stub.run().await;
+}
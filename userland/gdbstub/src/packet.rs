@@ -0,0 +1,140 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use pi::pl011::Pl011;
+
+/// Byte GDB sends out of band to interrupt a running target.
+pub const INTERRUPT: u8 = 0x03;
+
+/// GDB Remote Serial Protocol framing over a polled UART: `$<data>#<checksum>`, acknowledged
+/// with `+` or `-`.
+pub struct Connection {
+    uart: Pl011,
+}
+
+impl Connection {
+    pub fn new(uart: Pl011) -> Self {
+        Self { uart }
+    }
+
+    /// Whether GDB sent an interrupt. Anything else read meanwhile is dropped.
+    pub fn poll_interrupt(&mut self) -> bool {
+        while self.uart.has_byte() {
+            if self.uart.read_byte() == INTERRUPT {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Block until a packet with a valid checksum arrives and acknowledge it. A bare interrupt
+    /// byte is returned as a packet of its own.
+    pub fn read_packet(&mut self) -> Vec<u8> {
+        loop {
+            match self.uart.read_byte() {
+                b'$' => {}
+                INTERRUPT => return alloc::vec![INTERRUPT],
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            let mut sum: u8 = 0;
+            loop {
+                match self.uart.read_byte() {
+                    b'#' => break,
+                    b => {
+                        sum = sum.wrapping_add(b);
+                        data.push(b);
+                    }
+                }
+            }
+            let hi = self.uart.read_byte();
+            let lo = self.uart.read_byte();
+
+            if decode_hex_byte(hi, lo) == Some(sum) {
+                self.uart.write_byte(b'+');
+                return unescape(data);
+            }
+            self.uart.write_byte(b'-');
+        }
+    }
+
+    /// Send `data` as a packet until GDB acknowledges it.
+    pub fn write_packet(&mut self, data: &[u8]) {
+        let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        loop {
+            self.uart.write_byte(b'$');
+            for b in data {
+                self.uart.write_byte(*b);
+            }
+            self.uart.write_byte(b'#');
+            self.uart.write_byte(HEX_DIGITS[(sum >> 4) as usize]);
+            self.uart.write_byte(HEX_DIGITS[(sum & 0xf) as usize]);
+
+            loop {
+                match self.uart.read_byte() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => continue,
+                }
+            }
+        }
+    }
+}
+
+/* `}` escapes the next byte, which is xor-ed with 0x20 */
+fn unescape(data: Vec<u8>) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(data.len());
+    let mut iter = data.into_iter();
+    while let Some(b) = iter.next() {
+        if b == b'}' {
+            if let Some(escaped) = iter.next() {
+                ret.push(escaped ^ 0x20);
+            }
+        } else {
+            ret.push(b);
+        }
+    }
+    ret
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn decode_hex_byte(hi: u8, lo: u8) -> Option<u8> {
+    Some(hex_value(hi)? << 4 | hex_value(lo)?)
+}
+
+/// Decode a hex string like `4142` into bytes.
+pub fn decode_hex(s: &[u8]) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    s.chunks(2).map(|c| decode_hex_byte(c[0], c[1])).collect()
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        s.push(HEX_DIGITS[(b >> 4) as usize] as char);
+        s.push(HEX_DIGITS[(b & 0xf) as usize] as char);
+    }
+    s
+}
+
+/// Parse a big-endian hex number, as used for addresses and lengths.
+pub fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0, |acc, c| Some(acc << 4 | hex_value(*c)? as usize))
+}
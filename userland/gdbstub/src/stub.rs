@@ -0,0 +1,460 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use naive::fs::File;
+use naive::io::AsyncReadExt;
+use naive::ipc::{Fault, IpcMessage};
+use naive::objects::{EndpointObj, EpCap, ReplyCap};
+use naive::process::{Child, ProcessBuilder};
+use naive::space_manager::{copy_cap, gsm, NAME_SERVICE_CAP, STDERR_CAP, STDIN_CAP, STDOUT_CAP};
+use rustyl4api::error::SysError;
+use rustyl4api::fault::UserExceptionKind;
+use rustyl4api::thread::{UserContext, SPSR_SS};
+
+use log::{error, info};
+
+use crate::packet::{decode_hex, encode_hex, parse_hex, Connection, INTERRUPT};
+
+/* `brk #0`, the software breakpoint GDB uses on AArch64 */
+const BRK_INSN: [u8; 4] = 0xd4200000u32.to_le_bytes();
+
+/* How long to wait between polls of the UART and the fault endpoint while the target runs */
+const POLL_INTERVAL_MS: u64 = 10;
+
+/* Largest memory transfer, so that the hex encoded reply fits in `PacketSize` */
+const MAX_MEMORY_XFER: usize = 0x7f0;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+const SIGSYS: u8 = 31;
+
+/* x0-x30, sp, pc and cpsr, numbered as in GDB's org.gnu.gdb.aarch64.core feature */
+const NUM_REGS: usize = 34;
+
+/// The process being debugged. Only its main thread is under control of the stub.
+struct Inferior {
+    child: Child,
+    /// Answers the fault the thread is blocked on. `None` if the thread is suspended instead.
+    reply: Option<ReplyCap>,
+    /// Original instructions under the planted breakpoints.
+    breakpoints: BTreeMap<usize, [u8; 4]>,
+}
+
+/// A GDB server for processes it spawns itself, through `vRun` in extended mode. It receives
+/// their faults on its own endpoint and reports them as stops.
+pub struct GdbStub {
+    conn: Connection,
+    fault_ep: EpCap,
+    inferior: Option<Inferior>,
+    last_signal: u8,
+}
+
+impl GdbStub {
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            fault_ep: gsm!().alloc_object::<EndpointObj>(12).unwrap(),
+            inferior: None,
+            last_signal: SIGTRAP,
+        }
+    }
+
+    pub async fn run(&mut self) -> ! {
+        loop {
+            let packet = self.conn.read_packet();
+            if let Some(resp) = self.handle_packet(&packet).await {
+                self.conn.write_packet(resp.as_bytes());
+            }
+        }
+    }
+
+    /* `None` for packets that take no reply */
+    async fn handle_packet(&mut self, packet: &[u8]) -> Option<String> {
+        let (cmd, args) = match packet.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return Some(String::new()),
+        };
+
+        let resp = match cmd {
+            b'?' => Some(self.stop_reply()),
+            b'!' | b'H' | b'T' => Some("OK".into()),
+            b'g' => self.read_registers(),
+            b'G' => self.write_registers(args),
+            b'p' => self.read_register(args),
+            b'P' => self.write_register(args),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'Z' => self.insert_breakpoint(args),
+            b'z' => self.remove_breakpoint(args),
+            b'c' => self.resume(args, false).await,
+            b's' => self.resume(args, true).await,
+            b'D' => self.detach(),
+            b'k' => {
+                self.kill();
+                return None;
+            }
+            b'q' => Some(self.query(args)),
+            b'v' => self.handle_v(args).await,
+            /* The target is stopped already */
+            INTERRUPT => return None,
+            _ => Some(String::new()),
+        };
+        Some(resp.unwrap_or_else(|| "E01".into()))
+    }
+
+    fn stop_reply(&self) -> String {
+        if self.inferior.is_some() {
+            format!("S{:02x}", self.last_signal)
+        } else {
+            "W00".into()
+        }
+    }
+
+    fn query(&self, args: &[u8]) -> String {
+        if args.starts_with(b"Supported") {
+            "PacketSize=1000;qXfer:features:read+".into()
+        } else if args == b"Attached" {
+            "0".into()
+        } else if args == b"C" {
+            "QC1".into()
+        } else if args == b"fThreadInfo" {
+            (if self.inferior.is_some() { "m1" } else { "l" }).into()
+        } else if args == b"sThreadInfo" {
+            "l".into()
+        } else if let Some(range) = args.strip_prefix(b"Xfer:features:read:target.xml:") {
+            xfer_target_xml(range).unwrap_or_else(|| "E01".into())
+        } else {
+            String::new()
+        }
+    }
+
+    async fn handle_v(&mut self, args: &[u8]) -> Option<String> {
+        if let Some(args) = args.strip_prefix(b"Run;") {
+            let path = args.split(|c| *c == b';').next()?;
+            let path = String::from_utf8(decode_hex(path)?).ok()?;
+            self.spawn(&path).await
+        } else if args.starts_with(b"Kill") {
+            self.kill();
+            Some("OK".into())
+        } else {
+            Some(String::new())
+        }
+    }
+
+    /* Start `path` suspended at its entry point. A running inferior is killed first */
+    async fn spawn(&mut self, path: &str) -> Option<String> {
+        self.kill();
+
+        let mut file = File::open(path).await.ok()?;
+        let mut elf = Vec::new();
+        file.read_to_end(&mut elf).await.ok()?;
+
+        let child = ProcessBuilder::new(&elf)
+            .stdin(copy_cap(&STDIN_CAP)?)
+            .stdout(copy_cap(&STDOUT_CAP)?)
+            .stderr(copy_cap(&STDERR_CAP)?)
+            .name_server(copy_cap(&NAME_SERVICE_CAP)?)
+            .fault_handler(copy_cap(&self.fault_ep)?)
+            .suspended()
+            .spawn()
+            .ok()?;
+        info!("debugging {}", path);
+
+        self.inferior = Some(Inferior {
+            child,
+            reply: None,
+            breakpoints: BTreeMap::new(),
+        });
+        self.last_signal = SIGTRAP;
+        Some(self.stop_reply())
+    }
+
+    fn kill(&mut self) {
+        if let Some(inferior) = self.inferior.take() {
+            inferior.child.kill().unwrap_or(());
+        }
+    }
+
+    fn detach(&mut self) -> Option<String> {
+        let inferior = self.inferior.as_mut()?;
+        for (addr, insn) in core::mem::take(&mut inferior.breakpoints) {
+            inferior.child.write_memory(addr, &insn).ok()?;
+        }
+        self.continue_inferior(false).ok()?;
+        self.inferior = None;
+        Some("OK".into())
+    }
+
+    fn context(&self) -> Option<UserContext> {
+        self.inferior.as_ref()?.child.tcb().read_registers().ok()
+    }
+
+    fn set_context(&self, ctx: &UserContext) -> Option<()> {
+        self.inferior.as_ref()?.child.tcb().write_registers(ctx).ok()
+    }
+
+    fn read_registers(&self) -> Option<String> {
+        let ctx = self.context()?;
+        let bytes: Vec<u8> = (0..NUM_REGS).flat_map(|n| register_bytes(&ctx, n)).collect();
+        Some(encode_hex(&bytes))
+    }
+
+    fn write_registers(&self, args: &[u8]) -> Option<String> {
+        let bytes = decode_hex(args)?;
+        let mut ctx = self.context()?;
+        let mut offset = 0;
+        for n in 0..NUM_REGS {
+            let len = register_size(n);
+            set_register(&mut ctx, n, bytes.get(offset..offset + len)?);
+            offset += len;
+        }
+        self.set_context(&ctx)?;
+        Some("OK".into())
+    }
+
+    fn read_register(&self, args: &[u8]) -> Option<String> {
+        let n = parse_hex(args)?;
+        if n >= NUM_REGS {
+            return None;
+        }
+        Some(encode_hex(&register_bytes(&self.context()?, n)))
+    }
+
+    fn write_register(&self, args: &[u8]) -> Option<String> {
+        let mut parts = args.splitn(2, |c| *c == b'=');
+        let n = parse_hex(parts.next()?)?;
+        let bytes = decode_hex(parts.next()?)?;
+        if n >= NUM_REGS || bytes.len() != register_size(n) {
+            return None;
+        }
+        let mut ctx = self.context()?;
+        set_register(&mut ctx, n, &bytes);
+        self.set_context(&ctx)?;
+        Some("OK".into())
+    }
+
+    /* Breakpoints are hidden from memory reads, GDB wants to see the original code */
+    fn read_memory(&self, args: &[u8]) -> Option<String> {
+        let inferior = self.inferior.as_ref()?;
+        let (addr, len) = parse_addr_len(args)?;
+        let mut buf = alloc::vec![0u8; len.min(MAX_MEMORY_XFER)];
+        inferior.child.read_memory(addr, &mut buf).ok()?;
+
+        let end = addr + buf.len();
+        for (bp, insn) in inferior.breakpoints.range(addr.saturating_sub(3)..end) {
+            for (i, b) in insn.iter().enumerate() {
+                if let Some(byte) = (bp + i).checked_sub(addr).and_then(|o| buf.get_mut(o)) {
+                    *byte = *b;
+                }
+            }
+        }
+        Some(encode_hex(&buf))
+    }
+
+    /* A write over a breakpoint updates the instruction restored when it is removed */
+    fn write_memory(&mut self, args: &[u8]) -> Option<String> {
+        let inferior = self.inferior.as_mut()?;
+        let mut parts = args.splitn(2, |c| *c == b':');
+        let (addr, len) = parse_addr_len(parts.next()?)?;
+        let data = decode_hex(parts.next()?)?;
+        if data.len() != len {
+            return None;
+        }
+        inferior.child.write_memory(addr, &data).ok()?;
+
+        let end = addr + data.len();
+        for (bp, insn) in inferior.breakpoints.range_mut(addr.saturating_sub(3)..end) {
+            for (i, b) in insn.iter_mut().enumerate() {
+                if let Some(byte) = (bp + i).checked_sub(addr).and_then(|o| data.get(o)) {
+                    *b = *byte;
+                }
+            }
+            inferior.child.write_memory(*bp, &BRK_INSN).ok()?;
+        }
+        Some("OK".into())
+    }
+
+    /* Only software breakpoints, `Z0,addr,kind`. Others are left for GDB to emulate */
+    fn insert_breakpoint(&mut self, args: &[u8]) -> Option<String> {
+        let addr = match parse_breakpoint(args) {
+            Some(addr) => addr,
+            None => return Some(String::new()),
+        };
+        let inferior = self.inferior.as_mut()?;
+        if !inferior.breakpoints.contains_key(&addr) {
+            let mut insn = [0u8; 4];
+            inferior.child.read_memory(addr, &mut insn).ok()?;
+            inferior.child.write_memory(addr, &BRK_INSN).ok()?;
+            inferior.breakpoints.insert(addr, insn);
+        }
+        Some("OK".into())
+    }
+
+    fn remove_breakpoint(&mut self, args: &[u8]) -> Option<String> {
+        let addr = match parse_breakpoint(args) {
+            Some(addr) => addr,
+            None => return Some(String::new()),
+        };
+        let inferior = self.inferior.as_mut()?;
+        if let Some(insn) = inferior.breakpoints.remove(&addr) {
+            inferior.child.write_memory(addr, &insn).ok()?;
+        }
+        Some("OK".into())
+    }
+
+    /* `c[addr]` and `s[addr]`. The reply is sent when the thread stops again */
+    async fn resume(&mut self, args: &[u8], step: bool) -> Option<String> {
+        if !args.is_empty() {
+            let mut ctx = self.context()?;
+            ctx.elr = parse_hex(args)?;
+            self.set_context(&ctx)?;
+        }
+        self.continue_inferior(step).ok()?;
+        self.wait_for_stop().await;
+        Some(self.stop_reply())
+    }
+
+    /* Answer the pending fault, or resume a suspended thread */
+    fn continue_inferior(&mut self, step: bool) -> Result<(), SysError> {
+        let mut ctx = self.context().ok_or(SysError::InvalidValue)?;
+        if step {
+            ctx.spsr |= SPSR_SS;
+        } else {
+            ctx.spsr &= !SPSR_SS;
+        }
+        self.set_context(&ctx).ok_or(SysError::InvalidValue)?;
+
+        let inferior = self.inferior.as_mut().ok_or(SysError::InvalidValue)?;
+        match inferior.reply.take() {
+            Some(reply) => reply.reply(&[], Vec::new()),
+            None => inferior.child.tcb().resume(),
+        }
+    }
+
+    async fn wait_for_stop(&mut self) {
+        loop {
+            if self.conn.poll_interrupt() {
+                if let Some(inferior) = &self.inferior {
+                    inferior.child.tcb().suspend().unwrap_or(());
+                }
+                self.last_signal = SIGINT;
+                break;
+            }
+
+            match self.fault_ep.try_receive(Vec::new()) {
+                Ok(IpcMessage::Fault(msg)) => {
+                    let slot = gsm!().cspace_alloc().unwrap();
+                    if let Some(inferior) = self.inferior.as_mut() {
+                        inferior.reply = ReplyCap::save_caller(slot).ok();
+                    }
+                    self.last_signal = fault_signal(&msg.info);
+                    break;
+                }
+                Ok(msg) => error!("unexpected message on fault endpoint: {:?}", msg),
+                Err(SysError::Timeout) => naive::time::sleep_ms(POLL_INTERVAL_MS).await,
+                Err(e) => {
+                    error!("receiving fault error {:?}", e);
+                    naive::time::sleep_ms(POLL_INTERVAL_MS).await;
+                }
+            }
+        }
+
+        /* A step that ended in another fault leaves SS set */
+        if let Some(mut ctx) = self.context() {
+            if ctx.spsr & SPSR_SS != 0 {
+                ctx.spsr &= !SPSR_SS;
+                self.set_context(&ctx).unwrap_or(());
+            }
+        }
+    }
+}
+
+fn fault_signal(fault: &Fault) -> u8 {
+    match fault {
+        Fault::DataFault(_) | Fault::PrefetchFault(_) => SIGSEGV,
+        Fault::UnknownSyscall(_) => SIGSYS,
+        Fault::UserException(info) => match info.kind {
+            UserExceptionKind::UndefinedInstruction
+            | UserExceptionKind::IllegalExecutionState
+            | UserExceptionKind::TrappedFpu => SIGILL,
+            UserExceptionKind::PcAlignment | UserExceptionKind::SpAlignment => SIGBUS,
+            _ => SIGTRAP,
+        },
+    }
+}
+
+fn register_size(n: usize) -> usize {
+    if n == NUM_REGS - 1 {
+        4
+    } else {
+        8
+    }
+}
+
+fn register_bytes(ctx: &UserContext, n: usize) -> Vec<u8> {
+    match n {
+        0..=30 => ctx.x[n].to_le_bytes().to_vec(),
+        31 => ctx.sp.to_le_bytes().to_vec(),
+        32 => ctx.elr.to_le_bytes().to_vec(),
+        _ => (ctx.spsr as u32).to_le_bytes().to_vec(),
+    }
+}
+
+/* `bytes` is `register_size(n)` long */
+fn set_register(ctx: &mut UserContext, n: usize, bytes: &[u8]) {
+    let mut val = [0u8; 8];
+    val[..bytes.len()].copy_from_slice(bytes);
+    let val = usize::from_le_bytes(val);
+    match n {
+        0..=30 => ctx.x[n] = val,
+        31 => ctx.sp = val,
+        32 => ctx.elr = val,
+        _ => ctx.spsr = ctx.spsr & !0xffff_ffff | val,
+    }
+}
+
+fn parse_addr_len(args: &[u8]) -> Option<(usize, usize)> {
+    let mut parts = args.splitn(2, |c| *c == b',');
+    let addr = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?)?;
+    Some((addr, len))
+}
+
+/* Address of a `0,addr,kind` software breakpoint */
+fn parse_breakpoint(args: &[u8]) -> Option<usize> {
+    let mut parts = args.split(|c| *c == b',');
+    if parts.next()? != b"0" {
+        return None;
+    }
+    parse_hex(parts.next()?)
+}
+
+/* `offset,length` of the target description, with `m` if more follows or `l` for the last part */
+fn xfer_target_xml(range: &[u8]) -> Option<String> {
+    let (offset, len) = parse_addr_len(range)?;
+    let xml = target_xml();
+    let part = xml.get(offset.min(xml.len())..(offset + len).min(xml.len()))?;
+    let more = offset + len < xml.len();
+    Some(format!("{}{}", if more { "m" } else { "l" }, part))
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target><architecture>aarch64</architecture>\
+         <feature name=\"org.gnu.gdb.aarch64.core\">",
+    );
+    for n in 0..31 {
+        xml += &format!("<reg name=\"x{}\" bitsize=\"64\"/>", n);
+    }
+    xml += "<reg name=\"sp\" bitsize=\"64\" type=\"data_ptr\"/>\
+            <reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\"/>\
+            <reg name=\"cpsr\" bitsize=\"32\"/>\
+            </feature></target>";
+    xml
+}
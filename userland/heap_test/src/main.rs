@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate naive;
+
+use alloc::vec::Vec;
+
+use log::trace;
+
+use naive::objects::identify::{cap_identify, IdentifyResult};
+use naive::objects::RamObj;
+use naive::space_manager::{copy_cap, gsm};
+use naive::{heap_stats, untyped_stats};
+use rustyl4api::vspace::FRAME_BIT_SIZE;
+
+/* Large enough to bypass the slab pools and get frames of its own */
+const ALLOC_SIZE: usize = 1 << 20;
+const ROUNDS: usize = 16;
+
+#[naive::main]
+async fn main() {
+    trace!("heap_test started");
+
+    /* The first round may carve untypeds and page tables, every later one must reuse them */
+    let mut baseline = None;
+    for round in 0..ROUNDS {
+        let mut buf: Vec<u8> = Vec::with_capacity(ALLOC_SIZE);
+        buf.resize(ALLOC_SIZE, round as u8);
+        if buf.iter().any(|b| *b != round as u8) {
+            println!("heap_test: FAILED, round {} read back wrong data", round).await;
+            return;
+        }
+        drop(buf);
+
+        let stats = (heap_stats(), untyped_stats());
+        match baseline {
+            None => baseline = Some(stats),
+            Some(expected) if expected != stats => {
+                println!(
+                    "heap_test: FAILED, round {} left {:?}, expected {:?}",
+                    round, stats, expected
+                )
+                .await;
+                return;
+            }
+            Some(_) => {}
+        }
+    }
+
+    /*
+     * A frame freed while a copy of it is still around, e.g. handed to another process, must
+     * take the copy down and still have its untyped reset and reused.
+     */
+    let before = untyped_stats();
+    let frame = gsm!().alloc_object::<RamObj>(FRAME_BIT_SIZE).unwrap();
+    let copy = copy_cap(&frame).unwrap();
+    if let Err(e) = gsm!().free_object(frame) {
+        println!("heap_test: FAILED, freeing a frame with a copy: {:?}", e).await;
+        return;
+    }
+    match cap_identify(copy.slot()) {
+        Ok(IdentifyResult::NullObj) => {}
+        other => {
+            println!("heap_test: FAILED, the copy survived: {:?}", other).await;
+            return;
+        }
+    }
+    drop(copy);
+    let after = untyped_stats();
+    if after != before {
+        println!(
+            "heap_test: FAILED, freeing a frame with a copy left {:?}, expected {:?}",
+            after, before
+        )
+        .await;
+        return;
+    }
+
+    println!("heap_test: PASS, {} rounds of {} bytes", ROUNDS, ALLOC_SIZE).await;
+}
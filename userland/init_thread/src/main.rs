@@ -26,13 +26,19 @@ use naive::rpc::{
     RequestMemoryResponse, RpcServer, RpcServerHandler
 };
 use naive::ep_server::MsgReceiver;
+use naive::shm::SharedRegion;
 use naive::space_manager::{copy_cap, gsm};
+use naive::untyped_stats;
 use rustyl4api::init::InitCSpaceSlot;
+use rustyl4api::objects::CapRights;
+use rustyl4api::vspace::Permission;
 use spin::Mutex;
 
 use log::trace;
 use vfs::Vfs;
 
+const CONSOLE_PRIORITY: usize = 192;
+
 lazy_static! {
     pub static ref IRQ_CAP: IrqRef = IrqRef::from_slot_num(InitCSpaceSlot::IrqController as usize);
     pub static ref MONITOR_CAP: MonitorRef =
@@ -43,7 +49,9 @@ pub fn allocate_frame_at(paddr: usize, _size: usize) -> Option<NonNull<u8>> {
     use rustyl4api::vspace::{Permission, FRAME_BIT_SIZE};
 
     let ram_obj = alloc_object_at::<RamObj>(paddr, FRAME_BIT_SIZE, true).unwrap();
-    let vaddr = gsm!().insert_ram_at(ram_obj, 0, Permission::writable());
+    let vaddr = gsm!()
+        .insert_ram_at(ram_obj, 0, Permission::writable())
+        .unwrap();
 
     NonNull::new(vaddr)
 }
@@ -113,7 +121,9 @@ impl rpc::RpcRequestHandlers for InitThreadApi {
             .open(&request.name)
             .map_err(|_| naive::Error::InternalError)?;
 
-        let ep = naive::space_manager::copy_cap(&node.cap).unwrap();
+        // Clients may send (and pass caps) to a service but never receive on its endpoint
+        let rights = CapRights::WRITE | CapRights::GRANT;
+        let ep = naive::space_manager::mint_cap(&node.cap, rights, None).unwrap();
         Ok((LookupServiceResponse {}, alloc::vec![ep.into_slot()]))
     }
 }
@@ -122,6 +132,42 @@ lazy_static! {
     static ref VFS: Mutex<Vfs> = Mutex::new(Vfs::new());
 }
 
+/* The first round may carve untypeds, every later one must give back all it took */
+fn kill_test(elf: &[u8], listener: &LmpListener) {
+    let mut baseline = None;
+    for round in 0..2 {
+        let child = naive::process::ProcessBuilder::new(elf)
+            .stdin(listener.derive_connector_ep().unwrap())
+            .stdout(listener.derive_connector_ep().unwrap())
+            .stderr(listener.derive_connector_ep().unwrap())
+            .name_server(listener.derive_connector_ep().unwrap())
+            .suspended()
+            .spawn()
+            .expect("spawn process failed");
+        if child.kill().is_err() {
+            rustyl4api::kprintln!("kill_test: FAILED, round {} cannot kill the child", round);
+            return;
+        }
+
+        let stats = untyped_stats();
+        let used = (stats.in_use, stats.packed_objects);
+        match baseline {
+            None => baseline = Some(used),
+            Some(expected) if expected != used => {
+                rustyl4api::kprintln!(
+                    "kill_test: FAILED, round {} left {:?}, expected {:?}",
+                    round,
+                    used,
+                    expected
+                );
+                return;
+            }
+            Some(_) => {}
+        }
+    }
+    rustyl4api::kprintln!("kill_test: PASS, a killed child gives its untypeds back");
+}
+
 #[naive::main]
 async fn main() {
     trace!("Init thread started");
@@ -135,6 +181,7 @@ async fn main() {
 
     let initfs = initfs::InitFs::new();
 
+    // the console serves keyboard interrupts, keep it ahead of busy user programs
     let console_proc = initfs
         .get(b"console")
         .map(|e| {
@@ -143,6 +190,7 @@ async fn main() {
                 .stdout(listener.derive_connector_ep().unwrap())
                 .stderr(listener.derive_connector_ep().unwrap())
                 .name_server(listener.derive_connector_ep().unwrap())
+                .priority(CONSOLE_PRIORITY)
                 .spawn()
                 .expect("spawn process failed")
         })
@@ -177,6 +225,96 @@ async fn main() {
         .expect("timer binary not found");
     core::mem::forget(timer_proc);
 
+    // the gdb stub is optional, it only serves GDB on UART0
+    let gdbstub_proc = initfs.get(b"gdbstub").map(|e| {
+        naive::process::ProcessBuilder::new(e)
+            .stdin(listener.derive_connector_ep().unwrap())
+            .stdout(listener.derive_connector_ep().unwrap())
+            .stderr(listener.derive_connector_ep().unwrap())
+            .name_server(listener.derive_connector_ep().unwrap())
+            .spawn()
+            .expect("spawn process failed")
+    });
+    core::mem::forget(gdbstub_proc);
+
+    // the shm test pair checks that a page written by one process reads back in another, which
+    // maps it read-only. Both find it at PROCESS_SHM_BASE
+    let shm_test = initfs.get(b"shm_producer").zip(initfs.get(b"shm_consumer"));
+    if let Some((producer, consumer)) = shm_test {
+        let region = SharedRegion::new(1).expect("allocating shared region failed");
+        let pair = [
+            (producer, Permission::writable()),
+            (consumer, Permission::readonly()),
+        ];
+        for &(elf, perm) in pair.iter() {
+            let proc = naive::process::ProcessBuilder::new(elf)
+                .stdin(listener.derive_connector_ep().unwrap())
+                .stdout(listener.derive_connector_ep().unwrap())
+                .stderr(listener.derive_connector_ep().unwrap())
+                .name_server(listener.derive_connector_ep().unwrap())
+                .shared_region(&region, 0, perm)
+                .spawn()
+                .expect("spawn process failed");
+            core::mem::forget(proc);
+        }
+    }
+
+    // shm_revoke drops a shared region while a read-only view of it is still mapped, and checks
+    // that the view still unmaps
+    let shm_revoke_proc = initfs.get(b"shm_revoke").map(|e| {
+        naive::process::ProcessBuilder::new(e)
+            .stdin(listener.derive_connector_ep().unwrap())
+            .stdout(listener.derive_connector_ep().unwrap())
+            .stderr(listener.derive_connector_ep().unwrap())
+            .name_server(listener.derive_connector_ep().unwrap())
+            .spawn()
+            .expect("spawn process failed")
+    });
+    core::mem::forget(shm_revoke_proc);
+
+    // the heap test allocates and frees 1M in a loop and checks that the memory it takes from
+    // the heap and the untypeds stays the same
+    let heap_test_proc = initfs.get(b"heap_test").map(|e| {
+        naive::process::ProcessBuilder::new(e)
+            .stdin(listener.derive_connector_ep().unwrap())
+            .stdout(listener.derive_connector_ep().unwrap())
+            .stderr(listener.derive_connector_ep().unwrap())
+            .name_server(listener.derive_connector_ep().unwrap())
+            .spawn()
+            .expect("spawn process failed")
+    });
+    core::mem::forget(heap_test_proc);
+
+    // the rights test checks that a Ram cap minted read-only can only be mapped non-writable
+    let rights_test_proc = initfs.get(b"rights_test").map(|e| {
+        naive::process::ProcessBuilder::new(e)
+            .stdin(listener.derive_connector_ep().unwrap())
+            .stdout(listener.derive_connector_ep().unwrap())
+            .stderr(listener.derive_connector_ep().unwrap())
+            .name_server(listener.derive_connector_ep().unwrap())
+            .spawn()
+            .expect("spawn process failed")
+    });
+    core::mem::forget(rights_test_proc);
+
+    // the vspace test deletes a VSpace and checks that a frame mapped in it still unmaps safely
+    let vspace_test_proc = initfs.get(b"vspace_test").map(|e| {
+        naive::process::ProcessBuilder::new(e)
+            .stdin(listener.derive_connector_ep().unwrap())
+            .stdout(listener.derive_connector_ep().unwrap())
+            .stderr(listener.derive_connector_ep().unwrap())
+            .name_server(listener.derive_connector_ep().unwrap())
+            .spawn()
+            .expect("spawn process failed")
+    });
+    core::mem::forget(vspace_test_proc);
+
+    // the kill test spawns a test program suspended and kills it, and checks that everything
+    // the child was given goes back to the untypeds
+    if let Some(elf) = initfs.get(b"heap_test") {
+        kill_test(elf, &listener);
+    }
+
     let rpc_api = InitThreadApi {};
     let rpc_api = RpcServerHandler::new(rpc_api);
     let mut rpc_server = RpcServer::new(listener);
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate naive;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::trace;
+
+use naive::ipc::{Fault, IpcMessage};
+use naive::objects::{EndpointObj, RamObj};
+use naive::space_manager::{gsm, mint_cap};
+use rustyl4api::fault::VmFaultKind;
+use rustyl4api::objects::CapRights;
+use rustyl4api::vspace::{Permission, FRAME_BIT_SIZE};
+
+/* Where the writer thread finds the read-only view */
+static VIEW: AtomicUsize = AtomicUsize::new(0);
+
+fn writer() -> ! {
+    let view = VIEW.load(Ordering::Acquire) as *mut u8;
+    unsafe { view.write_volatile(0xff) };
+    /* Only reached if the view was mapped writable */
+    naive::thread::exit()
+}
+
+#[naive::main]
+async fn main() {
+    trace!("rights_test started");
+
+    let ram = gsm!().alloc_object::<RamObj>(FRAME_BIT_SIZE).unwrap();
+    let ro = mint_cap(&ram, CapRights::READ, None).unwrap();
+    let page = gsm!()
+        .insert_ram_at(ram, 0, Permission::writable())
+        .unwrap();
+    let view = gsm!().insert_ram_at(ro, 0, Permission::readonly()).unwrap();
+
+    unsafe { page.write_volatile(0x5a) };
+    let read = unsafe { view.read_volatile() };
+    if read != 0x5a {
+        println!(
+            "rights_test: FAILED, the view reads {:#x}, expected 0x5a",
+            read
+        )
+        .await;
+        return;
+    }
+
+    /* A write through the view must fault rather than land in the page */
+    let fault_ep = gsm!().alloc_object::<EndpointObj>(12).unwrap();
+    VIEW.store(view as usize, Ordering::Release);
+    let _writer = naive::thread::spawn_with_fault_handler(writer, &fault_ep);
+
+    let fault = match fault_ep.receive(Vec::new()) {
+        Ok(IpcMessage::Fault(msg)) => msg.info,
+        other => {
+            println!("rights_test: FAILED, expected a fault, got {:?}", other).await;
+            return;
+        }
+    };
+    match fault {
+        Fault::DataFault(info)
+            if info.address as usize == view as usize
+                && matches!(info.kind, VmFaultKind::Permission) => {}
+        _ => {
+            println!("rights_test: FAILED, unexpected fault {:x?}", fault).await;
+            return;
+        }
+    }
+
+    let read = unsafe { page.read_volatile() };
+    if read != 0x5a {
+        println!(
+            "rights_test: FAILED, the page reads {:#x} after the write",
+            read
+        )
+        .await;
+        return;
+    }
+    println!("rights_test: PASS, a read-only Ram cap maps non-writable").await;
+}
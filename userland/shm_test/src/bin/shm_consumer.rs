@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate naive;
+
+#[path = "../protocol.rs"]
+mod protocol;
+
+use core::sync::atomic::Ordering;
+
+use log::trace;
+
+use protocol::{pattern, payload_ptr, ready_flag, PAYLOAD_LEN, READY};
+
+const POLL_INTERVAL_MS: u64 = 10;
+/* Give up on the producer after about 10 seconds */
+const POLL_LIMIT: usize = 1000;
+
+#[naive::main]
+async fn main() {
+    trace!("shm_consumer started");
+
+    let mut polls = 0;
+    while ready_flag().load(Ordering::Acquire) != READY {
+        if polls == POLL_LIMIT {
+            println!("shm_consumer: FAILED, no data from shm_producer").await;
+            return;
+        }
+        naive::time::sleep_ms(POLL_INTERVAL_MS).await;
+        polls += 1;
+    }
+
+    /* The region is mapped read-only here, so this only ever reads it */
+    let payload = unsafe { core::slice::from_raw_parts(payload_ptr(), PAYLOAD_LEN) };
+    match payload.iter().enumerate().find(|(i, b)| **b != pattern(*i)) {
+        None => println!("shm_consumer: {} bytes round-tripped", PAYLOAD_LEN).await,
+        Some((i, b)) => {
+            println!(
+                "shm_consumer: FAILED, byte {} is {:#x}, expected {:#x}",
+                i,
+                b,
+                pattern(i)
+            )
+            .await
+        }
+    }
+}
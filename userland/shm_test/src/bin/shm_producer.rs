@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate naive;
+
+#[path = "../protocol.rs"]
+mod protocol;
+
+use core::sync::atomic::Ordering;
+
+use log::trace;
+
+use protocol::{pattern, payload_ptr, ready_flag, PAYLOAD_LEN, READY};
+
+#[naive::main]
+async fn main() {
+    trace!("shm_producer started");
+
+    let payload = unsafe { core::slice::from_raw_parts_mut(payload_ptr(), PAYLOAD_LEN) };
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b = pattern(i);
+    }
+    ready_flag().store(READY, Ordering::Release);
+
+    println!("shm_producer: wrote {} bytes", PAYLOAD_LEN).await;
+}
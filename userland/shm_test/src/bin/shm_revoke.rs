@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate naive;
+
+use log::trace;
+
+use naive::shm::SharedRegion;
+use rustyl4api::vspace::Permission;
+
+#[naive::main]
+async fn main() {
+    trace!("shm_revoke started");
+
+    /* The view stands for another process, it only has the caps `share` handed out */
+    let region = SharedRegion::new(1).expect("allocating shared region failed");
+    let caps = region.share(false).expect("sharing the region failed");
+    let view = SharedRegion::from_caps(caps, false).unwrap();
+    let mapping = match view.map(0, Permission::readonly()) {
+        Ok(mapping) => mapping,
+        Err(e) => {
+            println!("shm_revoke: FAILED, mapping the view: {:?}", e).await;
+            return;
+        }
+    };
+    let base = mapping.as_ptr() as usize;
+
+    /* The creator lets go first, which revokes every copy, including the ones mapped */
+    drop(region);
+    drop(mapping);
+    drop(view);
+
+    /* The address range of the mapping must be free again */
+    let region = SharedRegion::new(1).expect("allocating shared region failed");
+    match region.map(base, Permission::writable()) {
+        Ok(_) => println!("shm_revoke: PASS, a view outlived its creator and unmapped").await,
+        Err(e) => println!("shm_revoke: FAILED, the range is still taken: {:?}", e).await,
+    }
+}
@@ -0,0 +1,26 @@
+use core::sync::atomic::AtomicU64;
+
+use rustyl4api::process::PROCESS_SHM_BASE;
+use rustyl4api::vspace::FRAME_SIZE;
+
+/*
+ * The page init_thread shares between shm_producer, which maps it writable, and shm_consumer,
+ * which maps it read-only. Both find it at PROCESS_SHM_BASE. The first word turns READY once the
+ * payload behind it is complete.
+ */
+pub const READY: u64 = 0x5348_4d52_4541_4459;
+pub const PAYLOAD_OFFSET: usize = 64;
+pub const PAYLOAD_LEN: usize = FRAME_SIZE - PAYLOAD_OFFSET;
+
+pub fn ready_flag() -> &'static AtomicU64 {
+    unsafe { &*(PROCESS_SHM_BASE as *const AtomicU64) }
+}
+
+pub fn payload_ptr() -> *mut u8 {
+    (PROCESS_SHM_BASE + PAYLOAD_OFFSET) as *mut u8
+}
+
+/* Byte `i` of the payload */
+pub fn pattern(i: usize) -> u8 {
+    (i.wrapping_mul(31) ^ (i >> 8)) as u8
+}
@@ -8,7 +8,9 @@ pub static SYSTEM_TIMER: Mutex<Option<Timer>> = Mutex::new(None);
 
 pub async fn init_timer_server() {
     let timer_ram_cap = crate::request_memory(0x3F003000, 4096, true).await.unwrap();
-    let timer_base = gsm!().insert_ram_at(timer_ram_cap, 0, Permission::writable());
+    let timer_base = gsm!()
+        .insert_ram_at(timer_ram_cap, 0, Permission::writable())
+        .unwrap();
 
     *SYSTEM_TIMER.lock() = Some(Timer::new(timer_base as usize));
 }
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate naive;
+
+use log::trace;
+
+use naive::objects::identify::{cap_identify, IdentifyResult};
+use naive::objects::{RamObj, VTableObj};
+use naive::space_manager::gsm;
+use rustyl4api::vspace::{Permission, FRAME_BIT_SIZE, FRAME_SIZE};
+
+const VADDR: usize = 0x1000_0000;
+
+#[naive::main]
+async fn main() {
+    trace!("vspace_test started");
+
+    /* A VSpace of its own with one frame, mapped through a table at every level */
+    let root = gsm!().alloc_object::<VTableObj>(12).unwrap();
+    let tables = gsm!().alloc_objects::<VTableObj>(12, 3).unwrap();
+    for (table, level) in tables.iter().zip((2..=4).rev()) {
+        table.map(&root, VADDR, level).unwrap();
+    }
+    let frame = gsm!().alloc_object::<RamObj>(FRAME_BIT_SIZE).unwrap();
+    frame.map(&root, VADDR, Permission::writable()).unwrap();
+
+    /*
+     * The untyped of the root goes back to the space manager, which hands it out again for the
+     * next object of its size. Fill it with what would look like table entries to a walk.
+     */
+    gsm!().free_object(root).unwrap();
+    let reuse = gsm!().alloc_object::<RamObj>(FRAME_BIT_SIZE).unwrap();
+    let page = gsm!()
+        .insert_ram_at(reuse, 0, Permission::writable())
+        .unwrap();
+    unsafe { core::ptr::write_bytes(page, 0xff, FRAME_SIZE) };
+
+    if let Err(e) = frame.unmap() {
+        println!("vspace_test: FAILED, unmapping the frame: {:?}", e).await;
+        return;
+    }
+    match cap_identify(frame.slot()) {
+        Ok(IdentifyResult::Ram {
+            mapped_vaddr: 0, ..
+        }) => {}
+        other => {
+            println!(
+                "vspace_test: FAILED, the frame still looks mapped: {:?}",
+                other
+            )
+            .await;
+            return;
+        }
+    }
+    if let Some(e) = tables.iter().find_map(|table| table.unmap().err()) {
+        println!("vspace_test: FAILED, unmapping a table: {:?}", e).await;
+        return;
+    }
+
+    let page = unsafe { core::slice::from_raw_parts(page, FRAME_SIZE) };
+    if page.iter().any(|b| *b != 0xff) {
+        println!("vspace_test: FAILED, the unmap wrote into memory of the deleted root").await;
+        return;
+    }
+
+    let freed = tables
+        .into_iter()
+        .try_for_each(|table| gsm!().free_object(table))
+        .and(gsm!().free_object(frame));
+    if let Err(e) = freed {
+        println!("vspace_test: FAILED, freeing the frame and tables: {:?}", e).await;
+        return;
+    }
+    println!("vspace_test: PASS, a frame of a deleted VSpace unmaps safely").await;
+}